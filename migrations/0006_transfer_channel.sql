@@ -0,0 +1,4 @@
+-- Where a transfer originated from (web, mobile or api)
+ALTER TABLE transfers ADD COLUMN IF NOT EXISTS channel VARCHAR(20) NOT NULL DEFAULT 'api';
+
+CREATE INDEX idx_transfers_channel ON transfers(channel);
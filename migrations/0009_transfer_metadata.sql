@@ -0,0 +1,2 @@
+-- Small integrator supplied JSON object attached to a transfer (order id, invoice ref, ...)
+ALTER TABLE transfers ADD COLUMN IF NOT EXISTS metadata JSONB;
@@ -0,0 +1,22 @@
+-- Gap-free sequential transfer number for accounting, distinct from the UUID.
+-- A sequence would skip values consumed by rolled back transfers, so the number is taken
+-- from a single row counter that is incremented inside the transfer's own transaction.
+CREATE TABLE IF NOT EXISTS transfer_counter (
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    value BIGINT NOT NULL
+);
+
+ALTER TABLE transfers ADD COLUMN IF NOT EXISTS transfer_no BIGINT;
+
+-- number already recorded transfers in creation order
+UPDATE transfers t
+SET transfer_no = numbered.no
+FROM (SELECT id, row_number() OVER (ORDER BY created_at, id) AS no FROM transfers) numbered
+WHERE t.id = numbered.id AND t.transfer_no IS NULL;
+
+INSERT INTO transfer_counter (value)
+SELECT COALESCE(MAX(transfer_no), 0) FROM transfers
+ON CONFLICT (id) DO NOTHING;
+
+ALTER TABLE transfers ALTER COLUMN transfer_no SET NOT NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_transfers_transfer_no ON transfers(transfer_no);
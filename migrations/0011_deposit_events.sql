@@ -0,0 +1,9 @@
+-- Deposits credited from payment provider webhooks, the provider's event id makes redelivery a no-op
+CREATE TABLE IF NOT EXISTS deposit_events (
+    event_id VARCHAR(255) PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id),
+    amount DECIMAL(19,4) NOT NULL CHECK (amount > 0),
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_deposit_events_user_id ON deposit_events(user_id);
@@ -0,0 +1,2 @@
+-- Free text memo supplied by the sender, stored after redaction
+ALTER TABLE transfers ADD COLUMN IF NOT EXISTS description TEXT;
@@ -0,0 +1,12 @@
+-- Idempotency-Key values sent with transfers, scoped per user. A replayed key answers with the
+-- transfer it first created; keys older than 24 hours are treated as unused and may be claimed again.
+-- The key is claimed before the transfer row exists, hence the deferred foreign key
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    user_id UUID NOT NULL REFERENCES users(id),
+    idempotency_key VARCHAR(255) NOT NULL,
+    transfer_id UUID NOT NULL REFERENCES transfers(id) DEFERRABLE INITIALLY DEFERRED,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, idempotency_key)
+);
+
+CREATE INDEX IF NOT EXISTS idx_idempotency_keys_created_at ON idempotency_keys(created_at);
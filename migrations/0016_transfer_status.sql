@@ -0,0 +1,13 @@
+-- Lifecycle of a transfer: recorded as pending before any money moves, completed in the same
+-- database transaction that moves it, failed when that transaction is rolled back
+CREATE TYPE transaction_status AS ENUM ('pending', 'completed', 'failed');
+
+-- transfers recorded so far were all committed, so they start out completed
+ALTER TABLE transfers ADD COLUMN IF NOT EXISTS status transaction_status NOT NULL DEFAULT 'completed';
+ALTER TABLE transfers ALTER COLUMN status SET DEFAULT 'pending';
+ALTER TABLE transfers ADD COLUMN IF NOT EXISTS updated_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP;
+
+-- the accounting number is only taken on completion, pending and failed transfers have none
+ALTER TABLE transfers ALTER COLUMN transfer_no DROP NOT NULL;
+ALTER TABLE transfers ADD CONSTRAINT transfer_no_when_completed
+    CHECK ((status = 'completed') = (transfer_no IS NOT NULL));
@@ -0,0 +1,6 @@
+-- An Idempotency-Key sent with a deposit is kept as its reference, a repeated key must not credit twice
+CREATE UNIQUE INDEX IF NOT EXISTS idx_transactions_deposit_reference
+    ON transactions(user_id, reference_id)
+    WHERE transaction_type = 'deposit' AND reference_id IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_transactions_user_created ON transactions(user_id, created_at DESC, id DESC);
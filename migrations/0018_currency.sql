@@ -0,0 +1,7 @@
+-- ISO 4217 currency of an account, balances and every amount moved on it are in this currency
+ALTER TABLE users ADD COLUMN IF NOT EXISTS currency CHAR(3) NOT NULL DEFAULT 'USD';
+
+-- transfers only move money between accounts of the same currency, recorded for reporting;
+-- existing transfers predate multi currency support and were all in the implicit USD
+ALTER TABLE transfers ADD COLUMN IF NOT EXISTS currency CHAR(3) NOT NULL DEFAULT 'USD';
+ALTER TABLE transfers ALTER COLUMN currency DROP DEFAULT;
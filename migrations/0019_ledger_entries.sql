@@ -0,0 +1,28 @@
+-- Append-only record of every balance change, a user's balance is SUM(delta) over their entries.
+-- tx_id is the transfer (one debit and one credit entry) or the transactions row (deposit, withdrawal)
+-- the entry belongs to. users.balance is kept only as a cache of the sum, written in the same transaction
+CREATE TABLE IF NOT EXISTS ledger_entries (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id),
+    delta DECIMAL(19,4) NOT NULL CHECK (delta <> 0),
+    tx_id UUID NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_ledger_entries_user_id ON ledger_entries(user_id);
+CREATE INDEX IF NOT EXISTS idx_ledger_entries_tx_id ON ledger_entries(tx_id);
+
+-- opening entries carry over the balances held before the ledger existed
+INSERT INTO ledger_entries (user_id, delta, tx_id)
+SELECT id, balance, gen_random_uuid() FROM users WHERE balance <> 0;
+
+CREATE OR REPLACE FUNCTION reject_ledger_entry_changes() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'ledger_entries is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER ledger_entries_append_only
+BEFORE UPDATE OR DELETE ON ledger_entries
+FOR EACH ROW
+EXECUTE FUNCTION reject_ledger_entry_changes();
@@ -0,0 +1,4 @@
+-- TOTP secret, AES-256-GCM encrypted with TOTP_ENCRYPTION_KEY. It is stored on enable and only
+-- required at login once a code has been verified against it, which sets totp_enabled
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_secret TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_enabled BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- Authorization role, carried in the access token claims. Admins are promoted directly in the database
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'user'
+    CONSTRAINT users_role_check CHECK (role IN ('user', 'admin'));
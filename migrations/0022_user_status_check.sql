@@ -0,0 +1,3 @@
+-- Statuses understood by the auth flow, only `active` accounts can log in or refresh their tokens
+ALTER TABLE users ADD CONSTRAINT users_status_check
+    CHECK (status IN ('active', 'pending', 'frozen', 'closed'));
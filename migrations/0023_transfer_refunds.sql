@@ -0,0 +1,6 @@
+-- A refund is a transfer in the opposite direction pointing at the transfer it reverses
+ALTER TABLE transfers ADD COLUMN IF NOT EXISTS reversed_tx_id UUID REFERENCES transfers(id);
+
+-- at most one refund per transfer, a pending one blocks a concurrent second attempt
+CREATE UNIQUE INDEX IF NOT EXISTS idx_transfers_reversed_tx_id ON transfers(reversed_tx_id)
+    WHERE reversed_tx_id IS NOT NULL AND status <> 'failed';
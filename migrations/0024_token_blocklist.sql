@@ -0,0 +1,10 @@
+-- Access tokens revoked before their expiry, keyed by the token's jti. A row is only needed
+-- until the token would have expired anyway, after that the purge worker drops it
+CREATE TABLE IF NOT EXISTS token_blocklist (
+    jti UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    revoked_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_token_blocklist_expires_at ON token_blocklist(expires_at);
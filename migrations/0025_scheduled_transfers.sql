@@ -0,0 +1,6 @@
+-- Transfers queued to run later stay pending until `scheduled_for` has passed, the scheduler
+-- then moves the money the same way an immediate transfer does
+ALTER TABLE transfers ADD COLUMN IF NOT EXISTS scheduled_for TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX IF NOT EXISTS idx_transfers_due ON transfers (scheduled_for)
+    WHERE status = 'pending' AND scheduled_for IS NOT NULL;
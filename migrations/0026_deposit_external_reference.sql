@@ -0,0 +1,7 @@
+-- Reference an external system (bank statement, payment provider) booked a deposit under. Kept apart from
+-- `reference_id`, which holds the Idempotency-Key, so the same booking can't be credited twice under two keys
+ALTER TABLE transactions ADD COLUMN IF NOT EXISTS external_reference VARCHAR(255);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_transactions_deposit_external_reference
+    ON transactions(user_id, external_reference)
+    WHERE transaction_type = 'deposit' AND external_reference IS NOT NULL;
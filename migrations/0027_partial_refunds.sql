@@ -0,0 +1,17 @@
+-- A transfer can be refunded in several parts, `refunded_amount` sums the completed refunds and never exceeds the amount
+ALTER TABLE transfers ADD COLUMN IF NOT EXISTS refunded_amount DECIMAL(19,4) NOT NULL DEFAULT 0;
+ALTER TABLE transfers ADD CONSTRAINT transfers_refunded_amount_check CHECK (refunded_amount >= 0 AND refunded_amount <= amount);
+
+UPDATE transfers t
+SET refunded_amount = refunds.total
+FROM (
+    SELECT reversed_tx_id, SUM(amount) AS total
+    FROM transfers
+    WHERE reversed_tx_id IS NOT NULL AND status = 'completed'
+    GROUP BY reversed_tx_id
+) refunds
+WHERE t.id = refunds.reversed_tx_id;
+
+-- no longer one refund per transfer, the row lock on the original serializes concurrent refunds instead
+DROP INDEX IF EXISTS idx_transfers_reversed_tx_id;
+CREATE INDEX IF NOT EXISTS idx_transfers_reversed_tx_id ON transfers(reversed_tx_id) WHERE reversed_tx_id IS NOT NULL;
@@ -0,0 +1,5 @@
+-- Admin corrections of a balance are `adjustment` transactions, the only kind whose amount is signed:
+-- a negative one takes money off the account
+ALTER TABLE transactions DROP CONSTRAINT transactions_amount_check;
+ALTER TABLE transactions ADD CONSTRAINT transactions_amount_check
+    CHECK (amount > 0 OR (transaction_type = 'adjustment' AND amount <> 0));
@@ -0,0 +1,8 @@
+-- External transfers leave for another institution: the sender is debited right away, the recipient is only
+-- credited once the transfer settles at `settles_at`. Until then it stays pending with the funds held
+CREATE TYPE transfer_kind AS ENUM ('internal', 'external');
+
+ALTER TABLE transfers ADD COLUMN transfer_kind transfer_kind NOT NULL DEFAULT 'internal';
+ALTER TABLE transfers ADD COLUMN settles_at TIMESTAMPTZ;
+
+CREATE INDEX idx_transfers_settling ON transfers(settles_at) WHERE status = 'pending' AND settles_at IS NOT NULL;
@@ -0,0 +1,4 @@
+-- The trigger from 0005 deleted refresh tokens the moment they expired, ignoring the clock skew leeway the
+-- server still accepts them within. The periodic purge honours the leeway, so expired tokens are left to it
+DROP TRIGGER IF EXISTS clear_expired_tokens ON refresh_tokens;
+DROP FUNCTION IF EXISTS delete_expired_tokens();
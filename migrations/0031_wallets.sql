@@ -0,0 +1,39 @@
+-- A wallet holds a user's money in one currency. Every account has the wallet in its own currency,
+-- the primary one whose sum `users.balance` caches, and may open wallets in further currencies
+CREATE TABLE IF NOT EXISTS wallets (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id),
+    currency CHAR(3) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (user_id, currency)
+);
+
+INSERT INTO wallets (user_id, currency)
+SELECT id, currency FROM users
+ON CONFLICT (user_id, currency) DO NOTHING;
+
+-- every ledger entry moves the balance of one wallet, the existing ones all belong to primary wallets
+ALTER TABLE ledger_entries ADD COLUMN IF NOT EXISTS wallet_id UUID REFERENCES wallets(id);
+
+ALTER TABLE ledger_entries DISABLE TRIGGER ledger_entries_append_only;
+UPDATE ledger_entries e SET wallet_id = w.id
+FROM users u JOIN wallets w ON w.user_id = u.id AND w.currency = u.currency
+WHERE u.id = e.user_id AND e.wallet_id IS NULL;
+ALTER TABLE ledger_entries ENABLE TRIGGER ledger_entries_append_only;
+
+ALTER TABLE ledger_entries ALTER COLUMN wallet_id SET NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_ledger_entries_wallet_id ON ledger_entries(wallet_id);
+
+-- the wallets a transfer moves money between, both in the transfer's currency
+ALTER TABLE transfers ADD COLUMN IF NOT EXISTS sender_wallet_id UUID REFERENCES wallets(id);
+ALTER TABLE transfers ADD COLUMN IF NOT EXISTS receiver_wallet_id UUID REFERENCES wallets(id);
+
+UPDATE transfers t SET sender_wallet_id = w.id
+FROM wallets w
+WHERE w.user_id = t.sender_id AND w.currency = t.currency AND t.sender_wallet_id IS NULL;
+UPDATE transfers t SET receiver_wallet_id = w.id
+FROM wallets w
+WHERE w.user_id = t.recipient_id AND w.currency = t.currency AND t.receiver_wallet_id IS NULL;
+
+ALTER TABLE transfers ALTER COLUMN sender_wallet_id SET NOT NULL;
+ALTER TABLE transfers ALTER COLUMN receiver_wallet_id SET NOT NULL;
@@ -0,0 +1,22 @@
+-- An authorization puts part of a wallet on hold for a recipient: the amount is debited right away and either
+-- captured into a transfer to the recipient, or released back to the wallet, at the latest once it expires
+CREATE TYPE hold_status AS ENUM ('held', 'captured', 'released');
+
+CREATE TABLE IF NOT EXISTS holds (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    sender_id UUID NOT NULL REFERENCES users(id),
+    receiver_id UUID NOT NULL REFERENCES users(id),
+    sender_wallet_id UUID NOT NULL REFERENCES wallets(id),
+    receiver_wallet_id UUID NOT NULL REFERENCES wallets(id),
+    amount DECIMAL(19,4) NOT NULL CHECK (amount > 0),
+    currency CHAR(3) NOT NULL,
+    status hold_status NOT NULL DEFAULT 'held',
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    transfer_id UUID REFERENCES transfers(id), -- the transfer a captured hold became
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_holds_sender_id ON holds(sender_id) WHERE status = 'held';
+CREATE INDEX IF NOT EXISTS idx_holds_receiver_id ON holds(receiver_id) WHERE status = 'held';
+CREATE INDEX IF NOT EXISTS idx_holds_expiring ON holds(expires_at) WHERE status = 'held';
@@ -0,0 +1,13 @@
+-- Long lived credentials a user hands to scripts and integrations instead of their password. Only a hash of
+-- the key is stored, the key itself is shown once when it is created
+CREATE TABLE IF NOT EXISTS api_keys (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id),
+    label VARCHAR(100) NOT NULL,
+    scopes TEXT[] NOT NULL,
+    key_hash CHAR(64) NOT NULL UNIQUE, -- hex SHA-256 of the key
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX IF NOT EXISTS idx_api_keys_user_id ON api_keys(user_id);
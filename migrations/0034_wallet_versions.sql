@@ -0,0 +1,3 @@
+-- Bumped with every debit of the wallet, a transfer checks it still holds the version it read
+-- before moving money and retries on a fresh read otherwise
+ALTER TABLE wallets ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- 0001 and 0005 both create idx_users_email. The UNIQUE constraint on users.email already indexes the
+-- column, so the plain index only costs writes and is dropped
+DROP INDEX IF EXISTS idx_users_email;
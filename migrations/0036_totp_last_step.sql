@@ -0,0 +1,3 @@
+-- Time step of the last TOTP code accepted for the user, codes of that step or an earlier one are refused
+-- so an intercepted code can't be replayed while it is still valid
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_last_step BIGINT;
@@ -0,0 +1,72 @@
+use rust_decimal::Decimal;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+// Kind of money movement recorded in `audit_logs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    Transfer,
+    Deposit,
+    Withdrawal,
+    Refund,
+    Hold,
+    HoldRelease,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Transfer => "transfer",
+            AuditAction::Deposit => "deposit",
+            AuditAction::Withdrawal => "withdrawal",
+            AuditAction::Refund => "refund",
+            AuditAction::Hold => "hold",
+            AuditAction::HoldRelease => "hold_release",
+        }
+    }
+}
+
+// Who moved what: `tx_id` is the transfer id for transfers and refunds, the hold for holds and their release,
+// the `transactions` row otherwise
+#[derive(Debug)]
+pub struct AuditEntry {
+    pub actor_id: Uuid,
+    pub action: AuditAction,
+    pub amount: Decimal,
+    pub counterparty_id: Option<Uuid>,
+    pub tx_id: Uuid,
+}
+
+impl AuditEntry {
+    // transfers and refunds live in `transfers`, holds in `holds`, deposits and withdrawals in `transactions`
+    fn entity_type(&self) -> &'static str {
+        match self.action {
+            AuditAction::Transfer | AuditAction::Refund => "transfer",
+            AuditAction::Hold | AuditAction::HoldRelease => "hold",
+            AuditAction::Deposit | AuditAction::Withdrawal => "transaction",
+        }
+    }
+}
+
+// Write the `audit_logs` row inside the caller's transaction, so it exists exactly when the movement commits
+pub async fn record(conn: &mut PgConnection, entry: &AuditEntry) -> Result<(), sqlx::Error> {
+    let details = serde_json::json!({
+        "amount": entry.amount,
+        "counterparty_id": entry.counterparty_id,
+    });
+
+    sqlx::query!(
+        r#"
+        INSERT INTO audit_logs (user_id, action, entity_type, entity_id, changes)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        entry.actor_id,
+        entry.action.as_str(),
+        entry.entity_type(),
+        entry.tx_id,
+        details
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
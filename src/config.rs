@@ -0,0 +1,348 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rust_decimal::Decimal;
+
+use crate::currency::Currency;
+use crate::routes::auth::{AuthConfig, SessionPolicy};
+use crate::routes::jwt_keys::{JwtKeys, RsaSigningKey};
+use crate::routes::totp::TotpKey;
+use crate::routes::tx::TxConfig;
+use crate::routes::webhook::{SignatureAlgorithm, WebhookConfig};
+
+// Startup settings read from the environment (or a `.env` file)
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub database_url: String,
+    pub jwt_keys: JwtKeys,
+    pub port: u16,
+    pub pool: PoolConfig,
+    pub max_body_bytes: usize,
+    pub log_file: String,
+    pub redact_db_credentials: bool,
+    pub export_poll_interval: Duration,
+    pub scheduled_transfer_poll_interval: Duration,
+    pub refresh_cleanup_interval: Duration,
+    pub shutdown_grace_period: Duration,
+    pub auth: AuthConfig,
+    pub tx: TxConfig,
+    pub webhook: Option<WebhookConfig>,
+}
+
+// Sizing and timeouts of the database connection pool
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    pub min_connections: u32,           // kept open even when idle
+    pub acquire_timeout: Duration,      // how long a request waits for a free connection before giving up
+    pub idle_timeout: Option<Duration>, // idle connections above `min_connections` are closed after this
+    pub test_before_acquire: bool,      // ping pooled connections before use so stale ones get recycled
+}
+
+// A variable that is missing or can't be used, naming the variable and the offending value.
+// The value of a secret is left out, the error ends up on stdout and in the logs
+#[derive(Debug)]
+pub enum ConfigError {
+    Missing(&'static str),
+    Invalid {
+        var: &'static str,
+        value: String,
+        reason: String,
+    },
+    InvalidSecret {
+        var: &'static str,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Missing(var) => write!(f, "{var} must be set"),
+            ConfigError::Invalid { var, value, reason } => {
+                write!(f, "invalid value `{value}` for {var}: {reason}")
+            }
+            ConfigError::InvalidSecret { var, reason } => write!(f, "invalid value for {var}: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        // mandatory fields
+        let database_url = dotenv::var("DATABASE_URL").map_err(|_| ConfigError::Missing("DATABASE_URL"))?;
+        let jwt_secret = dotenv::var("JWT_SECRET").unwrap_or("your-jwt-secret".to_string());
+        // a key list takes over from the single secret once keys are being rotated
+        let mut jwt_keys = match parse_optional_secret_var::<JwtKeys>("JWT_KEYS")? {
+            Some(jwt_keys) => jwt_keys,
+            None => JwtKeys::single(jwt_secret),
+        };
+        if let Some(rsa_key) = parse_optional_secret_var::<RsaSigningKey>("JWT_RSA_PRIVATE_KEY")? {
+            jwt_keys = jwt_keys.with_rsa(rsa_key);
+        }
+
+        // optional fields
+        let port = parse_var::<u16>("PORT", "3000")?;
+        ensure("PORT", port, port > 0, "must be between 1 and 65535")?;
+        let max_connection_pooling = parse_var::<u32>("MAX_CONNECTION_POOLING", "5")?;
+        ensure("MAX_CONNECTION_POOLING", max_connection_pooling, max_connection_pooling >= 1, "must be at least 1")?;
+        let min_connections = parse_var::<u32>("POOL_MIN_CONNECTIONS", "0")?;
+        ensure(
+            "POOL_MIN_CONNECTIONS",
+            min_connections,
+            min_connections <= max_connection_pooling,
+            "must not exceed MAX_CONNECTION_POOLING",
+        )?;
+        let acquire_timeout = parse_var::<u64>("POOL_ACQUIRE_TIMEOUT_SECS", "5")?;
+        ensure("POOL_ACQUIRE_TIMEOUT_SECS", acquire_timeout, acquire_timeout > 0, "must be at least 1")?;
+        // 0 keeps idle connections open for good
+        let idle_timeout = parse_var::<u64>("POOL_IDLE_TIMEOUT_SECS", "600")?;
+        let pool = PoolConfig {
+            max_connections: max_connection_pooling,
+            min_connections,
+            acquire_timeout: Duration::from_secs(acquire_timeout),
+            idle_timeout: (idle_timeout > 0).then(|| Duration::from_secs(idle_timeout)),
+            test_before_acquire: parse_var("POOL_TEST_BEFORE_ACQUIRE", "true")?,
+        };
+
+        let max_body_bytes = parse_var::<usize>("MAX_BODY_BYTES", "65536")?;
+        ensure("MAX_BODY_BYTES", max_body_bytes, max_body_bytes > 0, "must be at least 1")?;
+
+        let log_file = dotenv::var("LOG_FILE").unwrap_or("app.log".to_string());
+        let blocked_words = dotenv::var("DESCRIPTION_BLOCKED_WORDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|word| word.trim().to_lowercase())
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>();
+
+        let blocked_email_domains = dotenv::var("BLOCKED_EMAIL_DOMAINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|domain| domain.trim().trim_start_matches('@').to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect::<Vec<_>>();
+
+        let access_ttl = parse_var::<u64>("ACCESS_TOKEN_TTL_SECS", "900")?;
+        ensure("ACCESS_TOKEN_TTL_SECS", access_ttl, access_ttl > 0, "must be at least 1")?;
+        let refresh_ttl = parse_var::<u64>("REFRESH_TOKEN_TTL_SECS", "604800")?;
+        ensure("REFRESH_TOKEN_TTL_SECS", refresh_ttl, refresh_ttl > access_ttl, "must be longer than ACCESS_TOKEN_TTL_SECS")?;
+
+        let argon2_memory = parse_var::<u32>("ARGON2_MEMORY_KIB", "19456")?;
+        let argon2_iterations = parse_var::<u32>("ARGON2_ITERATIONS", "2")?;
+        ensure("ARGON2_ITERATIONS", argon2_iterations, argon2_iterations >= 1, "must be at least 1")?;
+        let argon2_parallelism = parse_var::<u32>("ARGON2_PARALLELISM", "1")?;
+        ensure(
+            "ARGON2_PARALLELISM",
+            argon2_parallelism,
+            (1..=argon2::Params::MAX_P_COST).contains(&argon2_parallelism),
+            "must be between 1 and 16777215",
+        )?;
+        // the memory floor depends on the parallelism, so it's left to argon2 to check
+        let argon2_params = argon2::Params::new(argon2_memory, argon2_iterations, argon2_parallelism, None)
+            .map_err(|err| ConfigError::Invalid {
+                var: "ARGON2_MEMORY_KIB",
+                value: argon2_memory.to_string(),
+                reason: err.to_string(),
+            })?;
+
+        // links in emails point here, never at whatever host a request came in on
+        let public_base_url = dotenv::var("PUBLIC_BASE_URL").unwrap_or("http://localhost:3000".to_string());
+        let public_base_url = public_base_url.trim().trim_end_matches('/').to_string();
+        ensure(
+            "PUBLIC_BASE_URL",
+            &public_base_url,
+            (public_base_url.starts_with("https://") || public_base_url.starts_with("http://"))
+                && !public_base_url.contains(['?', '#']),
+            "must be an http(s) URL without query or fragment",
+        )?;
+
+        let auth = AuthConfig {
+            leeway: parse_var("JWT_LEEWAY_SECS", "10")?,
+            access_ttl: Duration::from_secs(access_ttl),
+            refresh_ttl: Duration::from_secs(refresh_ttl),
+            email_verification_ttl: Duration::from_secs(parse_var("EMAIL_VERIFICATION_TTL_SECS", "86400")?),
+            email_change_ttl: Duration::from_secs(parse_var("EMAIL_CHANGE_TTL_SECS", "86400")?),
+            log_email_tokens: parse_var("LOG_EMAIL_TOKENS", "false")?,
+            public_base_url,
+            login_ip_max_failures: parse_var("LOGIN_IP_MAX_FAILURES", "20")?,
+            login_ip_window: Duration::from_secs(parse_var("LOGIN_IP_WINDOW_SECS", "300")?),
+            login_email_max_failures: parse_var("LOGIN_EMAIL_MAX_FAILURES", "5")?,
+            login_email_window: Duration::from_secs(parse_var("LOGIN_EMAIL_WINDOW_SECS", "60")?),
+            confirm_email_changes: parse_var("CONFIRM_EMAIL_CHANGES", "true")?,
+            max_sessions: parse_var("MAX_SESSIONS_PER_USER", "5")?,
+            session_policy: parse_var::<SessionPolicy>("SESSION_CAP_POLICY", "evict_oldest")?,
+            default_currency: parse_var::<Currency>("DEFAULT_CURRENCY", "USD")?,
+            totp_key: parse_optional_secret_var::<TotpKey>("TOTP_ENCRYPTION_KEY")?,
+            argon2_params,
+            blocked_email_domains,
+        };
+        let min_transfer_amount = parse_optional_var::<Decimal>("MIN_TRANSFER_AMOUNT")?;
+        if let Some(min_amount) = min_transfer_amount {
+            ensure("MIN_TRANSFER_AMOUNT", min_amount, min_amount > Decimal::ZERO, "must be positive")?;
+        }
+        let max_transfer_amount = parse_optional_var::<Decimal>("MAX_TRANSFER_AMOUNT")?;
+        if let Some(max_amount) = max_transfer_amount {
+            ensure(
+                "MAX_TRANSFER_AMOUNT",
+                max_amount,
+                max_amount >= min_transfer_amount.unwrap_or(Decimal::ZERO) && max_amount > Decimal::ZERO,
+                "must be positive and not below MIN_TRANSFER_AMOUNT",
+            )?;
+        }
+
+        let daily_transfer_limit = parse_optional_var::<Decimal>("DAILY_TRANSFER_LIMIT")?;
+        if let Some(daily_limit) = daily_transfer_limit {
+            ensure("DAILY_TRANSFER_LIMIT", daily_limit, daily_limit > Decimal::ZERO, "must be positive")?;
+        }
+
+        // 0 keeps transfers refundable for good
+        let reversal_window = parse_var::<u64>("REVERSAL_WINDOW_SECS", "2592000")?;
+
+        let tx = TxConfig {
+            record_failed_transfers: parse_var("RECORD_FAILED_TRANSFERS", "true")?,
+            filter_descriptions: parse_var("FILTER_DESCRIPTIONS", "true")?,
+            blocked_words,
+            max_metadata_bytes: parse_var("MAX_TRANSFER_METADATA_BYTES", "1024")?,
+            max_account_balance: parse_optional_var("MAX_ACCOUNT_BALANCE")?,
+            min_transfer_amount,
+            max_transfer_amount,
+            daily_transfer_limit,
+            reversal_window: (reversal_window > 0).then(|| Duration::from_secs(reversal_window)),
+            external_settlement_delay: Duration::from_secs(parse_var("EXTERNAL_SETTLEMENT_SECS", "86400")?),
+            hold_expiry: Duration::from_secs(parse_var("HOLD_EXPIRY_SECS", "604800")?),
+            version_retries: parse_var("TRANSFER_VERSION_RETRIES", "10")?,
+        };
+
+        let webhook_algorithm = parse_var::<SignatureAlgorithm>("WEBHOOK_SIGNATURE_ALGORITHM", "sha256")?;
+        let webhook_tolerance = Duration::from_secs(parse_var("WEBHOOK_TOLERANCE_SECS", "300")?);
+        let webhook = dotenv::var("DEPOSIT_WEBHOOK_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .map(|deposit_secret| WebhookConfig {
+                deposit_secret,
+                algorithm: webhook_algorithm,
+                tolerance: webhook_tolerance,
+            });
+
+        Ok(Self {
+            database_url,
+            jwt_keys,
+            port,
+            pool,
+            max_body_bytes,
+            log_file,
+            redact_db_credentials: parse_var("REDACT_DB_CREDENTIALS", "true")?,
+            export_poll_interval: Duration::from_secs(parse_var("EXPORT_POLL_INTERVAL", "5")?),
+            scheduled_transfer_poll_interval: Duration::from_secs(parse_var("SCHEDULED_TRANSFER_POLL_INTERVAL", "30")?),
+            refresh_cleanup_interval: Duration::from_secs(parse_var("REFRESH_CLEANUP_INTERVAL", "3600")?),
+            shutdown_grace_period: Duration::from_secs(parse_var("SHUTDOWN_GRACE_SECS", "30")?),
+            auth,
+            tx,
+            webhook,
+        })
+    }
+}
+
+// reads `var`, falling back to `default` when unset, and parses it as `T`
+fn parse_var<T>(var: &'static str, default: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = dotenv::var(var).unwrap_or(default.to_string());
+    value.trim().parse::<T>().map_err(|err| ConfigError::Invalid {
+        var,
+        reason: err.to_string(),
+        value,
+    })
+}
+
+// like `parse_var` for settings that are off unless set
+fn parse_optional_var<T>(var: &'static str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match dotenv::var(var) {
+        Ok(value) if !value.trim().is_empty() => value.trim().parse::<T>().map(Some).map_err(|err| ConfigError::Invalid {
+            var,
+            reason: err.to_string(),
+            value,
+        }),
+        _ => Ok(None),
+    }
+}
+
+// like `parse_optional_var` for keys and secrets, whose value is never echoed back
+fn parse_optional_secret_var<T>(var: &'static str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    parse_optional_var(var).map_err(|err| match err {
+        ConfigError::Invalid { var, reason, .. } => ConfigError::InvalidSecret { var, reason },
+        err => err,
+    })
+}
+
+fn ensure<T: fmt::Display>(var: &'static str, value: T, valid: bool, reason: &str) -> Result<(), ConfigError> {
+    if valid {
+        return Ok(());
+    }
+    Err(ConfigError::Invalid {
+        var,
+        value: value.to_string(),
+        reason: reason.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_secret_is_not_echoed() {
+        std::env::set_var("TEST_CONFIG_SECRET", "hunter2-not-a-number");
+        let err = parse_optional_secret_var::<u32>("TEST_CONFIG_SECRET").unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("TEST_CONFIG_SECRET"), "{message}");
+        assert!(!message.contains("hunter2"), "{message}");
+    }
+
+    #[test]
+    fn invalid_setting_names_its_value() {
+        std::env::set_var("TEST_CONFIG_SETTING", "not-a-number");
+        let err = parse_var::<u32>("TEST_CONFIG_SETTING", "1").unwrap_err();
+        assert!(err.to_string().contains("`not-a-number`"), "{err}");
+    }
+
+    // one test for everything going through `from_env`, as the environment is shared by the whole test binary
+    #[test]
+    fn out_of_range_settings_are_rejected() {
+        // left alone when set, the database tests connect through it
+        if dotenv::var("DATABASE_URL").is_err() {
+            std::env::set_var("DATABASE_URL", "postgres://localhost/payments");
+        }
+
+        std::env::set_var("MIN_TRANSFER_AMOUNT", "10");
+        std::env::set_var("MAX_TRANSFER_AMOUNT", "5");
+        let err = Config::from_env().unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { var: "MAX_TRANSFER_AMOUNT", .. }), "{err}");
+        assert!(err.to_string().contains("not below MIN_TRANSFER_AMOUNT"), "{err}");
+        std::env::remove_var("MIN_TRANSFER_AMOUNT");
+        std::env::remove_var("MAX_TRANSFER_AMOUNT");
+
+        for port in ["0", "65536"] {
+            std::env::set_var("PORT", port);
+            let err = Config::from_env().unwrap_err();
+            assert!(matches!(err, ConfigError::Invalid { var: "PORT", .. }), "{err}");
+        }
+        std::env::remove_var("PORT");
+
+        Config::from_env().unwrap();
+    }
+}
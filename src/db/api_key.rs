@@ -0,0 +1,78 @@
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// `read` keys may make GET requests, `write` keys everything else
+pub const API_KEY_SCOPES: [&str; 2] = ["read", "write"];
+
+// An API key as its owner sees it, never with the key itself
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub label: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>, // last request authenticated with the key
+}
+
+const API_KEY_COLUMNS: &str = "id, label, scopes, created_at, last_used_at";
+
+// a new random key, `pk_` followed by 32 bytes in hex
+pub fn generate_key() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("pk_{}", hex::encode(bytes))
+}
+
+// what is stored in place of the key, a plain hash is enough for 256 random bits
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+// Database repository for API keys, `AuthService::verify_api_key` checks them on requests
+pub struct ApiKeyRepository {
+    pool: PgPool,
+}
+
+impl ApiKeyRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(&self, user_id: Uuid, label: &str, scopes: &[String], key: &str) -> Result<ApiKey, sqlx::Error> {
+        sqlx::query_as::<_, ApiKey>(&format!(
+            r#"
+            INSERT INTO api_keys (user_id, label, scopes, key_hash)
+            VALUES ($1, $2, $3, $4)
+            RETURNING {API_KEY_COLUMNS}
+            "#
+        ))
+        .bind(user_id)
+        .bind(label)
+        .bind(scopes)
+        .bind(hash_key(key))
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    // the user's keys, newest first
+    pub async fn list(&self, user_id: Uuid) -> Result<Vec<ApiKey>, sqlx::Error> {
+        sqlx::query_as::<_, ApiKey>(&format!(
+            "SELECT {API_KEY_COLUMNS} FROM api_keys WHERE user_id = $1 ORDER BY created_at DESC, id"
+        ))
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    // revokes the key for good, false when the user has no such key
+    pub async fn delete(&self, user_id: Uuid, key_id: Uuid) -> Result<bool, sqlx::Error> {
+        sqlx::query!("DELETE FROM api_keys WHERE id = $1 AND user_id = $2", key_id, user_id)
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected() == 1)
+    }
+}
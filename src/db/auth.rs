@@ -1,10 +1,19 @@
 use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
 use sqlx::PgPool;
 use uuid::Uuid;
 
+use crate::currency::Currency;
+
 // use crate::db_schema::User;
 use super::user::User;
 
+// Outcome of `AuthRepository::close_account`
+pub enum AccountClosure {
+    Closed,
+    NonzeroBalance(Decimal), // the account still holds money and was left open
+}
+
 // Database repository
 pub struct AuthRepository {
     pool: PgPool,
@@ -20,16 +29,25 @@ impl AuthRepository {
         email: &str,
         password_hash: &str,
         full_name: Option<&str>,
+        currency: &Currency,
     ) -> Result<(Uuid, String), sqlx::Error> {
+        // the primary wallet comes with the account
         sqlx::query!(
             r#"
-            INSERT INTO users (email, password_hash, full_name)
-            VALUES ($1, $2, $3)
-            RETURNING id, email
+            WITH new_user AS (
+                INSERT INTO users (email, password_hash, full_name, currency)
+                VALUES ($1, $2, $3, $4)
+                RETURNING id, email, currency
+            ), primary_wallet AS (
+                INSERT INTO wallets (user_id, currency)
+                SELECT id, currency FROM new_user
+            )
+            SELECT id AS "id!", email AS "email!" FROM new_user
             "#,
             email,
             password_hash,
-            full_name
+            full_name,
+            currency.as_str()
         )
         .fetch_one(&self.pool)
         .await
@@ -39,10 +57,10 @@ impl AuthRepository {
     pub async fn find_user_by_email(
         &self,
         email: &str,
-    ) -> Result<Option<(Uuid, String, String)>, sqlx::Error> {
+    ) -> Result<Option<(Uuid, String, String, bool, String, String)>, sqlx::Error> {
         sqlx::query!(
             r#"
-            SELECT id, email, password_hash
+            SELECT id, email, password_hash, email_verified, role, status
             FROM users
             WHERE email = $1
             "#,
@@ -50,7 +68,200 @@ impl AuthRepository {
         )
         .fetch_optional(&self.pool)
         .await
-        .map(|row| row.map(|row| (row.id, row.email, row.password_hash)))
+        .map(|row| row.map(|row| (row.id, row.email, row.password_hash, row.email_verified, row.role, row.status)))
+    }
+
+    // balance (the cached ledger sum) and currency of the account, handed out along with fresh tokens
+    pub async fn find_balance(&self, user_id: Uuid) -> Result<(Decimal, Currency), sqlx::Error> {
+        sqlx::query!(
+            r#"SELECT balance, currency AS "currency: Currency" FROM users WHERE id = $1"#,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map(|row| (row.balance, row.currency))
+    }
+
+    pub async fn find_password_hash(&self, user_id: Uuid) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar!("SELECT password_hash FROM users WHERE id = $1", user_id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    // Marks the account closed and drops its refresh tokens and API keys, unless it still holds money. The row lock is the
+    // one every balance change takes, so nothing can be credited between the balance check and the closure.
+    // The row and its ledger history stay in place
+    pub async fn close_account(&self, user_id: Uuid) -> Result<AccountClosure, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!("SELECT id FROM users WHERE id = $1 FOR UPDATE", user_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        // every wallet of the account has to be empty, not just the primary one, funds on hold included
+        let nonzero = sqlx::query_scalar!(
+            r#"
+            SELECT SUM(delta) AS "balance!"
+            FROM (
+                SELECT wallet_id, delta FROM ledger_entries WHERE user_id = $1
+                UNION ALL
+                SELECT sender_wallet_id, amount FROM holds WHERE sender_id = $1 AND status = 'held'
+            ) AS funds
+            GROUP BY wallet_id
+            HAVING SUM(delta) <> 0
+            LIMIT 1
+            "#,
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(balance) = nonzero {
+            return Ok(AccountClosure::NonzeroBalance(balance));
+        }
+
+        sqlx::query!(
+            "UPDATE users SET status = 'closed', updated_at = CURRENT_TIMESTAMP WHERE id = $1",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM refresh_tokens WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM api_keys WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO audit_logs (user_id, action, entity_type, entity_id, changes)
+            VALUES ($1, 'account_closed', 'user', $1, $2)
+            "#,
+            user_id,
+            serde_json::json!({ "status": "closed" })
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(AccountClosure::Closed)
+    }
+
+    // swaps the hash only while it still is `old_hash`
+    pub async fn replace_password_hash(&self, user_id: Uuid, old_hash: &str, new_hash: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE users SET password_hash = $3, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND password_hash = $2
+            "#,
+            user_id,
+            old_hash,
+            new_hash
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected() == 1)
+    }
+
+    // replaces any pending verification of the user
+    pub async fn store_email_verification_token(
+        &self,
+        user_id: Uuid,
+        token: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO email_verification_tokens (user_id, token, expires_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id) DO UPDATE
+            SET token = EXCLUDED.token, expires_at = EXCLUDED.expires_at, created_at = CURRENT_TIMESTAMP
+            "#,
+            user_id,
+            token,
+            sqlx::types::time::OffsetDateTime::from_unix_timestamp(expires_at.timestamp()).unwrap()
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // consumes an unexpired verification token, returning the user it belongs to
+    pub async fn consume_email_verification_token(&self, token: &str) -> Result<Option<Uuid>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            DELETE FROM email_verification_tokens
+            WHERE token = $1 AND expires_at > CURRENT_TIMESTAMP
+            RETURNING user_id
+            "#,
+            token
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn mark_email_verified(&self, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        sqlx::query!(
+            "UPDATE users SET email_verified = TRUE, updated_at = CURRENT_TIMESTAMP WHERE id = $1",
+            user_id
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected() == 1)
+    }
+
+    // email, encrypted TOTP secret and whether 2FA is active, `None` for an unknown user
+    pub async fn find_totp(&self, user_id: Uuid) -> Result<Option<(String, Option<String>, bool)>, sqlx::Error> {
+        sqlx::query!(
+            "SELECT email, totp_secret, totp_enabled FROM users WHERE id = $1",
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| row.map(|row| (row.email, row.totp_secret, row.totp_enabled)))
+    }
+
+    // replaces a secret which is not active yet, `false` once 2FA is enabled
+    pub async fn store_totp_secret(&self, user_id: Uuid, encrypted_secret: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE users SET totp_secret = $2, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND NOT totp_enabled
+            "#,
+            user_id,
+            encrypted_secret
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected() == 1)
+    }
+
+    // activates the stored secret, `false` when there is none or it is active already
+    pub async fn enable_totp(&self, user_id: Uuid, encrypted_secret: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE users SET totp_enabled = TRUE, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND totp_secret = $2 AND NOT totp_enabled
+            "#,
+            user_id,
+            encrypted_secret
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected() == 1)
+    }
+
+    // records `step` as the latest one a code was accepted for, `false` when that step or a later one was used already
+    pub async fn use_totp_step(&self, user_id: Uuid, step: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE users SET totp_last_step = $2
+            WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)
+            "#,
+            user_id,
+            step
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected() == 1)
     }
 
     pub async fn store_refresh_token(
@@ -73,15 +284,142 @@ impl AuthRepository {
         Ok(())
     }
 
-    pub async fn verify_refresh_token(&self, token: &str) -> Result<Option<User>, sqlx::Error> {
+    // Deletes the token, reporting whether it still existed. Concurrent callers racing on the
+    // same token can't both see `true`, which makes it safe to gate a rotation on
+    pub async fn revoke_refresh_token(&self, token: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query!("DELETE FROM refresh_tokens WHERE token = $1", token)
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected() == 1)
+    }
+
+    // only tokens past their expiry plus `leeway` are gone, `verify_refresh_token` still accepts the others
+    pub async fn delete_expired_refresh_tokens(&self, leeway: u64) -> Result<u64, sqlx::Error> {
+        sqlx::query!(
+            "DELETE FROM refresh_tokens WHERE expires_at <= CURRENT_TIMESTAMP - make_interval(secs => $1)",
+            leeway as f64
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected())
+    }
+
+    // blocks the access token with this jti until `expires_at`, revoking it twice is a no-op
+    pub async fn block_access_token(
+        &self,
+        jti: Uuid,
+        user_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
         sqlx::query!(
             r#"
-            SELECT u.id, u.email, u.password_hash, u.full_name, u.created_at, u.updated_at
+            INSERT INTO token_blocklist (jti, user_id, expires_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (jti) DO NOTHING
+            "#,
+            jti,
+            user_id,
+            sqlx::types::time::OffsetDateTime::from_unix_timestamp(expires_at.timestamp()).unwrap()
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Owner and scopes of the API key with this hash, stamping it as used. Keys of closed accounts are deleted
+    // along with the closure
+    pub async fn use_api_key(&self, key_hash: &str) -> Result<Option<(Uuid, Vec<String>)>, sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE api_keys SET last_used_at = CURRENT_TIMESTAMP
+            WHERE key_hash = $1
+            RETURNING user_id, scopes
+            "#,
+            key_hash
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| row.map(|row| (row.user_id, row.scopes)))
+    }
+
+    pub async fn is_access_token_blocked(&self, jti: Uuid) -> Result<bool, sqlx::Error> {
+        sqlx::query!("SELECT 1 AS found FROM token_blocklist WHERE jti = $1", jti)
+            .fetch_optional(&self.pool)
+            .await
+            .map(|row| row.is_some())
+    }
+
+    // tokens past their expiry plus `leeway` are rejected on their own, their blocklist rows are no longer needed
+    pub async fn delete_expired_blocked_tokens(&self, leeway: u64) -> Result<u64, sqlx::Error> {
+        sqlx::query!(
+            "DELETE FROM token_blocklist WHERE expires_at <= CURRENT_TIMESTAMP - make_interval(secs => $1)",
+            leeway as f64
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected())
+    }
+
+    // refresh tokens the user can still use within `leeway`, one per logged in session
+    pub async fn count_active_refresh_tokens(&self, user_id: Uuid, leeway: u64) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!"
+            FROM refresh_tokens
+            WHERE user_id = $1 AND expires_at > CURRENT_TIMESTAMP - make_interval(secs => $2)
+            "#,
+            user_id,
+            leeway as f64
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    // drops expired tokens and all but the `keep` most recent active ones, active as in `count_active_refresh_tokens`
+    pub async fn evict_oldest_refresh_tokens(
+        &self,
+        user_id: Uuid,
+        keep: i64,
+        leeway: u64,
+    ) -> Result<u64, sqlx::Error> {
+        sqlx::query!(
+            r#"
+            DELETE FROM refresh_tokens
+            WHERE user_id = $1
+              AND (
+                expires_at <= CURRENT_TIMESTAMP - make_interval(secs => $3)
+                OR id NOT IN (
+                    SELECT id FROM refresh_tokens
+                    WHERE user_id = $1 AND expires_at > CURRENT_TIMESTAMP - make_interval(secs => $3)
+                    ORDER BY created_at DESC, id DESC
+                    LIMIT $2
+                )
+              )
+            "#,
+            user_id,
+            keep,
+            leeway as f64
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected())
+    }
+
+    // `leeway` extends the expiry by the same clock skew allowance used for access tokens
+    pub async fn verify_refresh_token(
+        &self,
+        token: &str,
+        leeway: u64,
+    ) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query!(
+            r#"
+            SELECT u.id, u.email, u.password_hash, u.full_name, u.currency, u.status, u.role, u.email_verified, u.created_at, u.updated_at
             FROM users u
             INNER JOIN refresh_tokens rt ON rt.user_id = u.id
-            WHERE rt.token = $1 AND rt.expires_at > CURRENT_TIMESTAMP
+            WHERE rt.token = $1 AND rt.expires_at > CURRENT_TIMESTAMP - make_interval(secs => $2)
             "#,
-            token
+            token,
+            leeway as f64
         )
         .fetch_optional(&self.pool)
         .await
@@ -92,7 +430,10 @@ impl AuthRepository {
                 password_hash: real_user.password_hash,
                 full_name: real_user.full_name,
                 balance: 0.into(),
-                status: "active".to_string(),
+                currency: real_user.currency,
+                status: real_user.status,
+                role: real_user.role,
+                email_verified: real_user.email_verified,
                 created_at: super::utils::convert_offsetdt_to_dt(real_user.created_at.unwrap()),
                 updated_at: super::utils::convert_offsetdt_to_dt(real_user.updated_at.unwrap()),
             })
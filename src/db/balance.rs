@@ -0,0 +1,204 @@
+use std::fmt;
+
+use rust_decimal::Decimal;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use super::ledger;
+
+#[derive(Debug)]
+pub enum BalanceError {
+    UserNotFound,
+    InsufficientFunds,
+    BalanceCapExceeded,
+    AccountFrozen,
+    AccountClosed,
+    VersionConflict, // the wallet changed since the version the caller read
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for BalanceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BalanceError::UserNotFound => write!(f, "user not found"),
+            BalanceError::InsufficientFunds => write!(f, "insufficient funds"),
+            BalanceError::BalanceCapExceeded => write!(f, "balance cap exceeded"),
+            BalanceError::AccountFrozen => write!(f, "account frozen"),
+            BalanceError::AccountClosed => write!(f, "account closed"),
+            BalanceError::VersionConflict => write!(f, "wallet changed concurrently"),
+            BalanceError::Database(err) => write!(f, "database error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for BalanceError {}
+
+impl From<sqlx::Error> for BalanceError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            // rows written for the entry, like its `transactions` row, reference a user that doesn't exist
+            sqlx::Error::Database(ref db_err) if db_err.is_foreign_key_violation() => BalanceError::UserNotFound,
+            err => BalanceError::Database(err),
+        }
+    }
+}
+
+// The balance a movement applies to
+#[derive(Debug, Clone, Copy)]
+pub enum Account {
+    Primary(Uuid), // the wallet in the account currency, by user id
+    Wallet(Uuid),  // any wallet, by its own id
+}
+
+// An account whose owner's row is locked
+struct Locked {
+    status: String,
+    user_id: Uuid,
+    wallet_id: Uuid,
+    primary: bool,
+}
+
+// Lock the owner's row, serializing every balance change of that user until the transaction ends.
+// A freeze or closure takes the same lock, so no movement can slip past one that just committed.
+// NO KEY UPDATE leaves the key share lock of a foreign key check alone, a ledger row inserted before the
+// balance changes would otherwise have two concurrent movements deadlock upgrading it
+async fn lock_row(conn: &mut PgConnection, account: Account) -> Result<Locked, BalanceError> {
+    let locked = match account {
+        Account::Primary(user_id) => {
+            sqlx::query_as!(
+                Locked,
+                r#"
+                SELECT u.status, u.id AS user_id, w.id AS wallet_id, TRUE AS "primary!"
+                FROM users u
+                JOIN wallets w ON w.user_id = u.id AND w.currency = u.currency
+                WHERE u.id = $1
+                FOR NO KEY UPDATE OF u
+                "#,
+                user_id
+            )
+            .fetch_optional(&mut *conn)
+            .await?
+        }
+        Account::Wallet(wallet_id) => {
+            sqlx::query_as!(
+                Locked,
+                r#"
+                SELECT u.status, u.id AS user_id, w.id AS wallet_id, w.currency = u.currency AS "primary!"
+                FROM wallets w
+                JOIN users u ON u.id = w.user_id
+                WHERE w.id = $1
+                FOR NO KEY UPDATE OF u
+                "#,
+                wallet_id
+            )
+            .fetch_optional(&mut *conn)
+            .await?
+        }
+    };
+    // wallets are never removed, one that can't be found has no account behind it
+    locked.ok_or(BalanceError::UserNotFound)
+}
+
+// `lock_row` for movements the account makes itself, refused while it is frozen or closed
+async fn lock_user(conn: &mut PgConnection, account: Account) -> Result<Locked, BalanceError> {
+    let locked = lock_row(&mut *conn, account).await?;
+    match locked.status.as_str() {
+        "frozen" => Err(BalanceError::AccountFrozen),
+        "closed" => Err(BalanceError::AccountClosed),
+        _ => Ok(locked),
+    }
+}
+
+// Record `delta` in the ledger and return the wallet's new sum, which `users.balance` caches for the primary wallet.
+// Only debits bump the wallet's version, money coming in can't make a debit decided on an older read overdraw
+async fn apply_delta(conn: &mut PgConnection, locked: &Locked, delta: Decimal, tx_id: Uuid) -> Result<Decimal, BalanceError> {
+    ledger::append_entry(&mut *conn, locked.user_id, locked.wallet_id, delta, tx_id).await?;
+    if delta < Decimal::ZERO {
+        sqlx::query!("UPDATE wallets SET version = version + 1 WHERE id = $1", locked.wallet_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+    let balance = ledger::wallet_balance_in_tx(&mut *conn, locked.wallet_id).await?;
+    if locked.primary {
+        sqlx::query!("UPDATE users SET balance = $1 WHERE id = $2", balance, locked.user_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(balance)
+}
+
+// Lock the owner's row, make sure the ledger balance of the account covers `amount` and append the debit entry for `tx_id`,
+// returning the new balance. Must run inside the caller's transaction so the lock is held until it commits or rolls back.
+pub async fn debit_if_sufficient(
+    conn: &mut PgConnection,
+    account: Account,
+    amount: Decimal,
+    tx_id: Uuid,
+) -> Result<Decimal, BalanceError> {
+    debit_at_version(conn, account, amount, tx_id, None).await
+}
+
+// `debit_if_sufficient` that also refuses with `VersionConflict` once the wallet moved past `expected_version`,
+// the version the caller read before deciding on the debit. None skips the check
+pub async fn debit_at_version(
+    conn: &mut PgConnection,
+    account: Account,
+    amount: Decimal,
+    tx_id: Uuid,
+    expected_version: Option<i64>,
+) -> Result<Decimal, BalanceError> {
+    let locked = lock_user(&mut *conn, account).await?;
+
+    // the wallet row is locked as well, a change still committing when the check comes is waited for and then seen
+    if let Some(expected_version) = expected_version {
+        let version = sqlx::query_scalar!("SELECT version FROM wallets WHERE id = $1 FOR NO KEY UPDATE", locked.wallet_id)
+            .fetch_one(&mut *conn)
+            .await?;
+        if version != expected_version {
+            return Err(BalanceError::VersionConflict);
+        }
+    }
+
+    let balance = ledger::wallet_balance_in_tx(&mut *conn, locked.wallet_id).await?;
+    if balance < amount {
+        return Err(BalanceError::InsufficientFunds);
+    }
+
+    apply_delta(conn, &locked, -amount, tx_id).await
+}
+
+// Lock the owner's row and append the credit entry for `tx_id` unless the result would exceed `max_balance`,
+// returning the new balance. Like the debit it relies on the caller's transaction to keep the lock until commit.
+pub async fn credit_within_cap(
+    conn: &mut PgConnection,
+    account: Account,
+    amount: Decimal,
+    max_balance: Option<Decimal>,
+    tx_id: Uuid,
+) -> Result<Decimal, BalanceError> {
+    let locked = lock_user(&mut *conn, account).await?;
+
+    let balance = ledger::wallet_balance_in_tx(&mut *conn, locked.wallet_id).await?;
+    if max_balance.is_some_and(|max_balance| balance + amount > max_balance) {
+        return Err(BalanceError::BalanceCapExceeded);
+    }
+
+    apply_delta(conn, &locked, amount, tx_id).await
+}
+
+// Admin correction or held funds going back: append `delta` of either sign for `tx_id` and return the new balance.
+// Unlike the user's own movements it goes through on a frozen account, but never on a closed one or below zero,
+// and ignores the cap
+pub async fn adjust(conn: &mut PgConnection, account: Account, delta: Decimal, tx_id: Uuid) -> Result<Decimal, BalanceError> {
+    let locked = lock_row(&mut *conn, account).await?;
+    if locked.status == "closed" {
+        return Err(BalanceError::AccountClosed);
+    }
+
+    let balance = ledger::wallet_balance_in_tx(&mut *conn, locked.wallet_id).await?;
+    if balance + delta < Decimal::ZERO {
+        return Err(BalanceError::InsufficientFunds);
+    }
+
+    apply_delta(conn, &locked, delta, tx_id).await
+}
@@ -0,0 +1,130 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::utils::convert_offsetdt_to_dt;
+
+// A queued transaction export, `content` holds the CSV once completed
+#[derive(Debug)]
+pub struct ExportJob {
+    pub id: Uuid,
+    pub status: String,
+    pub content: Option<String>,
+    pub error: Option<String>,
+}
+
+// Database repository for export jobs
+pub struct ExportRepository {
+    pool: PgPool,
+}
+
+impl ExportRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create_job(&self, user_id: Uuid) -> Result<Uuid, sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO export_jobs (user_id)
+            VALUES ($1)
+            RETURNING id
+            "#,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map(|row| row.id)
+    }
+
+    pub async fn find_job(&self, job_id: Uuid, user_id: Uuid) -> Result<Option<ExportJob>, sqlx::Error> {
+        sqlx::query_as!(
+            ExportJob,
+            r#"
+            SELECT id, status, content, error
+            FROM export_jobs
+            WHERE id = $1 AND user_id = $2
+            "#,
+            job_id,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    // Pick the oldest pending job and mark it as processing, skipping jobs claimed by other workers
+    pub async fn claim_pending_job(&self) -> Result<Option<(Uuid, Uuid)>, sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE export_jobs SET status = 'processing'
+            WHERE id = (
+                SELECT id FROM export_jobs
+                WHERE status = 'pending'
+                ORDER BY created_at
+                FOR UPDATE SKIP LOCKED
+                LIMIT 1
+            )
+            RETURNING id, user_id
+            "#
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| row.map(|row| (row.id, row.user_id)))
+    }
+
+    // Render every transfer the user took part in as CSV
+    pub async fn build_csv(&self, user_id: Uuid) -> Result<String, sqlx::Error> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, sender_id, recipient_id, amount, created_at
+            FROM transfers
+            WHERE (sender_id = $1 OR recipient_id = $1) AND status = 'completed'
+            ORDER BY created_at, id
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut csv = String::from("id,created_at,sender_id,recipient_id,amount\n");
+        for row in rows {
+            let created_at = row
+                .created_at
+                .map(|created_at| convert_offsetdt_to_dt(created_at).to_rfc3339())
+                .unwrap_or_default();
+            csv.push_str(&format!(
+                "{},{},{},{},{}\n",
+                row.id, created_at, row.sender_id, row.recipient_id, row.amount
+            ));
+        }
+
+        Ok(csv)
+    }
+
+    pub async fn complete_job(&self, job_id: Uuid, content: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE export_jobs SET status = 'completed', content = $2, completed_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+            "#,
+            job_id,
+            content
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn fail_job(&self, job_id: Uuid, error: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE export_jobs SET status = 'failed', error = $2, completed_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+            "#,
+            job_id,
+            error
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
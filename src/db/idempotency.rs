@@ -0,0 +1,41 @@
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+// Id of the transfer created earlier with this key by the user, if the key hasn't expired.
+// Immediate transfers only keep their key once completed, scheduled ones from the moment they are queued
+pub async fn find_transfer(pool: &PgPool, user_id: Uuid, key: &str) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT t.id
+        FROM idempotency_keys k
+        JOIN transfers t ON t.id = k.transfer_id
+        WHERE k.user_id = $1 AND k.idempotency_key = $2 AND k.created_at > NOW() - INTERVAL '24 hours'
+        "#,
+        user_id,
+        key
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// Bind the key to the pending transfer, inside the transaction that completes it.
+// An expired key is taken over, false means the key is still bound to another transfer.
+// A concurrent claim of the same key waits on the primary key until the first one commits or rolls back
+pub async fn claim_key(conn: &mut PgConnection, user_id: Uuid, key: &str, transfer_id: Uuid) -> Result<bool, sqlx::Error> {
+    let claimed = sqlx::query!(
+        r#"
+        INSERT INTO idempotency_keys (user_id, idempotency_key, transfer_id)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id, idempotency_key) DO UPDATE
+        SET transfer_id = EXCLUDED.transfer_id, created_at = CURRENT_TIMESTAMP
+        WHERE idempotency_keys.created_at <= NOW() - INTERVAL '24 hours'
+        "#,
+        user_id,
+        key,
+        transfer_id
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(claimed.rows_affected() == 1)
+}
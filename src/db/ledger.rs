@@ -0,0 +1,110 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+// Where the money of a wallet stands. `available` is the ledger balance, what can be spent. `held` left it
+// already but hasn't arrived anywhere yet (uncaptured holds, unsettled external transfers), `pending_incoming` is on
+// its way in by the same means, and `total` is all three together
+#[derive(Debug, Serialize)]
+pub struct BalanceBreakdown {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub pending_incoming: Decimal,
+    pub total: Decimal,
+}
+
+// Read side of the append-only ledger, the balance helpers in `balance` do the writing
+pub struct LedgerRepository {
+    pool: PgPool,
+}
+
+impl LedgerRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    // the authoritative balance of the user's primary wallet along with the funds on hold and on their way in,
+    // the breakdown read in a single statement so its parts agree
+    pub async fn breakdown_of(&self, user_id: Uuid) -> Result<BalanceBreakdown, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let wallet_id = sqlx::query_scalar!(
+            "SELECT w.id FROM wallets w JOIN users u ON u.id = w.user_id AND u.currency = w.currency WHERE w.user_id = $1",
+            user_id
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        breakdown_in_tx(&mut conn, wallet_id).await
+    }
+}
+
+// The breakdown of a single wallet, read inside the caller's transaction
+pub async fn breakdown_in_tx(conn: &mut PgConnection, wallet_id: Uuid) -> Result<BalanceBreakdown, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT
+            (SELECT COALESCE(SUM(delta), 0) FROM ledger_entries WHERE wallet_id = $1) AS "available!",
+            (SELECT COALESCE(SUM(amount), 0) FROM holds WHERE sender_wallet_id = $1 AND status = 'held')
+                + (SELECT COALESCE(SUM(amount), 0) FROM transfers
+                   WHERE sender_wallet_id = $1 AND status = 'pending' AND transfer_kind = 'external') AS "held!",
+            (SELECT COALESCE(SUM(amount), 0) FROM holds WHERE receiver_wallet_id = $1 AND status = 'held')
+                + (SELECT COALESCE(SUM(amount), 0) FROM transfers
+                   WHERE receiver_wallet_id = $1 AND status = 'pending' AND transfer_kind = 'external') AS "pending_incoming!"
+        "#,
+        wallet_id
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(BalanceBreakdown {
+        available: row.available,
+        held: row.held,
+        pending_incoming: row.pending_incoming,
+        total: row.available + row.held + row.pending_incoming,
+    })
+}
+
+// The `available` balance of `breakdown_of`, read inside the caller's transaction
+pub async fn balance_in_tx(conn: &mut PgConnection, user_id: Uuid) -> Result<Decimal, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT COALESCE(SUM(e.delta), 0) AS "balance!"
+        FROM ledger_entries e
+        JOIN wallets w ON w.id = e.wallet_id
+        JOIN users u ON u.id = w.user_id AND u.currency = w.currency
+        WHERE e.user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_one(conn)
+    .await
+}
+
+// Balance of a single wallet, read inside the caller's transaction
+pub async fn wallet_balance_in_tx(conn: &mut PgConnection, wallet_id: Uuid) -> Result<Decimal, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT COALESCE(SUM(delta), 0) AS "balance!" FROM ledger_entries WHERE wallet_id = $1"#,
+        wallet_id
+    )
+    .fetch_one(conn)
+    .await
+}
+
+pub async fn append_entry(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    wallet_id: Uuid,
+    delta: Decimal,
+    tx_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO ledger_entries (user_id, wallet_id, delta, tx_id) VALUES ($1, $2, $3, $4)",
+        user_id,
+        wallet_id,
+        delta,
+        tx_id
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
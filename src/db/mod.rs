@@ -1,4 +1,10 @@
+pub mod api_key;
 pub mod auth;
+pub mod balance;
+pub mod export;
+pub mod idempotency;
+pub mod ledger;
 pub mod tx;
 pub mod user;
 pub mod utils;
+pub mod wallet;
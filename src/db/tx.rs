@@ -1,6 +1,7 @@
 use chrono::{DateTime, Utc};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -11,21 +12,134 @@ pub struct Transaction {
     pub transaction_type: TransactionType,
     pub status: TransactionStatus,
     pub reference_id: Option<String>,
+    pub external_reference: Option<String>,
     pub description: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
     Transfer,
+    Adjustment,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl TransactionType {
+    // value stored in `transactions.transaction_type`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Transfer => "transfer",
+            TransactionType::Adjustment => "adjustment",
+        }
+    }
+}
+
+// Also the `transaction_status` Postgres enum tracking the lifecycle of a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "transaction_status", rename_all = "lowercase")]
 pub enum TransactionStatus {
     Pending,
     Completed,
     Failed,
 }
+
+// Also the `transfer_kind` Postgres enum: internal transfers complete at once, external ones hold the
+// sender's funds until they settle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "transfer_kind", rename_all = "lowercase")]
+pub enum TransferKind {
+    #[default]
+    Internal,
+    External,
+}
+
+// Also the `hold_status` Postgres enum, a hold stays `Held` until it is captured or released
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "hold_status", rename_all = "lowercase")]
+pub enum HoldStatus {
+    Held,
+    Captured,
+    Released,
+}
+
+impl TransactionStatus {
+    // value stored in `transactions.status`, which is plain text unlike `transfers.status`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionStatus::Pending => "pending",
+            TransactionStatus::Completed => "completed",
+            TransactionStatus::Failed => "failed",
+        }
+    }
+}
+
+// Write a ledger entry for a single sided money movement, meant to run in the same
+// database transaction as the balance change it records
+pub async fn insert_transaction(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    amount: Decimal,
+    transaction_type: TransactionType,
+    status: TransactionStatus,
+    reference_id: Option<&str>,
+    description: Option<&str>,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO transactions (user_id, amount, transaction_type, status, reference_id, description)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id
+        "#,
+        user_id,
+        amount,
+        transaction_type.as_str(),
+        status.as_str(),
+        reference_id,
+        description
+    )
+    .fetch_one(conn)
+    .await
+}
+
+// Ledger entry of the given type the user recorded under `reference_id`, used to replay idempotent requests
+pub async fn find_transaction_by_reference(
+    pool: &PgPool,
+    user_id: Uuid,
+    transaction_type: TransactionType,
+    reference_id: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT id FROM transactions
+        WHERE user_id = $1 AND transaction_type = $2 AND reference_id = $3
+        "#,
+        user_id,
+        transaction_type.as_str(),
+        reference_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// Attach the reference an external system booked the entry under, a deposit's reference is unique per user
+pub async fn set_external_reference(
+    conn: &mut PgConnection,
+    transaction_id: Uuid,
+    external_reference: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE transactions SET external_reference = $2 WHERE id = $1",
+        transaction_id,
+        external_reference
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
@@ -11,14 +11,55 @@ pub struct User {
     pub password_hash: String,
     pub full_name: String,
     pub balance: Decimal,
+    pub currency: String,
     pub status: String,
+    pub role: String,
+    pub email_verified: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// Authorization role of a user, stored as text in `users.role`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "user" => Ok(Role::User),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!("unknown role `{other}`")),
+        }
+    }
+}
+
+// Value of `users.status`, anything but `Active` keeps the user from logging in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum UserAccountStatus {
     Active,
-    Inactive,
-    Blocked,
+    Pending, // registered but not activated yet
+    Frozen,  // blocked by an admin, see the admin routes
+    Closed,
+}
+
+impl std::str::FromStr for UserAccountStatus {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "active" => Ok(UserAccountStatus::Active),
+            "pending" => Ok(UserAccountStatus::Pending),
+            "frozen" => Ok(UserAccountStatus::Frozen),
+            "closed" => Ok(UserAccountStatus::Closed),
+            other => Err(format!("unknown account status `{other}`")),
+        }
+    }
 }
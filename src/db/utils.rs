@@ -2,9 +2,34 @@ use chrono::{DateTime, Utc};
 use sqlx::types::time::OffsetDateTime;
 
 pub fn convert_offsetdt_to_dt(offset_datetime: OffsetDateTime) -> DateTime<Utc> {
-    // Get the Unix timestamp from OffsetDateTime
-    let timestamp = offset_datetime.unix_timestamp();
+    // Get the Unix timestamp in seconds along with the sub-second part
+    let seconds = offset_datetime.unix_timestamp();
+    let nanoseconds = offset_datetime.nanosecond();
 
     // Convert the timestamp into a DateTime<Utc>
-    DateTime::<Utc>::from_timestamp_micros(timestamp).unwrap_or(Utc::now())
+    DateTime::<Utc>::from_timestamp(seconds, nanoseconds).unwrap_or(Utc::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use sqlx::types::time::{OffsetDateTime, UtcOffset};
+
+    use super::convert_offsetdt_to_dt;
+
+    #[test]
+    fn conversion_keeps_the_instant_through_serde() {
+        // 2024-03-01T12:34:56.789Z, seen from a +02:00 offset
+        let offset_datetime = OffsetDateTime::from_unix_timestamp_nanos(1_709_296_496_789_000_000)
+            .unwrap()
+            .to_offset(UtcOffset::from_hms(2, 0, 0).unwrap());
+
+        let converted = convert_offsetdt_to_dt(offset_datetime);
+        assert_eq!(converted.timestamp(), 1_709_296_496);
+        assert_eq!(converted.timestamp_subsec_millis(), 789);
+
+        let json = serde_json::to_string(&converted).unwrap();
+        assert_eq!(json, "\"2024-03-01T12:34:56.789Z\"");
+        assert_eq!(serde_json::from_str::<DateTime<Utc>>(&json).unwrap(), converted);
+    }
 }
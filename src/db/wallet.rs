@@ -0,0 +1,117 @@
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::currency::Currency;
+
+// A user's money in one currency, the primary wallet is the one in the account currency
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Wallet {
+    pub id: Uuid,
+    pub currency: Currency,
+    pub balance: Decimal, // sum of the wallet's ledger entries
+    pub primary: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+// Owner and currency of a wallet, what a movement on it is checked against
+#[derive(Debug)]
+pub struct WalletRef {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub currency: Currency,
+}
+
+const WALLET_QUERY: &str = r#"
+    SELECT w.id, w.currency, COALESCE(SUM(e.delta), 0) AS balance, w.currency = u.currency AS "primary", w.created_at
+    FROM wallets w
+    JOIN users u ON u.id = w.user_id
+    LEFT JOIN ledger_entries e ON e.wallet_id = w.id
+"#;
+
+// Database repository for wallets, balances are moved through `balance` like any other
+pub struct WalletRepository {
+    pool: PgPool,
+}
+
+impl WalletRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    // the user's wallets, primary first
+    pub async fn list(&self, user_id: Uuid) -> Result<Vec<Wallet>, sqlx::Error> {
+        list_in_tx(&mut *self.pool.acquire().await?, user_id).await
+    }
+
+    // Open a wallet in `currency`, None when the user has one in it already
+    pub async fn open(&self, user_id: Uuid, currency: &Currency) -> Result<Option<Wallet>, sqlx::Error> {
+        let wallet_id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO wallets (user_id, currency)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id, currency) DO NOTHING
+            RETURNING id
+            "#,
+            user_id,
+            currency.as_str()
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some(wallet_id) = wallet_id else {
+            return Ok(None);
+        };
+        sqlx::query_as::<_, Wallet>(&format!("{WALLET_QUERY} WHERE w.id = $1 GROUP BY w.id, u.currency"))
+            .bind(wallet_id)
+            .fetch_one(&self.pool)
+            .await
+            .map(Some)
+    }
+
+    pub async fn find(&self, wallet_id: Uuid) -> Result<Option<WalletRef>, sqlx::Error> {
+        sqlx::query_as!(
+            WalletRef,
+            r#"SELECT id, user_id, currency AS "currency: Currency" FROM wallets WHERE id = $1"#,
+            wallet_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    // bumped with every debit of the wallet, see `balance::debit_at_version`
+    pub async fn version(&self, wallet_id: Uuid) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar!("SELECT version FROM wallets WHERE id = $1", wallet_id)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    // the user's wallet in `currency`, or the primary one without a currency
+    pub async fn find_for(&self, user_id: Uuid, currency: Option<&Currency>) -> Result<Option<WalletRef>, sqlx::Error> {
+        sqlx::query_as!(
+            WalletRef,
+            r#"
+            SELECT w.id, w.user_id, w.currency AS "currency: Currency"
+            FROM wallets w
+            JOIN users u ON u.id = w.user_id
+            WHERE w.user_id = $1 AND w.currency = COALESCE($2, u.currency)
+            "#,
+            user_id,
+            currency.map(Currency::as_str)
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+}
+
+// `WalletRepository::list`, read inside the caller's transaction
+pub async fn list_in_tx(conn: &mut PgConnection, user_id: Uuid) -> Result<Vec<Wallet>, sqlx::Error> {
+    sqlx::query_as::<_, Wallet>(&format!(
+        "{WALLET_QUERY} WHERE w.user_id = $1 GROUP BY w.id, u.currency ORDER BY w.currency = u.currency DESC, w.created_at, w.id"
+    ))
+    .bind(user_id)
+    .fetch_all(conn)
+    .await
+}
@@ -0,0 +1,538 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{rejection::PathRejection, Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::db::{
+    balance::{self, Account, BalanceError},
+    tx::{insert_transaction, TransactionStatus, TransactionType},
+    user::User,
+};
+
+use super::{
+    auth::AuthService,
+    error::ApiError,
+    tx::{TransferDetails, TxConfig},
+    utils::{id_from_path, AdminUser, JsonBody},
+};
+
+const MAX_REASON_CHARS: usize = 500;
+
+// profile of any user, for support and fraud investigations
+async fn get_user(
+    AdminUser(admin_id): AdminUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    user_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user_id = id_from_path(user_id)?;
+
+    match sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(&pool)
+        .await
+    {
+        Ok(Some(user)) => {
+            tracing::info!("Admin {admin_id} looked up user: {user_id}");
+            Ok((StatusCode::OK, Json(user)))
+        }
+        Ok(None) => Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found")),
+        Err(err) => {
+            tracing::error!("Failed to look up user {user_id}: {err}");
+            Err(ApiError::internal("Failed to look up user"))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct TraceLedgerEntry {
+    pub user_id: Uuid,
+    pub delta: Decimal,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct TraceAuditEntry {
+    pub user_id: Option<Uuid>, // who acted
+    pub action: String,
+    pub changes: Option<serde_json::Value>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// A transfer with everything recorded about it: where it originated (`channel`), the balance changes it made
+// and its audit trail, oldest first
+#[derive(Debug, Serialize)]
+pub struct TransferTrace {
+    #[serde(flatten)]
+    pub transfer: TransferDetails,
+    pub ledger_entries: Vec<TraceLedgerEntry>,
+    pub audit_entries: Vec<TraceAuditEntry>,
+}
+
+async fn load_transfer_trace(pool: &PgPool, transfer_id: Uuid) -> Result<Option<TransferTrace>, sqlx::Error> {
+    let transfer = sqlx::query_as::<_, TransferDetails>(
+        r#"
+        SELECT id, transfer_no, sender_id, recipient_id AS receiver_id, sender_wallet_id, receiver_wallet_id, amount, currency, description, channel, metadata, reversed_tx_id, refunded_amount, transfer_kind, status, scheduled_for, settles_at, created_at, updated_at
+        FROM transfers
+        WHERE id = $1
+        "#,
+    )
+    .bind(transfer_id)
+    .fetch_optional(pool)
+    .await?;
+    let Some(transfer) = transfer else {
+        return Ok(None);
+    };
+
+    let ledger_entries = sqlx::query_as::<_, TraceLedgerEntry>(
+        "SELECT user_id, delta, created_at FROM ledger_entries WHERE tx_id = $1 ORDER BY created_at, id",
+    )
+    .bind(transfer_id)
+    .fetch_all(pool)
+    .await?;
+    let audit_entries = sqlx::query_as::<_, TraceAuditEntry>(
+        r#"
+        SELECT user_id, action, changes, created_at FROM audit_logs
+        WHERE entity_type = 'transfer' AND entity_id = $1
+        ORDER BY created_at, id
+        "#,
+    )
+    .bind(transfer_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(Some(TransferTrace {
+        transfer,
+        ledger_entries,
+        audit_entries,
+    }))
+}
+
+// any transfer with its ledger entries and audit trail, for support and fraud investigations
+async fn trace_transfer(
+    AdminUser(admin_id): AdminUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    transfer_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    let transfer_id = id_from_path(transfer_id)?;
+
+    match load_transfer_trace(&pool, transfer_id).await {
+        Ok(Some(trace)) => {
+            tracing::info!("Admin {admin_id} traced transfer: {transfer_id}");
+            Ok((StatusCode::OK, Json(trace)))
+        }
+        Ok(None) => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "transaction_not_found",
+            "Transaction not found",
+        )),
+        Err(err) => {
+            tracing::error!("Failed to trace transfer {transfer_id}: {err}");
+            Err(ApiError::internal("Failed to trace transfer"))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountStatusResponse {
+    pub user_id: Uuid,
+    pub status: String,
+}
+
+// Sets the account status and records which admin did it, `frozen` stops every money movement
+// in or out of the account, see `balance::lock_user`
+async fn set_account_status(
+    pool: &PgPool,
+    admin_id: Uuid,
+    user_id: Uuid,
+    freeze: bool,
+) -> Result<Option<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // unfreezing only lifts a freeze, any other status is left as it is. A closed account stays closed
+    let status = sqlx::query_scalar!(
+        r#"
+        UPDATE users
+        SET status = CASE
+                WHEN status = 'closed' THEN status
+                WHEN $2 THEN 'frozen'
+                WHEN status = 'frozen' THEN 'active'
+                ELSE status
+            END,
+            updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1
+        RETURNING status
+        "#,
+        user_id,
+        freeze
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(status) = status else {
+        return Ok(None);
+    };
+
+    sqlx::query!(
+        r#"
+        INSERT INTO audit_logs (user_id, action, entity_type, entity_id, changes)
+        VALUES ($1, $2, 'user', $3, $4)
+        "#,
+        admin_id,
+        if freeze { "account_frozen" } else { "account_unfrozen" },
+        user_id,
+        serde_json::json!({ "status": status })
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(Some(status))
+}
+
+async fn account_status_response(
+    pool: &PgPool,
+    admin_id: Uuid,
+    user_id: Result<Path<Uuid>, PathRejection>,
+    freeze: bool,
+) -> Result<impl IntoResponse, ApiError> {
+    let user_id = id_from_path(user_id)?;
+
+    match set_account_status(pool, admin_id, user_id, freeze).await {
+        Ok(Some(status)) => {
+            tracing::info!("Admin {admin_id} set status of user {user_id} to: {status}");
+            Ok((StatusCode::OK, Json(AccountStatusResponse { user_id, status })))
+        }
+        Ok(None) => Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found")),
+        Err(err) => {
+            tracing::error!("Failed to update status of user {user_id}: {err}");
+            Err(ApiError::internal("Failed to update account status"))
+        }
+    }
+}
+
+// blocks all deposits, withdrawals and transfers of the account until it is unfrozen
+async fn freeze_account(
+    AdminUser(admin_id): AdminUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    user_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    account_status_response(&pool, admin_id, user_id, true).await
+}
+
+async fn unfreeze_account(
+    AdminUser(admin_id): AdminUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    user_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    account_status_response(&pool, admin_id, user_id, false).await
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Adjustment {
+    pub amount: Decimal, // negative takes money off the account
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdjustmentResponse {
+    pub user_id: Uuid,
+    pub transaction_id: Uuid,
+    pub amount: Decimal,
+    pub balance: Decimal,
+}
+
+// Books the adjustment as its own `transactions` row and records the admin and the reason in `audit_logs`,
+// all in one transaction
+async fn apply_adjustment(
+    pool: &PgPool,
+    admin_id: Uuid,
+    user_id: Uuid,
+    amount: Decimal,
+    reason: &str,
+) -> Result<(Uuid, Decimal), BalanceError> {
+    let mut tx = pool.begin().await?;
+
+    let transaction_id = insert_transaction(
+        &mut tx,
+        user_id,
+        amount,
+        TransactionType::Adjustment,
+        TransactionStatus::Completed,
+        None,
+        Some(reason),
+    )
+    .await?;
+    let balance = balance::adjust(&mut tx, Account::Primary(user_id), amount, transaction_id).await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO audit_logs (user_id, action, entity_type, entity_id, changes)
+        VALUES ($1, 'adjustment', 'transaction', $2, $3)
+        "#,
+        admin_id,
+        transaction_id,
+        serde_json::json!({ "amount": amount, "counterparty_id": user_id, "reason": reason })
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok((transaction_id, balance))
+}
+
+// credits or debits the account outside any transfer, to correct a mistake
+async fn adjust_balance(
+    AdminUser(admin_id): AdminUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    user_id: Result<Path<Uuid>, PathRejection>,
+    JsonBody(adjustment): JsonBody<Adjustment>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user_id = id_from_path(user_id)?;
+
+    if adjustment.amount.is_zero() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "invalid_amount", "Amount must not be zero"));
+    }
+    let reason = adjustment.reason.trim();
+    if reason.is_empty() || reason.chars().count() > MAX_REASON_CHARS {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_reason",
+            format!("A reason of 1 to {MAX_REASON_CHARS} characters is required"),
+        ));
+    }
+
+    match apply_adjustment(&pool, admin_id, user_id, adjustment.amount, reason).await {
+        Ok((transaction_id, balance)) => {
+            tracing::info!("Admin {admin_id} adjusted balance of user {user_id} by {}: {transaction_id}", adjustment.amount);
+            Ok((
+                StatusCode::OK,
+                Json(AdjustmentResponse {
+                    user_id,
+                    transaction_id,
+                    amount: adjustment.amount,
+                    balance,
+                }),
+            ))
+        }
+        Err(BalanceError::UserNotFound) => Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found")),
+        Err(BalanceError::AccountClosed) => {
+            Err(ApiError::new(StatusCode::FORBIDDEN, "account_closed", "Account is closed"))
+        }
+        Err(BalanceError::InsufficientFunds) => Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "negative_balance",
+            "The adjustment would leave a negative balance",
+        )),
+        Err(err) => {
+            tracing::error!("Failed to adjust balance of user {user_id}: {err}");
+            Err(ApiError::internal("Failed to adjust balance"))
+        }
+    }
+}
+
+// every route here requires a token issued to an admin
+pub fn admin_routes(service: Arc<AuthService>, pool: PgPool, tx_config: Arc<TxConfig>) -> Router {
+    Router::new()
+        .route("/admin/users/:id", get(get_user))
+        .route("/admin/users/:id/freeze", post(freeze_account))
+        .route("/admin/users/:id/unfreeze", post(unfreeze_account))
+        .route("/admin/users/:id/adjust", post(adjust_balance))
+        .route("/admin/transfers/:id", get(trace_transfer))
+        .with_state((service, pool, tx_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use rust_decimal::Decimal;
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+
+    use crate::test_utils::{decimal, TestApp, TestResponse, TestUser};
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn adjustment_moves_the_balance_and_records_the_reason(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let admin = app.register_admin("admin").await;
+        let alice = app.register("alice").await;
+        app.deposit(&alice, "100").await;
+
+        let uri = format!("/v1/admin/users/{}/adjust", alice.id);
+        let response = app
+            .post(&uri)
+            .token(&admin.token)
+            .json(json!({ "amount": "-30.50", "reason": "duplicate deposit" }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(decimal(&response.body["balance"]), Decimal::new(6950, 2));
+        assert_eq!(app.balance(&alice).await, Decimal::new(6950, 2));
+
+        let transaction_id: uuid::Uuid = response.body["transaction_id"].as_str().unwrap().parse().unwrap();
+        let (actor, changes): (uuid::Uuid, Value) = sqlx::query_as(
+            "SELECT user_id, changes FROM audit_logs WHERE action = 'adjustment' AND entity_id = $1",
+        )
+        .bind(transaction_id)
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+        assert_eq!(actor, admin.id);
+        assert_eq!(changes["reason"], "duplicate deposit");
+        assert_eq!(changes["counterparty_id"], json!(alice.id));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn adjustment_requires_a_reason_and_covered_funds(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let admin = app.register_admin("admin").await;
+        let alice = app.register("alice").await;
+        app.deposit(&alice, "10").await;
+        let uri = format!("/v1/admin/users/{}/adjust", alice.id);
+
+        let response = app
+            .post(&uri)
+            .token(&admin.token)
+            .json(json!({ "amount": "5", "reason": "  " }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.error_code(), "invalid_reason");
+
+        let response = app
+            .post(&uri)
+            .token(&admin.token)
+            .json(json!({ "amount": "-10.01", "reason": "chargeback" }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.error_code(), "negative_balance");
+        assert_eq!(app.balance(&alice).await, Decimal::from(10));
+    }
+
+    async fn traced_channel(app: &TestApp, admin: &TestUser, transfer: &TestResponse) -> Value {
+        let id = transfer.body["id"].as_str().unwrap();
+        let response = app.get(&format!("/v1/admin/transfers/{id}")).token(&admin.token).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        response.body["channel"].clone()
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn trace_shows_the_origination_channel(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let admin = app.register_admin("admin").await;
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "100").await;
+
+        let transfer = |channel: Option<&'static str>| {
+            let mut request = app
+                .post("/v1/tx/transfer")
+                .token(&alice.token)
+                .json(json!({ "sender_id": alice.id, "receiver_id": bob.id, "amount": "1" }));
+            if let Some(channel) = channel {
+                request = request.header("X-Client-Channel", channel);
+            }
+            request.send()
+        };
+
+        let mobile = transfer(Some("Mobile")).await;
+        assert_eq!(traced_channel(&app, &admin, &mobile).await, "mobile");
+        let web = transfer(Some("web")).await;
+        assert_eq!(traced_channel(&app, &admin, &web).await, "web");
+        // missing or unknown channels count as the plain api
+        let missing = transfer(None).await;
+        assert_eq!(traced_channel(&app, &admin, &missing).await, "api");
+        let unknown = transfer(Some("smart-fridge")).await;
+        assert_eq!(traced_channel(&app, &admin, &unknown).await, "api");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn trace_lists_ledger_and_audit_entries(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let admin = app.register_admin("admin").await;
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "100").await;
+        let transfer = app.transfer(&alice, bob.id, "25").await;
+        let id = transfer.body["id"].as_str().unwrap();
+
+        let response = app.get(&format!("/v1/admin/transfers/{id}")).token(&admin.token).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(response.body["status"], "completed");
+        let ledger = response.body["ledger_entries"].as_array().unwrap();
+        assert_eq!(ledger.len(), 2);
+        assert_eq!(ledger.iter().map(|entry| decimal(&entry["delta"])).sum::<Decimal>(), Decimal::ZERO);
+        assert_eq!(response.body["audit_entries"][0]["action"], "transfer");
+
+        // the trace is for admins only
+        let response = app.get(&format!("/v1/admin/transfers/{id}")).token(&alice.token).send().await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn only_admins_can_adjust(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+
+        let response = app
+            .post(&format!("/v1/admin/users/{}/adjust", alice.id))
+            .token(&alice.token)
+            .json(json!({ "amount": "1000", "reason": "gift" }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(app.balance(&alice).await, Decimal::ZERO);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn admin_routes_refuse_user_tokens(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let admin = app.register_admin("admin").await;
+        let alice = app.register("alice").await;
+        let uri = format!("/v1/admin/users/{}", alice.id);
+
+        let response = app.get(&uri).token(&alice.token).send().await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(response.error_code(), "insufficient_privileges");
+
+        let response = app.get(&uri).token(&admin.token).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(response.body["email"], alice.email);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn frozen_accounts_cannot_transfer_until_unfrozen(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let admin = app.register_admin("admin").await;
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "100").await;
+
+        let response = app.post(&format!("/v1/admin/users/{}/freeze", alice.id)).token(&admin.token).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(response.body["status"], "frozen");
+
+        let response = app.transfer(&alice, bob.id, "10").await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(response.error_code(), "account_frozen");
+        assert_eq!(app.balance(&bob).await, Decimal::ZERO);
+
+        let response = app.post(&format!("/v1/admin/users/{}/unfreeze", alice.id)).token(&admin.token).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(response.body["status"], "active");
+
+        let response = app.transfer(&alice, bob.id, "10").await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(app.balance(&bob).await, Decimal::from(10));
+    }
+}
@@ -0,0 +1,580 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{rejection::PathRejection, Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::audit::{self, AuditAction, AuditEntry};
+use crate::currency::Currency;
+use crate::db::{
+    balance::{self, Account, BalanceError},
+    tx::{HoldStatus, TransactionStatus},
+    wallet::WalletRepository,
+};
+
+use super::{
+    auth::AuthService,
+    error::ApiError,
+    tx::{check_minor_units, complete_transfer, wallet_not_owned, TxConfig},
+    utils::{id_from_path, AuthUser, JsonBody},
+};
+
+// Authorization of a payment to `receiver_id`: the amount leaves the sender's wallet right away and
+// stays on hold until the recipient captures it, or until it is released
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewHold {
+    pub receiver_id: Uuid,
+    pub amount: Decimal,
+    #[serde(default)]
+    pub wallet_id: Option<Uuid>, // one of the sender's wallets, the primary one when left out
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>, // at most `HOLD_EXPIRY_SECS` ahead, which is also the default
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Hold {
+    pub id: Uuid,
+    pub sender_id: Uuid,
+    pub receiver_id: Uuid,
+    pub sender_wallet_id: Uuid,
+    pub receiver_wallet_id: Uuid,
+    pub amount: Decimal,
+    pub currency: Currency,
+    pub status: HoldStatus,
+    pub expires_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transfer_id: Option<Uuid>, // the transfer a captured hold became
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+const HOLD_COLUMNS: &str = "id, sender_id, receiver_id, sender_wallet_id, receiver_wallet_id, amount, currency, status, expires_at, transfer_id, created_at, updated_at";
+
+fn hold_not_found() -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "hold_not_found", "Hold not found")
+}
+
+fn hold_not_active(status: HoldStatus) -> ApiError {
+    let message = match status {
+        HoldStatus::Captured => "Hold is already captured",
+        _ => "Hold is already released",
+    };
+    ApiError::new(StatusCode::CONFLICT, "hold_not_active", message)
+}
+
+// A hold as seen by one of its parties, someone else's looks exactly like one that doesn't exist
+async fn find_hold(pool: &PgPool, user_id: Uuid, hold_id: Uuid) -> Result<Hold, ApiError> {
+    let hold = sqlx::query_as::<_, Hold>(&format!(
+        "SELECT {HOLD_COLUMNS} FROM holds WHERE id = $1 AND (sender_id = $2 OR receiver_id = $2)"
+    ))
+    .bind(hold_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await;
+
+    match hold {
+        Ok(Some(hold)) => Ok(hold),
+        Ok(None) => Err(hold_not_found()),
+        Err(err) => {
+            tracing::error!("Failed to read hold {hold_id}: {err}");
+            Err(ApiError::internal("Failed to read hold"))
+        }
+    }
+}
+
+// put part of the caller's wallet on hold for a recipient, answered 201 with the hold
+async fn create_hold(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, config)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    JsonBody(payload): JsonBody<NewHold>,
+) -> Result<impl IntoResponse, ApiError> {
+    if payload.amount <= Decimal::ZERO {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_amount",
+            "Amount must be positive",
+        ));
+    }
+    if payload.receiver_id == user_id {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "self_transfer",
+            "Cannot hold funds for self",
+        ));
+    }
+
+    let now = Utc::now();
+    let latest = now + config.hold_expiry;
+    let expires_at = payload.expires_at.unwrap_or(latest);
+    if expires_at <= now || expires_at > latest {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_expiry",
+            format!("A hold has to expire within {} seconds", config.hold_expiry.as_secs()),
+        ));
+    }
+
+    // the recipient is credited in the currency of the wallet the funds are held from
+    let wallets = WalletRepository::new(pool.clone());
+    let sender_wallet = match payload.wallet_id {
+        Some(wallet_id) => wallets.find(wallet_id).await,
+        None => wallets.find_for(user_id, None).await,
+    };
+    let sender_wallet = match sender_wallet {
+        Ok(Some(wallet)) if wallet.user_id == user_id => wallet,
+        Ok(_) => {
+            let Some(wallet_id) = payload.wallet_id else {
+                return Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found"));
+            };
+            tracing::warn!("Hold by user {user_id} on wallet of another user: {wallet_id}");
+            return Err(wallet_not_owned(wallet_id, "sender"));
+        }
+        Err(err) => {
+            tracing::error!("Failed to look up the sender's wallet: {err}");
+            return Err(ApiError::internal("Failed to hold funds"));
+        }
+    };
+    check_minor_units(payload.amount, &sender_wallet.currency)?;
+    let receiver_wallet = match wallets.find_for(payload.receiver_id, Some(&sender_wallet.currency)).await {
+        Ok(Some(wallet)) => wallet,
+        Ok(None) => {
+            let receiver = sqlx::query_scalar!("SELECT id FROM users WHERE id = $1", payload.receiver_id)
+                .fetch_optional(&pool)
+                .await;
+            return Err(match receiver {
+                Ok(Some(_)) => ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "currency_mismatch",
+                    format!("Recipient has no {} wallet", sender_wallet.currency),
+                ),
+                Ok(None) => ApiError::new(StatusCode::NOT_FOUND, "recipient_not_found", "Recipient not found"),
+                Err(err) => {
+                    tracing::error!("Failed to look up the recipient: {err}");
+                    ApiError::internal("Failed to hold funds")
+                }
+            });
+        }
+        Err(err) => {
+            tracing::error!("Failed to look up the recipient's wallet: {err}");
+            return Err(ApiError::internal("Failed to hold funds"));
+        }
+    };
+
+    // the hold and the debit holding its funds commit together
+    let held = async {
+        let mut tx = pool.begin().await?;
+        let hold = sqlx::query_as::<_, Hold>(&format!(
+            r#"
+            INSERT INTO holds (sender_id, receiver_id, sender_wallet_id, receiver_wallet_id, amount, currency, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING {HOLD_COLUMNS}
+            "#
+        ))
+        .bind(user_id)
+        .bind(payload.receiver_id)
+        .bind(sender_wallet.id)
+        .bind(receiver_wallet.id)
+        .bind(payload.amount)
+        .bind(sender_wallet.currency.as_str())
+        .bind(expires_at)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        balance::debit_if_sufficient(&mut tx, Account::Wallet(sender_wallet.id), hold.amount, hold.id).await?;
+        let audit_entry = AuditEntry {
+            actor_id: user_id,
+            action: AuditAction::Hold,
+            amount: hold.amount,
+            counterparty_id: Some(hold.receiver_id),
+            tx_id: hold.id,
+        };
+        audit::record(&mut tx, &audit_entry).await?;
+
+        tx.commit().await?;
+        Ok::<_, BalanceError>(hold)
+    }
+    .await;
+
+    match held {
+        Ok(hold) => {
+            tracing::info!("User {user_id} put {} on hold {} until {}", hold.amount, hold.id, hold.expires_at);
+            Ok((StatusCode::CREATED, Json(hold)))
+        }
+        Err(BalanceError::InsufficientFunds) => {
+            tracing::warn!("Insufficient funds for hold by user: {user_id}");
+            Err(ApiError::new(
+                StatusCode::PAYMENT_REQUIRED,
+                "insufficient_funds",
+                "Insufficient funds",
+            ))
+        }
+        Err(BalanceError::AccountFrozen) => {
+            tracing::warn!("Hold attempt from frozen account: {user_id}");
+            Err(ApiError::new(StatusCode::FORBIDDEN, "account_frozen", "Account is frozen"))
+        }
+        Err(BalanceError::AccountClosed) => {
+            tracing::warn!("Hold attempt from closed account: {user_id}");
+            Err(ApiError::new(StatusCode::FORBIDDEN, "account_closed", "Account is closed"))
+        }
+        Err(err) => {
+            tracing::error!("Failed to hold funds of user {user_id}: {err}");
+            Err(ApiError::internal("Failed to hold funds"))
+        }
+    }
+}
+
+// return a hold the caller is the sender or the recipient of
+async fn get_hold(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    hold_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    let hold_id = id_from_path(hold_id)?;
+    let hold = find_hold(&pool, user_id, hold_id).await?;
+    Ok((StatusCode::OK, Json(hold)))
+}
+
+// Turn the hold into a completed transfer to the recipient, who is the only one that can capture it.
+// The sender's debit was booked under the hold's id when the funds were put on hold, the transfer
+// only adds the recipient's credit
+async fn capture_hold(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, config)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    hold_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    let hold_id = id_from_path(hold_id)?;
+    let hold = find_hold(&pool, user_id, hold_id).await?;
+
+    if hold.receiver_id != user_id {
+        tracing::warn!("Capture of hold {hold_id} attempted by its sender: {user_id}");
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "capture_not_allowed",
+            "Only the recipient can capture a hold",
+        ));
+    }
+    if hold.status != HoldStatus::Held {
+        return Err(hold_not_active(hold.status));
+    }
+    if hold.expires_at <= Utc::now() {
+        return Err(ApiError::new(StatusCode::CONFLICT, "hold_expired", "Hold has expired"));
+    }
+
+    let captured = async {
+        let mut tx = pool.begin().await?;
+
+        // only one capture or release gets past this, an expired hold is left to the release worker
+        let claimed = sqlx::query_scalar!(
+            r#"
+            UPDATE holds SET status = $2, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND status = $3 AND expires_at > CURRENT_TIMESTAMP
+            RETURNING id
+            "#,
+            hold_id,
+            HoldStatus::Captured as HoldStatus,
+            HoldStatus::Held as HoldStatus,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if claimed.is_none() {
+            return Ok(None);
+        }
+
+        let transfer_id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO transfers (sender_id, recipient_id, sender_wallet_id, receiver_wallet_id, amount, currency, description, status)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id
+            "#,
+            hold.sender_id,
+            hold.receiver_id,
+            hold.sender_wallet_id,
+            hold.receiver_wallet_id,
+            hold.amount,
+            hold.currency.as_str(),
+            format!("Capture of hold {hold_id}"),
+            TransactionStatus::Pending as TransactionStatus,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let receiver = Account::Wallet(hold.receiver_wallet_id);
+        balance::credit_within_cap(&mut tx, receiver, hold.amount, config.max_account_balance, transfer_id).await?;
+
+        let transfer_no = sqlx::query_scalar!(
+            "UPDATE transfer_counter SET value = value + 1 RETURNING value"
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        complete_transfer(&mut tx, transfer_id, transfer_no).await?;
+
+        let hold = sqlx::query_as::<_, Hold>(&format!(
+            "UPDATE holds SET transfer_id = $2 WHERE id = $1 RETURNING {HOLD_COLUMNS}"
+        ))
+        .bind(hold_id)
+        .bind(transfer_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let audit_entry = AuditEntry {
+            actor_id: hold.sender_id,
+            action: AuditAction::Transfer,
+            amount: hold.amount,
+            counterparty_id: Some(hold.receiver_id),
+            tx_id: transfer_id,
+        };
+        audit::record(&mut tx, &audit_entry).await?;
+
+        tx.commit().await?;
+        Ok::<_, BalanceError>(Some(hold))
+    }
+    .await;
+
+    match captured {
+        Ok(Some(hold)) => {
+            tracing::info!("Hold {hold_id} captured by user {user_id} into transfer {:?}", hold.transfer_id);
+            Ok((StatusCode::OK, Json(hold)))
+        }
+        // released, captured or expired meanwhile
+        Ok(None) => Err(ApiError::new(StatusCode::CONFLICT, "hold_not_active", "Hold is no longer held")),
+        Err(BalanceError::AccountFrozen) => {
+            tracing::warn!("Capture of hold {hold_id} into frozen account: {user_id}");
+            Err(ApiError::new(StatusCode::FORBIDDEN, "account_frozen", "Account is frozen"))
+        }
+        Err(BalanceError::AccountClosed) => {
+            tracing::warn!("Capture of hold {hold_id} into closed account: {user_id}");
+            Err(ApiError::new(StatusCode::FORBIDDEN, "account_closed", "Account is closed"))
+        }
+        Err(BalanceError::BalanceCapExceeded) => Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "balance_cap_exceeded",
+            "Capture would exceed the maximum account balance",
+        )),
+        Err(err) => {
+            tracing::error!("Failed to capture hold {hold_id}: {err}");
+            Err(ApiError::internal("Failed to capture hold"))
+        }
+    }
+}
+
+// Give the held amount back to the sender's wallet, unless the hold got captured or released first.
+// `expired_only` leaves holds that haven't expired yet alone
+async fn release(conn: &mut PgConnection, hold_id: Uuid, expired_only: bool) -> Result<Option<Hold>, BalanceError> {
+    let hold = sqlx::query_as::<_, Hold>(&format!(
+        r#"
+        UPDATE holds SET status = $2, updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1 AND status = $3 AND (NOT $4 OR expires_at <= CURRENT_TIMESTAMP)
+        RETURNING {HOLD_COLUMNS}
+        "#
+    ))
+    .bind(hold_id)
+    .bind(HoldStatus::Released)
+    .bind(HoldStatus::Held)
+    .bind(expired_only)
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some(hold) = hold else {
+        return Ok(None);
+    };
+
+    // the money comes back even to a frozen account, closing one is refused while it has funds on hold
+    balance::adjust(&mut *conn, Account::Wallet(hold.sender_wallet_id), hold.amount, hold.id).await?;
+    let audit_entry = AuditEntry {
+        actor_id: hold.sender_id,
+        action: AuditAction::HoldRelease,
+        amount: hold.amount,
+        counterparty_id: Some(hold.receiver_id),
+        tx_id: hold.id,
+    };
+    audit::record(&mut *conn, &audit_entry).await?;
+    Ok(Some(hold))
+}
+
+// the recipient voids the hold before it expires, the sender gets the funds back
+async fn release_hold(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    hold_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    let hold_id = id_from_path(hold_id)?;
+    let hold = find_hold(&pool, user_id, hold_id).await?;
+
+    // the sender authorized the payment, taking the funds back is up to the recipient
+    if hold.receiver_id != user_id {
+        tracing::warn!("Release of hold {hold_id} attempted by its sender: {user_id}");
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "release_not_allowed",
+            "Only the recipient can release a hold",
+        ));
+    }
+    if hold.status != HoldStatus::Held {
+        return Err(hold_not_active(hold.status));
+    }
+
+    let released = async {
+        let mut tx = pool.begin().await?;
+        let released = release(&mut tx, hold_id, false).await?;
+        tx.commit().await?;
+        Ok::<_, BalanceError>(released)
+    }
+    .await;
+
+    match released {
+        Ok(Some(hold)) => {
+            tracing::info!("Hold {hold_id} released by user {user_id}");
+            Ok((StatusCode::OK, Json(hold)))
+        }
+        Ok(None) => Err(ApiError::new(StatusCode::CONFLICT, "hold_not_active", "Hold is no longer held")),
+        Err(err) => {
+            tracing::error!("Failed to release hold {hold_id}: {err}");
+            Err(ApiError::internal("Failed to release hold"))
+        }
+    }
+}
+
+// Release the holds which expired without being captured, the funds go back to the wallets they were held from
+pub async fn release_expired_holds(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let expired = sqlx::query_scalar!(
+        r#"
+        SELECT id FROM holds
+        WHERE status = 'held' AND expires_at <= CURRENT_TIMESTAMP
+        ORDER BY expires_at
+        LIMIT 100
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut released = 0;
+    for hold_id in expired {
+        let result = async {
+            let mut tx = pool.begin().await?;
+            let hold = release(&mut tx, hold_id, true).await?;
+            tx.commit().await?;
+            Ok::<_, BalanceError>(hold)
+        }
+        .await;
+
+        match result {
+            Ok(Some(_)) => released += 1,
+            // captured or released by another instance in the meantime
+            Ok(None) => {}
+            Err(err) => tracing::error!("Failed to release expired hold {hold_id}: {err}"),
+        }
+    }
+    Ok(released)
+}
+
+pub fn hold_routes(service: Arc<AuthService>, pool: PgPool, config: Arc<TxConfig>) -> Router {
+    Router::new()
+        .route("/tx/holds", post(create_hold))
+        .route("/tx/holds/:id", get(get_hold))
+        .route("/tx/holds/:id/capture", post(capture_hold))
+        .route("/tx/holds/:id/release", post(release_hold))
+        .with_state((service, pool, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use super::release_expired_holds;
+    use crate::test_utils::{decimal, TestApp};
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn uncaptured_hold_is_released_after_its_expiry(pool: PgPool) {
+        let app = TestApp::new(pool.clone());
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let hold = app
+            .post("/v1/tx/holds")
+            .token(&alice.token)
+            .json(json!({ "receiver_id": bob.id, "amount": "20" }))
+            .send()
+            .await;
+        assert_eq!(hold.status, StatusCode::CREATED, "{}", hold.body);
+        assert_eq!(hold.body["status"], "held");
+        let hold_id: Uuid = hold.body["id"].as_str().unwrap().parse().unwrap();
+        assert_eq!(app.balance(&alice).await, decimal(&json!("30")));
+
+        // not expired yet, the worker leaves it alone
+        assert_eq!(release_expired_holds(&pool).await.unwrap(), 0);
+
+        sqlx::query!("UPDATE holds SET expires_at = CURRENT_TIMESTAMP - INTERVAL '1 second' WHERE id = $1", hold_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(release_expired_holds(&pool).await.unwrap(), 1);
+        assert_eq!(app.balance(&alice).await, decimal(&json!("50")));
+
+        // the release is on the ledger under the hold's id, next to the debit that held the funds
+        let entries = sqlx::query_scalar!(
+            "SELECT delta FROM ledger_entries WHERE tx_id = $1 ORDER BY created_at, id",
+            hold_id
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(entries, [decimal(&json!("-20")), decimal(&json!("20"))]);
+
+        let hold = app.get(&format!("/v1/tx/holds/{hold_id}")).token(&alice.token).send().await;
+        assert_eq!(hold.body["status"], "released");
+        let capture = app.post(&format!("/v1/tx/holds/{hold_id}/capture")).token(&bob.token).send().await;
+        assert_eq!(capture.status, StatusCode::CONFLICT);
+        assert_eq!(capture.error_code(), "hold_not_active");
+
+        // released once only
+        assert_eq!(release_expired_holds(&pool).await.unwrap(), 0);
+        assert_eq!(app.balance(&alice).await, decimal(&json!("50")));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn captured_hold_credits_the_recipient(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let hold = app
+            .post("/v1/tx/holds")
+            .token(&alice.token)
+            .json(json!({ "receiver_id": bob.id, "amount": "20" }))
+            .send()
+            .await;
+        assert_eq!(hold.status, StatusCode::CREATED, "{}", hold.body);
+        let hold_id = hold.body["id"].as_str().unwrap();
+
+        // the sender cannot take the payment
+        let capture = app.post(&format!("/v1/tx/holds/{hold_id}/capture")).token(&alice.token).send().await;
+        assert_eq!(capture.status, StatusCode::FORBIDDEN);
+
+        let capture = app.post(&format!("/v1/tx/holds/{hold_id}/capture")).token(&bob.token).send().await;
+        assert_eq!(capture.status, StatusCode::OK, "{}", capture.body);
+        assert_eq!(capture.body["status"], "captured");
+        assert_eq!(app.balance(&alice).await, decimal(&json!("30")));
+        assert_eq!(app.balance(&bob).await, decimal(&json!("20")));
+
+        let transfer_id = capture.body["transfer_id"].as_str().unwrap();
+        let transfer = app.get(&format!("/v1/tx/get_tx/{transfer_id}")).token(&alice.token).send().await;
+        assert_eq!(transfer.body["status"], "completed");
+
+        let release = app.post(&format!("/v1/tx/holds/{hold_id}/release")).token(&bob.token).send().await;
+        assert_eq!(release.status, StatusCode::CONFLICT);
+    }
+}
@@ -0,0 +1,169 @@
+use std::fmt;
+use std::str::FromStr;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, CommonParameters, Jwk, JwkSet, KeyAlgorithm, PublicKeyUse, RSAKeyParameters, RSAKeyType},
+    Algorithm, DecodingKey, EncodingKey, Header,
+};
+use rsa::{
+    pkcs1::DecodeRsaPrivateKey,
+    pkcs8::DecodePrivateKey,
+    traits::PublicKeyParts,
+    RsaPrivateKey,
+};
+use sha2::{Digest, Sha256};
+
+// key id assumed for tokens issued without a `kid`, and given to a plain `JWT_SECRET`
+pub const DEFAULT_KID: &str = "default";
+
+// Access token signing keys. HMAC secrets are kept by key id, newest first, and the first one signs
+// new tokens unless an RSA key is configured. The others only verify tokens signed before a rotation
+#[derive(Clone)]
+pub struct JwtKeys {
+    keys: Vec<(String, String)>,
+    rsa: Option<RsaSigningKey>,
+}
+
+impl JwtKeys {
+    pub fn single(secret: String) -> Self {
+        Self {
+            keys: vec![(DEFAULT_KID.to_string(), secret)],
+            rsa: None,
+        }
+    }
+
+    // sign with RS256 from now on, the HMAC secrets are still accepted for tokens already out there
+    pub fn with_rsa(mut self, rsa: RsaSigningKey) -> Self {
+        self.rsa = Some(rsa);
+        self
+    }
+
+    // header and key used to sign new tokens
+    pub fn signing_key(&self) -> (Header, EncodingKey) {
+        let (algorithm, kid, key) = match &self.rsa {
+            Some(rsa) => (Algorithm::RS256, rsa.kid.as_str(), rsa.encoding.clone()),
+            None => {
+                let (kid, secret) = &self.keys[0];
+                (Algorithm::HS256, kid.as_str(), EncodingKey::from_secret(secret.as_bytes()))
+            }
+        };
+        let mut header = Header::new(algorithm);
+        header.kid = Some(kid.to_string());
+        (header, key)
+    }
+
+    // key matching the algorithm and key id a token claims to be signed with, `None` for unknown keys
+    pub fn verification_key(&self, header: &Header) -> Option<(Algorithm, DecodingKey)> {
+        match header.alg {
+            Algorithm::RS256 => {
+                let rsa = self.rsa.as_ref()?;
+                (header.kid.as_deref() == Some(rsa.kid.as_str())).then(|| (Algorithm::RS256, rsa.decoding.clone()))
+            }
+            Algorithm::HS256 => {
+                let kid = header.kid.as_deref().unwrap_or(DEFAULT_KID);
+                self.keys
+                    .iter()
+                    .find(|(key_id, _)| key_id == kid)
+                    .map(|(_, secret)| (Algorithm::HS256, DecodingKey::from_secret(secret.as_bytes())))
+            }
+            _ => None,
+        }
+    }
+
+    // public keys other services can verify access tokens with, empty while only HMAC is in use
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.rsa.iter().map(|rsa| rsa.jwk.clone()).collect(),
+        }
+    }
+}
+
+// `kid:secret` pairs separated by commas, e.g. `2025-02:new-secret,2025-01:old-secret`
+impl FromStr for JwtKeys {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut keys: Vec<(String, String)> = Vec::new();
+        for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let Some((kid, secret)) = entry.split_once(':') else {
+                return Err("expected comma separated `kid:secret` pairs".to_string());
+            };
+            let (kid, secret) = (kid.trim(), secret.trim());
+            if kid.is_empty() || secret.is_empty() {
+                return Err("key ids and secrets must not be empty".to_string());
+            }
+            if keys.iter().any(|(key_id, _)| key_id == kid) {
+                return Err(format!("duplicate key id `{kid}`"));
+            }
+            keys.push((kid.to_string(), secret.to_string()));
+        }
+
+        if keys.is_empty() {
+            return Err("at least one key is required".to_string());
+        }
+        Ok(Self { keys, rsa: None })
+    }
+}
+
+// only the key ids, the secrets stay out of the logs
+impl fmt::Debug for JwtKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtKeys")
+            .field("hmac", &self.keys.iter().map(|(kid, _)| kid).collect::<Vec<_>>())
+            .field("rsa", &self.rsa.as_ref().map(|rsa| &rsa.kid))
+            .finish()
+    }
+}
+
+// RSA private key signing access tokens with RS256, published as a JWK under its RFC 7638 thumbprint
+#[derive(Clone)]
+pub struct RsaSigningKey {
+    kid: String,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    jwk: Jwk,
+}
+
+// a PKCS#1 or PKCS#8 PEM, with line breaks written as `\n` when it has to fit on one line
+impl FromStr for RsaSigningKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let pem = value.replace("\\n", "\n");
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+            .map_err(|_| "must be a PEM encoded RSA private key".to_string())?;
+        if private_key.size() < 256 {
+            return Err("must be at least 2048 bits".to_string());
+        }
+
+        let n = URL_SAFE_NO_PAD.encode(private_key.n().to_bytes_be());
+        let e = URL_SAFE_NO_PAD.encode(private_key.e().to_bytes_be());
+        let thumbprint = format!(r#"{{"e":"{e}","kty":"RSA","n":"{n}"}}"#);
+        let kid = URL_SAFE_NO_PAD.encode(Sha256::digest(thumbprint.as_bytes()));
+
+        let encoding = EncodingKey::from_rsa_pem(pem.as_bytes()).map_err(|err| err.to_string())?;
+        let decoding = DecodingKey::from_rsa_components(&n, &e).map_err(|err| err.to_string())?;
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(KeyAlgorithm::RS256),
+                key_id: Some(kid.clone()),
+                ..CommonParameters::default()
+            },
+            algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n,
+                e,
+            }),
+        };
+
+        Ok(Self {
+            kid,
+            encoding,
+            decoding,
+            jwk,
+        })
+    }
+}
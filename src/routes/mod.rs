@@ -1,4 +1,13 @@
+pub mod admin;
 pub mod auth;
+pub mod error;
+pub mod health;
+pub mod hold;
+pub mod jwt_keys;
+pub mod rate_limit;
+pub mod rules;
+pub mod totp;
 pub mod tx;
 pub mod user;
 pub mod utils;
+pub mod webhook;
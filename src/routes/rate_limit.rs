@@ -0,0 +1,88 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::http::{header, HeaderName};
+use rust_decimal::Decimal;
+
+// Outcome of a limit check that failed, carries what the rate limit headers report. The limit is counted
+// in whatever the limit is about, attempts for the login throttles and money for the daily transfer limit
+#[derive(Debug, Clone, Copy)]
+pub struct Throttled {
+    pub limit: Decimal,
+    pub remaining: Decimal,
+    pub retry_after: Duration,
+}
+
+impl Throttled {
+    // whole seconds to wait, never 0 so clients don't retry immediately
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs().max(1)
+    }
+
+    // Header set attached to every throttled response, whichever limiter produced it
+    pub fn headers(&self) -> [(HeaderName, String); 3] {
+        [
+            (HeaderName::from_static("x-ratelimit-limit"), self.limit.normalize().to_string()),
+            (HeaderName::from_static("x-ratelimit-remaining"), self.remaining.normalize().to_string()),
+            (header::RETRY_AFTER, self.retry_after_secs().to_string()),
+        ]
+    }
+}
+
+// In-memory sliding window counter keyed by an arbitrary string (client ip, email, ...)
+pub struct SlidingWindowLimiter {
+    max_hits: u32,
+    window: Duration,
+    hits: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl SlidingWindowLimiter {
+    pub fn new(max_hits: u32, window: Duration) -> Self {
+        Self {
+            max_hits,
+            window,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Fails with how long the caller has to wait if `key` already used up its hits in the current window
+    pub fn check(&self, key: &str) -> Result<(), Throttled> {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+        let Some(times) = hits.get_mut(key) else {
+            return Ok(());
+        };
+
+        while times
+            .front()
+            .is_some_and(|hit| now.duration_since(*hit) >= self.window)
+        {
+            times.pop_front();
+        }
+
+        match times.front() {
+            Some(oldest) if times.len() >= self.max_hits as usize => {
+                Err(Throttled {
+                    limit: Decimal::from(self.max_hits),
+                    remaining: Decimal::ZERO,
+                    retry_after: self.window.saturating_sub(now.duration_since(*oldest)),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record(&self, key: &str) {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+
+        // forget keys whose hits all fell out of the window so the map doesn't grow forever
+        hits.retain(|_, times| {
+            times
+                .back()
+                .is_some_and(|hit| now.duration_since(*hit) < self.window)
+        });
+        hits.entry(key.to_string()).or_default().push_back(now);
+    }
+}
@@ -0,0 +1,191 @@
+use axum::http::StatusCode;
+use rust_decimal::Decimal;
+use uuid::Uuid;
+
+use super::{
+    error::ApiError,
+    tx::{Transfer, TxConfig},
+};
+
+// One check of a transfer request, decided on the request alone. Checks that need the database
+// (account status, wallets and their currency, the daily limit) run where those rows are read
+pub trait TransferRule: Send + Sync {
+    fn check(&self, user_id: Uuid, transfer: &Transfer) -> Result<(), ApiError>;
+}
+
+// the token's user has to be the sender
+pub struct SenderIsCaller;
+
+impl TransferRule for SenderIsCaller {
+    fn check(&self, user_id: Uuid, transfer: &Transfer) -> Result<(), ApiError> {
+        if transfer.sender_id == user_id {
+            return Ok(());
+        }
+        Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "sender_mismatch",
+            "Sender does not match the authenticated user",
+        ))
+    }
+}
+
+// moving money to oneself is always a client bug
+pub struct NotToSelf;
+
+impl TransferRule for NotToSelf {
+    fn check(&self, _: Uuid, transfer: &Transfer) -> Result<(), ApiError> {
+        if transfer.sender_id != transfer.receiver_id {
+            return Ok(());
+        }
+        Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "self_transfer",
+            "Cannot transfer to self",
+        ))
+    }
+}
+
+// a negative amount would reverse the direction of the transfer, a zero one only adds noise to the history
+pub struct PositiveAmount;
+
+impl TransferRule for PositiveAmount {
+    fn check(&self, _: Uuid, transfer: &Transfer) -> Result<(), ApiError> {
+        if transfer.amount > Decimal::ZERO {
+            return Ok(());
+        }
+        Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_amount",
+            "Amount must be positive",
+        ))
+    }
+}
+
+// inclusive lower bound of a single transfer
+pub struct MinAmount(pub Decimal);
+
+impl TransferRule for MinAmount {
+    fn check(&self, _: Uuid, transfer: &Transfer) -> Result<(), ApiError> {
+        if transfer.amount >= self.0 {
+            return Ok(());
+        }
+        Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "amount_below_minimum",
+            format!("Amount must be at least {}", self.0),
+        ))
+    }
+}
+
+// inclusive upper bound of a single transfer
+pub struct MaxAmount(pub Decimal);
+
+impl TransferRule for MaxAmount {
+    fn check(&self, _: Uuid, transfer: &Transfer) -> Result<(), ApiError> {
+        if transfer.amount <= self.0 {
+            return Ok(());
+        }
+        Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "amount_above_maximum",
+            format!("Amount must be at most {}", self.0),
+        ))
+    }
+}
+
+// Ordered rules a transfer request has to pass before any database work, the first failing one answers
+pub struct TransferRules(Vec<Box<dyn TransferRule>>);
+
+impl TransferRules {
+    pub fn new(rules: Vec<Box<dyn TransferRule>>) -> Self {
+        Self(rules)
+    }
+
+    // the rules every transfer is held to, with the configured limits
+    pub fn from_config(config: &TxConfig) -> Self {
+        let mut rules: Vec<Box<dyn TransferRule>> =
+            vec![Box::new(SenderIsCaller), Box::new(NotToSelf), Box::new(PositiveAmount)];
+        if let Some(min_amount) = config.min_transfer_amount {
+            rules.push(Box::new(MinAmount(min_amount)));
+        }
+        if let Some(max_amount) = config.max_transfer_amount {
+            rules.push(Box::new(MaxAmount(max_amount)));
+        }
+        Self::new(rules)
+    }
+
+    pub fn check(&self, user_id: Uuid, transfer: &Transfer) -> Result<(), ApiError> {
+        self.0.iter().try_for_each(|rule| rule.check(user_id, transfer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::tx_config;
+
+    fn transfer(sender_id: Uuid, receiver_id: Uuid, amount: &str) -> Transfer {
+        serde_json::from_value(json!({ "sender_id": sender_id, "receiver_id": receiver_id, "amount": amount })).unwrap()
+    }
+
+    fn code(result: Result<(), ApiError>) -> &'static str {
+        result.err().map_or("", |err| err.code())
+    }
+
+    #[test]
+    fn sender_has_to_be_the_caller() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(SenderIsCaller.check(alice, &transfer(alice, bob, "1")).is_ok());
+        let err = SenderIsCaller.check(bob, &transfer(alice, bob, "1")).unwrap_err();
+        assert_eq!((err.status(), err.code()), (StatusCode::FORBIDDEN, "sender_mismatch"));
+    }
+
+    #[test]
+    fn transfers_to_self_are_rejected() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(NotToSelf.check(alice, &transfer(alice, bob, "1")).is_ok());
+        assert_eq!(code(NotToSelf.check(alice, &transfer(alice, alice, "1"))), "self_transfer");
+    }
+
+    #[test]
+    fn amount_has_to_be_positive() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(PositiveAmount.check(alice, &transfer(alice, bob, "0.01")).is_ok());
+        for amount in ["0", "-5"] {
+            assert_eq!(code(PositiveAmount.check(alice, &transfer(alice, bob, amount))), "invalid_amount");
+        }
+    }
+
+    #[test]
+    fn amount_limits_are_inclusive() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let (min, max) = (MinAmount(Decimal::from(5)), MaxAmount(Decimal::from(10)));
+        assert!(min.check(alice, &transfer(alice, bob, "5")).is_ok());
+        assert_eq!(code(min.check(alice, &transfer(alice, bob, "4.99"))), "amount_below_minimum");
+        assert!(max.check(alice, &transfer(alice, bob, "10")).is_ok());
+        assert_eq!(code(max.check(alice, &transfer(alice, bob, "10.01"))), "amount_above_maximum");
+    }
+
+    #[test]
+    fn pipeline_answers_with_the_first_failing_rule() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let rules = TransferRules::from_config(&TxConfig {
+            min_transfer_amount: Some(Decimal::from(5)),
+            max_transfer_amount: Some(Decimal::from(10)),
+            ..tx_config()
+        });
+
+        assert!(rules.check(alice, &transfer(alice, bob, "7")).is_ok());
+        assert_eq!(code(rules.check(alice, &transfer(alice, bob, "1"))), "amount_below_minimum");
+        assert_eq!(code(rules.check(alice, &transfer(alice, bob, "11"))), "amount_above_maximum");
+        // a transfer breaking several rules is answered by the earliest of them
+        assert_eq!(code(rules.check(alice, &transfer(alice, alice, "-1"))), "self_transfer");
+        assert_eq!(code(rules.check(bob, &transfer(alice, alice, "-1"))), "sender_mismatch");
+
+        // without configured limits any positive amount passes
+        let rules = TransferRules::from_config(&tx_config());
+        assert!(rules.check(alice, &transfer(alice, bob, "1000000")).is_ok());
+    }
+}
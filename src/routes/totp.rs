@@ -0,0 +1,80 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use rand::RngCore;
+use totp_rs::{Algorithm, TOTP};
+
+// Name shown next to the account in authenticator apps
+const ISSUER: &str = "backend-payment-system";
+// 160 bit secrets, the size RFC 4226 recommends
+const SECRET_LEN: usize = 20;
+const NONCE_LEN: usize = 12;
+
+// AES-256-GCM key the TOTP secrets are encrypted with before they reach the database
+#[derive(Clone)]
+pub struct TotpKey([u8; 32]);
+
+impl FromStr for TotpKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(value).map_err(|_| "must be hex encoded".to_string())?;
+        let key = <[u8; 32]>::try_from(bytes).map_err(|_| "must be 32 bytes (64 hex characters)".to_string())?;
+        Ok(Self(key))
+    }
+}
+
+// keeps the key out of `Config` debug output
+impl fmt::Debug for TotpKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TotpKey(..)")
+    }
+}
+
+impl TotpKey {
+    // hex of `nonce || ciphertext`, a fresh nonce per secret
+    pub fn encrypt(&self, secret: &[u8]) -> Option<String> {
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.0));
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, secret).ok()?;
+        Some(hex::encode([nonce.as_slice(), &ciphertext].concat()))
+    }
+
+    // `None` when the value was not produced by `encrypt` with this key
+    pub fn decrypt(&self, encrypted: &str) -> Option<Vec<u8>> {
+        let bytes = hex::decode(encrypted).ok()?;
+        if bytes.len() <= NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.0));
+        cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+    }
+}
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+// 6 digit codes over 30 second steps, one step of clock skew tolerated either way
+pub fn totp(secret: Vec<u8>, account_name: &str) -> Option<TOTP> {
+    TOTP::new(Algorithm::SHA1, 6, 1, 30, secret, Some(ISSUER.to_string()), account_name.to_string()).ok()
+}
+
+// The step `code` was generated for, `None` when it matches none within the tolerated skew.
+// Callers remember the step of an accepted code so it can't be used a second time
+pub fn matching_step(secret: Vec<u8>, account_name: &str, code: &str) -> Option<u64> {
+    let mut exact = totp(secret, account_name)?;
+    let skew = u64::from(exact.skew);
+    exact.skew = 0;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let current = now / exact.step;
+    (current.saturating_sub(skew)..=current + skew).find(|step| exact.check(code.trim(), step * exact.step))
+}
@@ -1,213 +1,3633 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::{
-    extract::{Path, State},
-    http::{HeaderMap, StatusCode},
-    response::{sse::Event, IntoResponse, Sse},
+    extract::{
+        rejection::{PathRejection, QueryRejection},
+        Path, Query, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::{sse::Event, IntoResponse, Response, Sse},
     routing::{get, post},
     Json, Router,
 };
+use chrono::{DateTime, Utc};
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use sqlx::{
     types::
         Decimal
     ,
-    PgPool,
+    PgConnection, PgPool, Postgres, QueryBuilder,
 };
 use uuid::Uuid;
 
-use super::{auth::AuthService, utils};
+use crate::audit::{self, AuditAction, AuditEntry};
+use crate::currency::Currency;
+use crate::db::{
+    balance::{self, Account, BalanceError},
+    export::ExportRepository,
+    idempotency,
+    tx::{insert_transaction, TransactionStatus, TransactionType, TransferKind},
+    utils::convert_offsetdt_to_dt,
+    wallet::WalletRepository,
+};
+
+use super::{
+    auth::AuthService,
+    error::ApiError,
+    rate_limit::Throttled,
+    rules::TransferRules,
+    utils::{self, AuthUser, JsonBody, OptionalJsonBody},
+};
+
+// Tunables for money movement
+#[derive(Debug, Clone)]
+pub struct TxConfig {
+    pub record_failed_transfers: bool,        // keep an audit entry for every rejected transfer attempt
+    pub filter_descriptions: bool,            // redact card/SSN like numbers and reject blocked words in descriptions
+    pub blocked_words: Vec<String>,           // lowercase words which make a description get rejected
+    pub max_metadata_bytes: usize,            // upper bound for the serialized metadata object of a transfer
+    pub max_account_balance: Option<Decimal>, // credits (deposits and incoming transfers) may not push a balance past this
+    pub min_transfer_amount: Option<Decimal>, // smallest amount a single transfer may move
+    pub max_transfer_amount: Option<Decimal>, // largest amount a single transfer may move
+    pub daily_transfer_limit: Option<Decimal>, // total a user may send per UTC day, in each currency
+    pub reversal_window: Option<Duration>,    // how long after it moved money a transfer can still be refunded
+    pub external_settlement_delay: Duration,  // how long an external transfer holds the sender's funds before it settles
+    pub hold_expiry: Duration,                // longest a hold may stay uncaptured before it is released, also its default
+    pub version_retries: u32,                 // reruns of a transfer whose sender wallet was debited under it, then 409
+}
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Transfer {
     pub sender_id: Uuid,
     pub receiver_id: Uuid,
     pub amount: Decimal,
+    // optional on requests, where it has to be the currency of both accounts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    // assigned on commit, only present on transfers read back from the database
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub transfer_no: Option<i64>,
+    // queue the transfer to run at this time, a time already past runs it right away
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub execute_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub transfer_kind: TransferKind,
+    // wallets to move the money between, by default the sender's in the transfer currency (or its primary one)
+    // and the recipient's in the same currency
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_wallet_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receiver_wallet_id: Option<Uuid>,
+}
+
+// Resolve the origination channel from the `X-Client-Channel` header, unknown or missing values count as `api`
+fn origination_channel(headers: &HeaderMap) -> &'static str {
+    let channel = headers
+        .get("X-Client-Channel")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_ascii_lowercase());
+
+    match channel.as_deref() {
+        Some("web") => "web",
+        Some("mobile") => "mobile",
+        _ => "api",
+    }
+}
+
+// A transfer as answered when it is created (or refunded), the whole `transfers` row. Scheduled
+// transfers are answered while still pending, without a number until they run
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TransferReceipt {
+    pub id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transfer_no: Option<i64>,
+    pub sender_id: Uuid,
+    pub receiver_id: Uuid,
+    pub sender_wallet_id: Uuid,
+    pub receiver_wallet_id: Uuid,
+    pub amount: Decimal,
+    pub currency: Currency,
+    pub description: Option<String>,
+    pub channel: String,
+    pub metadata: Option<serde_json::Value>,
+    pub reversed_tx_id: Option<Uuid>, // set on refunds, the transfer they reverse
+    pub transfer_kind: TransferKind,
+    pub status: TransactionStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheduled_for: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settles_at: Option<DateTime<Utc>>, // set while an external transfer holds the sender's funds
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// A transfer as looked up by one of its parties, the receipt along with when it was last touched.
+// Pending (e.g. scheduled) and failed transfers are included, they have no number
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct TransferDetails {
+    pub id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transfer_no: Option<i64>,
+    pub sender_id: Uuid,
+    pub receiver_id: Uuid,
+    pub sender_wallet_id: Uuid,
+    pub receiver_wallet_id: Uuid,
+    pub amount: Decimal,
+    pub currency: Currency,
     pub description: Option<String>,
+    pub channel: String,
+    pub metadata: Option<serde_json::Value>,
+    pub reversed_tx_id: Option<Uuid>, // set on refunds, the transfer they reverse
+    pub refunded_amount: Decimal,     // sum of the refunds made of this transfer so far
+    pub transfer_kind: TransferKind,
+    pub status: TransactionStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheduled_for: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settles_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+// 202 while a scheduled transfer waits for its time, 200 once the money has moved
+fn transfer_created(receipt: TransferReceipt) -> (StatusCode, Json<TransferReceipt>) {
+    let status = if receipt.status == TransactionStatus::Pending {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::OK
+    };
+    (status, Json(receipt))
+}
+
+// Numbers and completes a pending transfer inside the transaction that moved its money
+pub async fn complete_transfer(
+    conn: &mut PgConnection,
+    transfer_id: Uuid,
+    transfer_no: i64,
+) -> Result<TransferReceipt, sqlx::Error> {
+    sqlx::query_as::<_, TransferReceipt>(
+        r#"
+        UPDATE transfers SET status = $3, transfer_no = $2, updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1 AND status = $4
+        RETURNING id, transfer_no, sender_id, recipient_id AS receiver_id, sender_wallet_id, receiver_wallet_id, amount, currency, description, channel, metadata, reversed_tx_id, transfer_kind, status, scheduled_for, settles_at, created_at
+        "#,
+    )
+    .bind(transfer_id)
+    .bind(transfer_no)
+    .bind(TransactionStatus::Completed)
+    .bind(TransactionStatus::Pending)
+    .fetch_one(conn)
+    .await
+}
+
+// The transfer a replayed `Idempotency-Key` refers to, answered the same way as when it was created
+async fn replay_transfer(pool: &PgPool, user_id: Uuid, transfer_id: Uuid) -> Result<TransferReceipt, ApiError> {
+    let receipt = sqlx::query_as::<_, TransferReceipt>(
+        r#"
+        SELECT id, transfer_no, sender_id, recipient_id AS receiver_id, sender_wallet_id, receiver_wallet_id, amount, currency, description, channel, metadata, reversed_tx_id, transfer_kind, status, scheduled_for, settles_at, created_at
+        FROM transfers
+        WHERE id = $1
+        "#,
+    )
+    .bind(transfer_id)
+    .fetch_one(pool)
+    .await;
+
+    match receipt {
+        Ok(receipt) => {
+            tracing::info!("Replayed idempotency key of user {user_id} for transfer: {transfer_id}");
+            Ok(receipt)
+        }
+        Err(err) => {
+            tracing::error!("Failed to load replayed transfer {transfer_id}: {err}");
+            Err(ApiError::internal("Failed to transfer amount"))
+        }
+    }
+}
+
+// Store a failed transfer attempt as an audit entry, no money is moved
+async fn record_failed_transfer(pool: &PgPool, config: &TxConfig, user_id: Uuid, transfer: &Transfer, reason: &str) {
+    if !config.record_failed_transfers {
+        return;
+    }
+
+    let details = serde_json::json!({
+        "sender_id": transfer.sender_id,
+        "receiver_id": transfer.receiver_id,
+        "amount": transfer.amount,
+        "reason": reason,
+    });
+
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO audit_logs (user_id, action, entity_type, entity_id, changes)
+        VALUES ($1, 'transfer_failed', 'transfer', gen_random_uuid(), $2)
+        "#,
+        user_id,
+        details
+    )
+    .execute(pool)
+    .await;
+
+    if let Err(err) = result {
+        tracing::error!("Failed to record failed transfer for user {user_id}: {err}");
+    }
+}
+
+// Time until the daily transfer limits reset at midnight UTC
+fn until_utc_midnight() -> Duration {
+    let now = Utc::now();
+    let midnight = now.date_naive().succ_opt().and_then(|day| day.and_hms_opt(0, 0, 0));
+    midnight
+        .and_then(|midnight| (midnight - now.naive_utc()).to_std().ok())
+        .unwrap_or(Duration::from_secs(1))
+}
+
+// Move a pending transfer to failed once the transaction that would have completed it is rolled back
+async fn mark_transfer_failed(pool: &PgPool, transfer_id: Uuid) {
+    let result = sqlx::query!(
+        r#"
+        UPDATE transfers SET status = $2, updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1 AND status = $3
+        "#,
+        transfer_id,
+        TransactionStatus::Failed as TransactionStatus,
+        TransactionStatus::Pending as TransactionStatus,
+    )
+    .execute(pool)
+    .await;
+
+    if let Err(err) = result {
+        tracing::error!("Failed to mark transfer {transfer_id} as failed: {err}");
+    }
+}
+
+// A wallet that isn't the party's, whether it belongs to someone else or doesn't exist at all
+pub fn wallet_not_owned(wallet_id: Uuid, party: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "wallet_not_owned",
+        format!("Wallet {wallet_id} does not belong to the {party}"),
+    )
+}
+
+// 400 unless `amount` is a whole number of the currency's minor units, e.g. no fractions of a yen
+pub fn check_minor_units(amount: Decimal, currency: &Currency) -> Result<(), ApiError> {
+    if currency.fits(amount) {
+        return Ok(());
+    }
+    Err(ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_amount",
+        format!("{currency} amounts have at most {} decimal places", currency.minor_units()),
+    ))
+}
+
+// The account a deposit or withdrawal of the user moves and its currency: the wallet named in the request
+// once it turns out to be the user's, the primary wallet without one
+pub async fn own_account(pool: &PgPool, user_id: Uuid, wallet_id: Option<Uuid>) -> Result<(Account, Currency), ApiError> {
+    let wallets = WalletRepository::new(pool.clone());
+    let Some(wallet_id) = wallet_id else {
+        return match wallets.find_for(user_id, None).await {
+            Ok(Some(wallet)) => Ok((Account::Primary(user_id), wallet.currency)),
+            Ok(None) => {
+                tracing::warn!("User not found: {user_id}");
+                Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found"))
+            }
+            Err(err) => {
+                tracing::error!("Failed to look up the primary wallet of user {user_id}: {err}");
+                Err(ApiError::internal("Failed to look up wallet"))
+            }
+        };
+    };
+
+    match wallets.find(wallet_id).await {
+        Ok(Some(wallet)) if wallet.user_id == user_id => Ok((Account::Wallet(wallet_id), wallet.currency)),
+        Ok(_) => {
+            tracing::warn!("User {user_id} named wallet {wallet_id} of another user");
+            Err(wallet_not_owned(wallet_id, "user"))
+        }
+        Err(err) => {
+            tracing::error!("Failed to look up wallet {wallet_id}: {err}");
+            Err(ApiError::internal("Failed to look up wallet"))
+        }
+    }
 }
 
 async fn create_transaction(
     headers: HeaderMap,
-    State((service, pool)): State<(Arc<AuthService>, PgPool)>,
-    Json(transfer): Json<Transfer>,
-) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    AuthUser(header_uid): AuthUser,
+    State((_, pool, config)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    JsonBody(mut transfer): JsonBody<Transfer>,
+) -> Result<impl IntoResponse, ApiError> {
     tracing::info!("Starting transaction creation process");
 
-    let header_uid = match utils::validate_auth_token(headers, &service) {
-        Ok(val) => val,
-        Err(err) => {
-            tracing::error!("Invalid token: {err}");
-            return Err((err, "Invalid token"));
+    let channel = origination_channel(&headers);
+    let idempotency_key = utils::idempotency_key(&headers)?;
+
+    // A replayed key answers with the transfer it created, without moving any money again
+    if let Some(key) = idempotency_key.as_deref() {
+        match idempotency::find_transfer(&pool, header_uid, key).await {
+            Ok(Some(tx_id)) => return replay_transfer(&pool, header_uid, tx_id).await.map(transfer_created),
+            Ok(None) => {}
+            Err(err) => {
+                tracing::error!("Failed to look up idempotency key: {err}");
+                return Err(ApiError::internal("Failed to transfer amount"));
+            }
         }
-    };
+    }
 
-    // Transfer sender_id must match the token user_id
-    if header_uid != transfer.sender_id {
-        tracing::warn!("Unauthorized transaction attempt by user: {header_uid}");
-        return Err((axum::http::StatusCode::UNAUTHORIZED, "Invalid token"));
+    // ownership, self transfers, the amount and its configured limits, in that order
+    if let Err(err) = TransferRules::from_config(&config).check(header_uid, &transfer) {
+        tracing::warn!("Transfer by user {header_uid} rejected: {err}");
+        record_failed_transfer(&pool, &config, header_uid, &transfer, err.code()).await;
+        return Err(err);
     }
 
-    // Begin a database transaction
-    let mut tx = match pool.begin().await {
-        Ok(tx) => tx,
-        Err(err) => {
-            tracing::error!("Failed to start transaction: {err}");
-            return Err((axum::http::StatusCode::INTERNAL_SERVER_ERROR, "Failed to transfer amount"));
+    // Strip sensitive numbers from the description before it reaches the recipient's history
+    if config.filter_descriptions {
+        if let Some(description) = transfer.description.as_deref() {
+            match utils::sanitize_description(description, &config.blocked_words) {
+                Ok(sanitized) => transfer.description = Some(sanitized),
+                Err(err) => {
+                    tracing::warn!("Rejected transfer description from user {header_uid}: {err}");
+                    record_failed_transfer(&pool, &config, header_uid, &transfer, "description_rejected").await;
+                    return Err(ApiError::new(StatusCode::BAD_REQUEST, "invalid_description", err));
+                }
+            }
         }
-    };
+    }
+
+    // Metadata has to be an object and stay within the configured size
+    if let Some(metadata) = transfer.metadata.as_ref() {
+        let rejection = if !metadata.is_object() {
+            Some("Metadata must be a JSON object")
+        } else if metadata.to_string().len() > config.max_metadata_bytes {
+            Some("Metadata exceeds the allowed size")
+        } else {
+            None
+        };
+
+        if let Some(reason) = rejection {
+            tracing::warn!("Rejected transfer metadata from user {header_uid}: {reason}");
+            record_failed_transfer(&pool, &config, header_uid, &transfer, "metadata_rejected").await;
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "invalid_metadata", reason));
+        }
+    }
 
     let sender_id = transfer.sender_id;
     let receiver_id = transfer.receiver_id;
     let amount = transfer.amount;
 
-    // Deduct amount from sender
-    let tx_one = sqlx::query!(
-        "UPDATE users SET balance = balance - $1 WHERE id = $2",
-        amount,
-        sender_id
-    )
-    .execute(&mut *tx)
-    .await;
+    // Both wallets have to hold the same currency, there is no conversion. A wallet's currency
+    // never changes, so checking ahead of the balance locks is enough
+    let wallets = WalletRepository::new(pool.clone());
+    let sender_wallet = match transfer.sender_wallet_id {
+        Some(wallet_id) => wallets.find(wallet_id).await,
+        None => wallets.find_for(sender_id, transfer.currency.as_ref()).await,
+    };
+    let sender_wallet = match sender_wallet {
+        Ok(Some(wallet)) if wallet.user_id == sender_id => wallet,
+        Ok(_) if transfer.sender_wallet_id.is_some() => {
+            let wallet_id = transfer.sender_wallet_id.unwrap_or_default();
+            tracing::warn!("Transfer by user {header_uid} from wallet of another user: {wallet_id}");
+            record_failed_transfer(&pool, &config, header_uid, &transfer, "wallet_not_owned").await;
+            return Err(wallet_not_owned(wallet_id, "sender"));
+        }
+        Ok(_) => {
+            let Some(currency) = transfer.currency.as_ref() else {
+                tracing::warn!("Sender not found: {sender_id}");
+                return Err(ApiError::new(StatusCode::NOT_FOUND, "sender_not_found", "Sender not found"));
+            };
+            tracing::warn!("Transfer by user {header_uid} in a currency without a wallet: {currency}");
+            record_failed_transfer(&pool, &config, header_uid, &transfer, "currency_mismatch").await;
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "currency_mismatch",
+                format!("Sender has no {currency} wallet"),
+            ));
+        }
+        Err(err) => {
+            tracing::error!("Failed to look up the sender's wallet: {err}");
+            record_failed_transfer(&pool, &config, header_uid, &transfer, "transfer_failed").await;
+            return Err(ApiError::internal("Failed to transfer amount"));
+        }
+    };
+    let currency = sender_wallet.currency.clone();
 
-    // Add amount to receiver
-    let tx_two = sqlx::query!(
-        "UPDATE users SET balance = balance + $1 WHERE id = $2",
-        amount,
-        receiver_id
-    )
-    .execute(&mut *tx)
-    .await;
+    let receiver_wallet = match transfer.receiver_wallet_id {
+        Some(wallet_id) => wallets.find(wallet_id).await,
+        None => wallets.find_for(receiver_id, Some(&currency)).await,
+    };
+    let receiver_wallet = match receiver_wallet {
+        Ok(Some(wallet)) if wallet.user_id == receiver_id => wallet,
+        Ok(_) if transfer.receiver_wallet_id.is_some() => {
+            let wallet_id = transfer.receiver_wallet_id.unwrap_or_default();
+            tracing::warn!("Transfer by user {header_uid} to a wallet not of the recipient: {wallet_id}");
+            record_failed_transfer(&pool, &config, header_uid, &transfer, "wallet_not_owned").await;
+            return Err(wallet_not_owned(wallet_id, "recipient"));
+        }
+        Ok(_) => {
+            // either there is no such recipient or it only holds other currencies
+            let receiver_currency = match sqlx::query_scalar!(
+                r#"SELECT currency AS "currency: Currency" FROM users WHERE id = $1"#,
+                receiver_id
+            )
+            .fetch_optional(&pool)
+            .await
+            {
+                Ok(receiver_currency) => receiver_currency,
+                Err(err) => {
+                    tracing::error!("Failed to look up the recipient: {err}");
+                    record_failed_transfer(&pool, &config, header_uid, &transfer, "transfer_failed").await;
+                    return Err(ApiError::internal("Failed to transfer amount"));
+                }
+            };
+            let Some(receiver_currency) = receiver_currency else {
+                tracing::warn!("Recipient not found: {receiver_id}");
+                record_failed_transfer(&pool, &config, header_uid, &transfer, "recipient_not_found").await;
+                return Err(ApiError::new(StatusCode::NOT_FOUND, "recipient_not_found", "Recipient not found"));
+            };
+            let reason = format!("Sender account holds {currency} but recipient account holds {receiver_currency}");
+            tracing::warn!("Currency mismatch in transfer by user {header_uid}: {reason}");
+            record_failed_transfer(&pool, &config, header_uid, &transfer, "currency_mismatch").await;
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "currency_mismatch", reason));
+        }
+        Err(err) => {
+            tracing::error!("Failed to look up the recipient's wallet: {err}");
+            record_failed_transfer(&pool, &config, header_uid, &transfer, "transfer_failed").await;
+            return Err(ApiError::internal("Failed to transfer amount"));
+        }
+    };
+
+    // wallets named in the request are taken as they are, so they can still disagree with each other
+    // or with the requested currency
+    let mismatch = if receiver_wallet.currency != currency {
+        Some(format!(
+            "Sender wallet holds {currency} but recipient wallet holds {}",
+            receiver_wallet.currency
+        ))
+    } else {
+        transfer
+            .currency
+            .as_ref()
+            .filter(|requested| **requested != currency)
+            .map(|requested| format!("Transfer currency {requested} differs from the sender wallet currency {currency}"))
+    };
+    if let Some(reason) = mismatch {
+        tracing::warn!("Wallet currency mismatch in transfer by user {header_uid}: {reason}");
+        record_failed_transfer(&pool, &config, header_uid, &transfer, "currency_mismatch").await;
+        return Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "wallet_currency_mismatch", reason));
+    }
+    if let Err(err) = check_minor_units(transfer.amount, &currency) {
+        tracing::warn!("Transfer amount {} finer than the {currency} minor unit from user: {header_uid}", transfer.amount);
+        record_failed_transfer(&pool, &config, header_uid, &transfer, "invalid_amount").await;
+        return Err(err);
+    }
+    transfer.sender_wallet_id = Some(sender_wallet.id);
+    transfer.receiver_wallet_id = Some(receiver_wallet.id);
+
+    // Scheduled times already past run right away
+    transfer.execute_at = transfer.execute_at.filter(|execute_at| *execute_at > Utc::now());
 
-    // Insert transaction record
-    let tx_three = sqlx::query!(
-        "INSERT INTO transfers (sender_id, recipient_id, amount) VALUES ($1, $2, $3) RETURNING id",
+    // Record the transfer as pending before any money moves, it stays visible even if the process dies midway
+    let pending = sqlx::query_scalar!(
+        r#"
+        INSERT INTO transfers (sender_id, recipient_id, sender_wallet_id, receiver_wallet_id, amount, channel, metadata, description, status, currency, scheduled_for, transfer_kind)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+        RETURNING id
+        "#,
         sender_id,
         receiver_id,
+        sender_wallet.id,
+        receiver_wallet.id,
         amount,
+        channel,
+        transfer.metadata,
+        transfer.description,
+        TransactionStatus::Pending as TransactionStatus,
+        currency.as_str(),
+        transfer.execute_at as _,
+        transfer.transfer_kind as TransferKind,
     )
-    .fetch_one(&mut *tx)
+    .fetch_one(&pool)
     .await;
 
+    let transfer_id = match pending {
+        Ok(transfer_id) => transfer_id,
+        Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+            // the sender is the authenticated user, so a dangling reference is nearly always the recipient
+            return Err(if err.constraint() == Some("transfers_sender_id_fkey") {
+                tracing::warn!("Sender not found: {sender_id}");
+                ApiError::new(StatusCode::NOT_FOUND, "sender_not_found", "Sender not found")
+            } else {
+                tracing::warn!("Recipient not found: {receiver_id}");
+                record_failed_transfer(&pool, &config, header_uid, &transfer, "recipient_not_found").await;
+                ApiError::new(StatusCode::NOT_FOUND, "recipient_not_found", "Recipient not found")
+            });
+        }
+        Err(err) => {
+            tracing::error!("Failed to record pending transfer: {err}");
+            record_failed_transfer(&pool, &config, header_uid, &transfer, "transfer_failed").await;
+            return Err(ApiError::internal("Failed to transfer amount"));
+        }
+    };
+
+    // The sender wallet's version is read before each run and checked again once its row is locked, another debit
+    // in between (a withdrawal, hold or transfer out) makes the run start over on a fresh read. Credits don't count
+    let mut attempt = 0;
+    loop {
+        let version = match wallets.version(sender_wallet.id).await {
+            Ok(version) => version,
+            Err(err) => {
+                tracing::error!("Failed to read the sender wallet version: {err}");
+                mark_transfer_failed(&pool, transfer_id).await;
+                record_failed_transfer(&pool, &config, header_uid, &transfer, "transfer_failed").await;
+                return Err(ApiError::internal("Failed to transfer amount"));
+            }
+        };
+        let key = idempotency_key.as_deref();
+        match process_transfer(&pool, &config, header_uid, transfer_id, &transfer, key, Some(version)).await {
+            Err(err) if err.code() == VERSION_CONFLICT => {
+                if attempt >= config.version_retries {
+                    tracing::warn!("Giving up on transfer {transfer_id} after {attempt} retries on a changing balance");
+                    mark_transfer_failed(&pool, transfer_id).await;
+                    record_failed_transfer(&pool, &config, header_uid, &transfer, err.code()).await;
+                    return Err(err);
+                }
+                attempt += 1;
+            }
+            result => return result.map(transfer_created),
+        }
+    }
+}
+
+const VERSION_CONFLICT: &str = "concurrent_modification";
+
+fn version_conflict() -> ApiError {
+    ApiError::new(
+        StatusCode::CONFLICT,
+        VERSION_CONFLICT,
+        "Balance changed while the transfer was processed, retry the request",
+    )
+}
+
+// Move the money of a transfer recorded as pending: debit, limits, credit and numbering in one database
+// transaction. A transfer still queued for later only claims its idempotency key, `execute_due_transfers`
+// runs it through here again once it is due. An external transfer stops after the debit with the funds held,
+// `settle_external_transfers` does the credit and numbering once it settles
+async fn process_transfer(
+    pool: &PgPool,
+    config: &TxConfig,
+    user_id: Uuid,
+    transfer_id: Uuid,
+    transfer: &Transfer,
+    idempotency_key: Option<&str>,
+    expected_version: Option<i64>,
+) -> Result<TransferReceipt, ApiError> {
+    let sender_id = transfer.sender_id;
+    let receiver_id = transfer.receiver_id;
+    let sender = transfer.sender_wallet_id.map_or(Account::Primary(sender_id), Account::Wallet);
+    let receiver = transfer.receiver_wallet_id.map_or(Account::Primary(receiver_id), Account::Wallet);
+    let amount = transfer.amount;
+
+    // Begin a database transaction
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(err) => {
+            tracing::error!("Failed to start transaction: {err}");
+            mark_transfer_failed(pool, transfer_id).await;
+            record_failed_transfer(pool, config, user_id, transfer, "transaction_unavailable").await;
+            return Err(ApiError::internal("Failed to transfer amount"));
+        }
+    };
+
+    // Hold the pending transfer for the rest of the transaction, a scheduler that finds it
+    // already held or settled leaves it to whoever got there first. External transfers waiting to
+    // settle are pending too, but their money has moved already
+    match sqlx::query_scalar!(
+        "SELECT id FROM transfers WHERE id = $1 AND status = 'pending' AND settles_at IS NULL FOR UPDATE SKIP LOCKED",
+        transfer_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "transfer_not_pending",
+                "Transfer is no longer pending",
+            ));
+        }
+        Err(err) => {
+            tracing::error!("Failed to lock pending transfer {transfer_id}: {err}");
+            drop(tx);
+            mark_transfer_failed(pool, transfer_id).await;
+            record_failed_transfer(pool, config, user_id, transfer, "transfer_failed").await;
+            return Err(ApiError::internal("Failed to transfer amount"));
+        }
+    }
+
+    // Claim the key before touching any balance, a concurrent retry waits here and then replays the winner's result
+    if let Some(key) = idempotency_key {
+        match idempotency::claim_key(&mut tx, user_id, key, transfer_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                drop(tx);
+                mark_transfer_failed(pool, transfer_id).await;
+                return match idempotency::find_transfer(pool, user_id, key).await {
+                    Ok(Some(tx_id)) => replay_transfer(pool, user_id, tx_id).await,
+                    Ok(None) => Err(ApiError::new(
+                        StatusCode::CONFLICT,
+                        "idempotency_key_conflict",
+                        "Idempotency-Key is in use, retry the request",
+                    )),
+                    Err(err) => {
+                        tracing::error!("Failed to look up idempotency key: {err}");
+                        Err(ApiError::internal("Failed to transfer amount"))
+                    }
+                };
+            }
+            Err(err) => {
+                tracing::error!("Failed to claim idempotency key: {err}");
+                drop(tx);
+                mark_transfer_failed(pool, transfer_id).await;
+                record_failed_transfer(pool, config, user_id, transfer, "transfer_failed").await;
+                return Err(ApiError::internal("Failed to transfer amount"));
+            }
+        }
+    }
+
+    // A transfer queued for later only keeps its idempotency key for now, the scheduler moves the money
+    if transfer.execute_at.is_some() {
+        let receipt = sqlx::query_as::<_, TransferReceipt>(
+            r#"
+            SELECT id, transfer_no, sender_id, recipient_id AS receiver_id, sender_wallet_id, receiver_wallet_id, amount, currency, description, channel, metadata, reversed_tx_id, transfer_kind, status, scheduled_for, settles_at, created_at
+            FROM transfers
+            WHERE id = $1
+            "#,
+        )
+        .bind(transfer_id)
+        .fetch_one(&mut *tx)
+        .await;
+
+        return match receipt {
+            Ok(receipt) => match tx.commit().await {
+                Ok(_) => {
+                    tracing::info!("Transfer {transfer_id} scheduled for {:?}", receipt.scheduled_for);
+                    Ok(receipt)
+                }
+                Err(err) => {
+                    tracing::error!("Failed to commit scheduled transfer: {err}");
+                    mark_transfer_failed(pool, transfer_id).await;
+                    Err(ApiError::internal("Failed to transfer amount"))
+                }
+            },
+            Err(err) => {
+                tracing::error!("Failed to load scheduled transfer {transfer_id}: {err}");
+                drop(tx);
+                mark_transfer_failed(pool, transfer_id).await;
+                Err(ApiError::internal("Failed to transfer amount"))
+            }
+        };
+    }
+
+    // Deduct amount from sender, the row stays locked until the transaction ends
+    let debited = balance::debit_at_version(&mut tx, sender, amount, transfer_id, expected_version).await;
+    if let Err(BalanceError::VersionConflict) = debited {
+        // the transfer stays pending for the caller to run again on a fresh read, rolling back right away
+        // releases its row before the rerun looks for it
+        tracing::warn!("Sender wallet of transfer {transfer_id} changed since it was read");
+        if let Err(err) = tx.rollback().await {
+            tracing::error!("Failed to roll back transfer {transfer_id}: {err}");
+        }
+        return Err(version_conflict());
+    }
+    if let Err(err) = debited {
+        drop(tx); // roll back before recording the attempt
+        mark_transfer_failed(pool, transfer_id).await;
+        return Err(match err {
+            BalanceError::InsufficientFunds => {
+                tracing::warn!("Insufficient funds for transfer by user: {sender_id}");
+                record_failed_transfer(pool, config, user_id, transfer, "insufficient_funds").await;
+                ApiError::new(
+                    StatusCode::PAYMENT_REQUIRED,
+                    "insufficient_funds",
+                    "Insufficient funds",
+                )
+            }
+            BalanceError::UserNotFound => {
+                tracing::warn!("Sender not found: {sender_id}");
+                ApiError::new(StatusCode::NOT_FOUND, "sender_not_found", "Sender not found")
+            }
+            BalanceError::AccountFrozen => {
+                tracing::warn!("Transfer attempt from frozen account: {sender_id}");
+                record_failed_transfer(pool, config, user_id, transfer, "sender_frozen").await;
+                ApiError::new(StatusCode::FORBIDDEN, "account_frozen", "Sender account is frozen")
+            }
+            BalanceError::AccountClosed => {
+                tracing::warn!("Transfer attempt from closed account: {sender_id}");
+                record_failed_transfer(pool, config, user_id, transfer, "sender_closed").await;
+                ApiError::new(StatusCode::FORBIDDEN, "account_closed", "Sender account is closed")
+            }
+            err => {
+                tracing::error!("Failed to debit sender: {err}");
+                record_failed_transfer(pool, config, user_id, transfer, "transfer_failed").await;
+                ApiError::internal("Failed to transfer amount")
+            }
+        });
+    }
+
+    // The debit holds the sender's row lock, so concurrent transfers of the same user are counted one after another.
+    // Scheduled transfers count towards the day they run on, external ones from when their funds are held.
+    // The limit applies per currency, only transfers in the currency of this one are added up
+    if let Some(daily_limit) = config.daily_transfer_limit {
+        let sent_today = sqlx::query_scalar!(
+            r#"
+            SELECT COALESCE(SUM(amount), 0) AS "sent!"
+            FROM transfers
+            WHERE sender_id = $1 AND (status = 'completed' OR (status = 'pending' AND settles_at IS NOT NULL))
+              AND currency = (SELECT currency FROM transfers WHERE id = $2)
+              AND COALESCE(scheduled_for, created_at) >= date_trunc('day', CURRENT_TIMESTAMP AT TIME ZONE 'UTC') AT TIME ZONE 'UTC'
+            "#,
+            sender_id,
+            transfer_id
+        )
+        .fetch_one(&mut *tx)
+        .await;
+
+        match sent_today {
+            Ok(sent_today) if sent_today + amount > daily_limit => {
+                drop(tx); // roll back before recording the attempt
+                mark_transfer_failed(pool, transfer_id).await;
+                tracing::warn!("Daily transfer limit exceeded by user: {sender_id}");
+                record_failed_transfer(pool, config, user_id, transfer, "daily_limit_exceeded").await;
+                let throttled = Throttled {
+                    limit: daily_limit,
+                    remaining: (daily_limit - sent_today).max(Decimal::ZERO),
+                    retry_after: until_utc_midnight(),
+                };
+                return Err(ApiError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "daily_limit_exceeded",
+                    format!("Transfer would exceed the daily limit of {daily_limit}"),
+                )
+                .with_headers(throttled.headers()));
+            }
+            Ok(_) => {}
+            Err(err) => {
+                tracing::error!("Failed to sum today's transfers of user {sender_id}: {err}");
+                drop(tx); // roll back before recording the attempt
+                mark_transfer_failed(pool, transfer_id).await;
+                record_failed_transfer(pool, config, user_id, transfer, "transfer_failed").await;
+                return Err(ApiError::internal("Failed to transfer amount"));
+            }
+        }
+    }
+
+    let completed = if transfer.transfer_kind == TransferKind::External {
+        hold_for_settlement(&mut tx, transfer_id, config.external_settlement_delay).await
+    } else {
+        // Add amount to receiver, crediting nobody would make the debited amount vanish
+        if let Err(err) = balance::credit_within_cap(&mut tx, receiver, amount, config.max_account_balance, transfer_id).await {
+            drop(tx); // roll back before recording the attempt
+            mark_transfer_failed(pool, transfer_id).await;
+            return Err(match err {
+                BalanceError::UserNotFound => {
+                    tracing::warn!("Recipient not found: {receiver_id}");
+                    record_failed_transfer(pool, config, user_id, transfer, "recipient_not_found").await;
+                    ApiError::new(StatusCode::NOT_FOUND, "recipient_not_found", "Recipient not found")
+                }
+                BalanceError::BalanceCapExceeded => {
+                    tracing::warn!("Transfer would exceed the maximum balance of recipient: {receiver_id}");
+                    record_failed_transfer(pool, config, user_id, transfer, "recipient_balance_cap").await;
+                    ApiError::new(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "balance_cap_exceeded",
+                        "Transfer would exceed the recipient's maximum balance",
+                    )
+                }
+                BalanceError::AccountFrozen => {
+                    tracing::warn!("Transfer to frozen account: {receiver_id}");
+                    record_failed_transfer(pool, config, user_id, transfer, "recipient_frozen").await;
+                    ApiError::new(StatusCode::FORBIDDEN, "account_frozen", "Recipient account is frozen")
+                }
+                BalanceError::AccountClosed => {
+                    tracing::warn!("Transfer to closed account: {receiver_id}");
+                    record_failed_transfer(pool, config, user_id, transfer, "recipient_closed").await;
+                    ApiError::new(StatusCode::FORBIDDEN, "account_closed", "Recipient account is closed")
+                }
+                err => {
+                    tracing::error!("Failed to credit recipient: {err}");
+                    record_failed_transfer(pool, config, user_id, transfer, "transfer_failed").await;
+                    ApiError::internal("Failed to transfer amount")
+                }
+            });
+        }
+
+        // Take the next transfer number, the counter row stays locked until the transaction ends
+        // so a rolled back transfer never leaves a gap in the numbering
+        let transfer_no = sqlx::query_scalar!(
+            "UPDATE transfer_counter SET value = value + 1 RETURNING value"
+        )
+        .fetch_one(&mut *tx)
+        .await;
+
+        // Complete the transfer, this only becomes visible together with the balance changes on commit
+        match transfer_no {
+            Ok(transfer_no) => complete_transfer(&mut tx, transfer_id, transfer_no).await,
+            Err(err) => Err(err),
+        }
+    };
+
     // Validate if all the transactions were successful
-    let tx_id = match (tx_one, tx_two, tx_three) {
-        (Ok(_), Ok(_), Ok(val)) => val.id,
+    let receipt = match completed {
+        Ok(receipt) => receipt,
         _ => {
             tracing::error!("Failed to transfer amount");
-            return Err((axum::http::StatusCode::INTERNAL_SERVER_ERROR, "Failed to transfer amount"));
+            drop(tx); // roll back before recording the attempt
+            mark_transfer_failed(pool, transfer_id).await;
+            record_failed_transfer(pool, config, user_id, transfer, "transfer_failed").await;
+            return Err(ApiError::internal("Failed to transfer amount"));
         }
     };
 
+    let tx_id = receipt.id;
+    let audit_entry = AuditEntry {
+        actor_id: user_id,
+        action: AuditAction::Transfer,
+        amount,
+        counterparty_id: Some(receiver_id),
+        tx_id,
+    };
+    if let Err(err) = audit::record(&mut tx, &audit_entry).await {
+        tracing::error!("Failed to record audit entry for transfer {tx_id}: {err}");
+        drop(tx); // roll back before recording the attempt
+        mark_transfer_failed(pool, transfer_id).await;
+        record_failed_transfer(pool, config, user_id, transfer, "transfer_failed").await;
+        return Err(ApiError::internal("Failed to transfer amount"));
+    }
+
     // Commit the transaction
     match tx.commit().await {
         Ok(_) => {
-            tracing::info!("Transaction successful with id: {tx_id}");
-            return Ok((axum::http::StatusCode::OK, format!("Transaction successful id: {tx_id}")));
+            tracing::info!("Transaction successful with id: {tx_id} no: {}", receipt.transfer_no.unwrap_or_default());
+            Ok(receipt)
         }
         Err(err) => {
             tracing::error!("Failed to commit transaction: {err}");
-            return Err((axum::http::StatusCode::INTERNAL_SERVER_ERROR, "Failed to transfer amount"));
+            mark_transfer_failed(pool, transfer_id).await;
+            record_failed_transfer(pool, config, user_id, transfer, "commit_failed").await;
+            Err(ApiError::internal("Failed to transfer amount"))
         }
     }
 }
 
-// return a specific transaction by it's transaction_id which belongs to it's user
-async fn get_transaction(
-    headers: HeaderMap,
-    State((service, pool)): State<(Arc<AuthService>, PgPool)>,
-    Path(transaction_id): Path<Uuid>, // transaction_id: Uuid
-) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
-    let header_uid = match utils::validate_auth_token(headers, &service) {
-        Ok(val) => val,
-        Err(err) => {
-            return Err((
-                err,
-                "Invalid token",
-            ));
-        }  
-    };
-
-    let transaction = match sqlx::query!(
+// Leave an external transfer pending with its amount taken off the sender until `delay` has passed
+async fn hold_for_settlement(
+    conn: &mut PgConnection,
+    transfer_id: Uuid,
+    delay: Duration,
+) -> Result<TransferReceipt, sqlx::Error> {
+    sqlx::query_as::<_, TransferReceipt>(
         r#"
-        SELECT sender_id, recipient_id, amount FROM transfers WHERE id = $1 AND (sender_id = $2 OR recipient_id = $2)
+        UPDATE transfers SET settles_at = CURRENT_TIMESTAMP + $2 * INTERVAL '1 second', updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1
+        RETURNING id, transfer_no, sender_id, recipient_id AS receiver_id, sender_wallet_id, receiver_wallet_id, amount, currency, description, channel, metadata, reversed_tx_id, transfer_kind, status, scheduled_for, settles_at, created_at
         "#,
-        transaction_id,
-        header_uid
     )
-    .fetch_one(&pool)
+    .bind(transfer_id)
+    .bind(delay.as_secs_f64())
+    .fetch_one(conn)
     .await
-    {
-        Ok(record) => Transfer {
+}
+
+// Run the scheduled transfers that have come due, with the same balance and limit checks as an immediate
+// transfer. A failed run marks the transfer failed and records the attempt like a rejected request
+pub async fn execute_due_transfers(pool: &PgPool, config: &TxConfig) -> Result<usize, sqlx::Error> {
+    let due = sqlx::query!(
+        r#"
+        SELECT id, sender_id, recipient_id, sender_wallet_id, receiver_wallet_id, amount, currency AS "currency: Currency",
+               description, metadata, transfer_kind AS "transfer_kind: TransferKind"
+        FROM transfers
+        WHERE status = 'pending' AND scheduled_for <= CURRENT_TIMESTAMP AND settles_at IS NULL
+        ORDER BY scheduled_for
+        LIMIT 100
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut executed = 0;
+    for record in due {
+        let transfer = Transfer {
             sender_id: record.sender_id,
             receiver_id: record.recipient_id,
             amount: record.amount,
-            description: None,
-        },
-        Err(err) => {
-            tracing::error!("Failed to retrieve transaction: {err}");
-            return Err((
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to retrieve transaction",
-            ));
-        }
-    };
+            currency: Some(record.currency),
+            description: record.description,
+            metadata: record.metadata,
+            transfer_no: None,
+            execute_at: None,
+            transfer_kind: record.transfer_kind,
+            sender_wallet_id: Some(record.sender_wallet_id),
+            receiver_wallet_id: Some(record.receiver_wallet_id),
+        };
 
-    Ok((
-        axum::http::StatusCode::OK,
-        serde_json::to_string(&transaction).unwrap(),
-    ))
+        match process_transfer(pool, config, record.sender_id, record.id, &transfer, None, None).await {
+            Ok(_) => executed += 1,
+            // picked up by another instance in the meantime
+            Err(err) if err.code() == "transfer_not_pending" => {}
+            Err(err) => tracing::warn!("Scheduled transfer {} failed: {err}", record.id),
+        }
+    }
+    Ok(executed)
 }
 
-// return all transactions which a user made through it's user_id 
-async fn list_transactions(
-    headers: HeaderMap,
-    State((service, pool)): State<(Arc<AuthService>, PgPool)>,
-) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+// Settle the external transfers whose time has come: credit the recipient and complete the transfer, or give the
+// held amount back to the sender and fail it when the recipient can't take it
+pub async fn settle_external_transfers(pool: &PgPool, config: &TxConfig) -> Result<usize, sqlx::Error> {
+    let due = sqlx::query_scalar!(
+        r#"
+        SELECT id FROM transfers
+        WHERE status = 'pending' AND settles_at <= CURRENT_TIMESTAMP
+        ORDER BY settles_at
+        LIMIT 100
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
 
-    let user_id = match utils::validate_auth_token(headers, &service) {
-        Ok(val) => val, 
-        Err(err) =>{
-            return Err((err, "Invalid token"));
+    let mut settled = 0;
+    for transfer_id in due {
+        match settle_transfer(pool, config, transfer_id).await {
+            Ok(true) => settled += 1,
+            // picked up by another instance in the meantime
+            Ok(false) => {}
+            Err(err) => tracing::error!("Failed to settle external transfer {transfer_id}: {err}"),
         }
-    };
-    
-    let cursor = match sqlx::query!(
-        "SELECT id, sender_id, recipient_id, amount FROM transfers WHERE sender_id = $1 OR recipient_id = $1",
-        user_id
+    }
+    Ok(settled)
+}
+
+async fn settle_transfer(pool: &PgPool, config: &TxConfig, transfer_id: Uuid) -> Result<bool, BalanceError> {
+    let mut tx = pool.begin().await?;
+
+    let held = sqlx::query!(
+        r#"
+        SELECT sender_wallet_id, receiver_wallet_id, amount FROM transfers
+        WHERE id = $1 AND status = 'pending' AND settles_at IS NOT NULL
+        FOR UPDATE SKIP LOCKED
+        "#,
+        transfer_id
     )
-    .fetch_all(&pool) // perhaps this better replaced with fetch method instead but avoided it due to static lifetime bound issue
-    .await{
-        Ok(cursor) => cursor,
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(held) = held else {
+        return Ok(false);
+    };
+
+    let receiver = Account::Wallet(held.receiver_wallet_id);
+    match balance::credit_within_cap(&mut tx, receiver, held.amount, config.max_account_balance, transfer_id).await {
+        Ok(_) => {
+            let transfer_no = sqlx::query_scalar!(
+                "UPDATE transfer_counter SET value = value + 1 RETURNING value"
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            complete_transfer(&mut tx, transfer_id, transfer_no).await?;
+            tracing::info!("External transfer {transfer_id} settled with no: {transfer_no}");
+        }
+        Err(err @ BalanceError::Database(_)) => return Err(err),
         Err(err) => {
-            tracing::error!("Failed to retrieve transactions: {err}");
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to retrieve transactions"));
+            // the recipient can't take the money, it goes back to the sender it was held from
+            balance::adjust(&mut tx, Account::Wallet(held.sender_wallet_id), held.amount, transfer_id).await?;
+            sqlx::query!(
+                "UPDATE transfers SET status = $2, updated_at = CURRENT_TIMESTAMP WHERE id = $1",
+                transfer_id,
+                TransactionStatus::Failed as TransactionStatus,
+            )
+            .execute(&mut *tx)
+            .await?;
+            tracing::warn!("External transfer {transfer_id} failed to settle, returned to the sender: {err}");
         }
-    };
+    }
 
-    let stream = futures::stream::iter(cursor).map(|transaction| {
-        let record = transaction;
-        let transfer = Transfer {
-            sender_id: record.sender_id,
-            receiver_id: record.recipient_id,
-            amount: record.amount,
-            description: None,
-        };
-        Event::default().json_data(transfer)
-    });
+    tx.commit().await?;
+    Ok(true)
+}
 
-    let sse = Sse::new(stream).keep_alive(
-        axum::response::sse::KeepAlive::new()
-        .interval(std::time::Duration::from_secs(2))
-        .text("keep-alive-text"),
-    );
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Withdrawal {
+    pub amount: Decimal,
+    pub destination_reference: String, // external account the funds are paid out to
+    #[serde(default)]
+    pub wallet_id: Option<Uuid>, // one of the user's wallets, the primary one when left out
+}
 
-    Ok(sse)
+#[derive(Debug, Serialize)]
+pub struct WithdrawalResponse {
+    pub transaction_id: Uuid,
+    pub balance: Decimal,
 }
 
-pub fn tx_route(service: Arc<AuthService>, pool: PgPool) -> Router {
-    Router::new()
-        .route("/tx/transfer", post(create_transaction))
-        .route("/tx/get_tx/:uid", get(get_transaction))
-        .route("/tx/list_txs", get(list_transactions))
-        .with_state((service, pool))
+// debit the authenticated user and record a withdrawal ledger entry, answering with the remaining balance
+async fn create_withdrawal(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    JsonBody(withdrawal): JsonBody<Withdrawal>,
+) -> Result<impl IntoResponse, ApiError> {
+    if withdrawal.amount <= Decimal::ZERO {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_amount",
+            "Amount must be positive",
+        ));
+    }
+    let destination_reference = withdrawal.destination_reference.trim();
+    if destination_reference.is_empty() || destination_reference.len() > 255 {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_destination_reference",
+            "Destination reference must be between 1 and 255 characters",
+        ));
+    }
+
+    let (account, currency) = own_account(&pool, user_id, withdrawal.wallet_id).await?;
+    check_minor_units(withdrawal.amount, &currency)?;
+
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(err) => {
+            tracing::error!("Failed to start transaction: {err}");
+            return Err(ApiError::internal("Failed to withdraw amount"));
+        }
+    };
+
+    // the withdrawal row comes first, its id ties the ledger entry to it
+    let transaction_id = match insert_transaction(
+        &mut tx,
+        user_id,
+        withdrawal.amount,
+        TransactionType::Withdrawal,
+        TransactionStatus::Completed,
+        Some(destination_reference),
+        None,
+    )
+    .await
+    {
+        Ok(transaction_id) => transaction_id,
+        Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+            tracing::warn!("User not found: {user_id}");
+            return Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found"));
+        }
+        Err(err) => {
+            tracing::error!("Failed to record withdrawal: {err}");
+            return Err(ApiError::internal("Failed to withdraw amount"));
+        }
+    };
+
+    // Deduct amount from the user, the row stays locked until the transaction ends
+    let balance = match balance::debit_if_sufficient(&mut tx, account, withdrawal.amount, transaction_id).await {
+        Ok(balance) => balance,
+        Err(BalanceError::InsufficientFunds) => {
+            tracing::warn!("Insufficient funds for withdrawal by user: {user_id}");
+            return Err(ApiError::new(
+                StatusCode::PAYMENT_REQUIRED,
+                "insufficient_funds",
+                "Insufficient funds",
+            ));
+        }
+        Err(BalanceError::UserNotFound) => {
+            tracing::warn!("User not found: {user_id}");
+            return Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found"));
+        }
+        Err(BalanceError::AccountFrozen) => {
+            tracing::warn!("Withdrawal attempt from frozen account: {user_id}");
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "account_frozen", "Account is frozen"));
+        }
+        Err(BalanceError::AccountClosed) => {
+            tracing::warn!("Withdrawal attempt from closed account: {user_id}");
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "account_closed", "Account is closed"));
+        }
+        Err(err) => {
+            tracing::error!("Failed to debit user: {err}");
+            return Err(ApiError::internal("Failed to withdraw amount"));
+        }
+    };
+
+    let audit_entry = AuditEntry {
+        actor_id: user_id,
+        action: AuditAction::Withdrawal,
+        amount: withdrawal.amount,
+        counterparty_id: None,
+        tx_id: transaction_id,
+    };
+    if let Err(err) = audit::record(&mut tx, &audit_entry).await {
+        tracing::error!("Failed to record audit entry for withdrawal {transaction_id}: {err}");
+        return Err(ApiError::internal("Failed to withdraw amount"));
+    }
+
+    match tx.commit().await {
+        Ok(_) => {
+            tracing::info!("Withdrawal {transaction_id} of {} by user: {user_id}", withdrawal.amount);
+            Ok((StatusCode::OK, Json(WithdrawalResponse { transaction_id, balance })))
+        }
+        Err(err) => {
+            tracing::error!("Failed to commit withdrawal: {err}");
+            Err(ApiError::internal("Failed to withdraw amount"))
+        }
+    }
+}
+
+// return a specific transaction by it's transaction_id which belongs to it's user
+async fn get_transaction(
+    AuthUser(header_uid): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    transaction_id: Result<Path<Uuid>, PathRejection>, // transaction_id: Uuid
+) -> Result<impl IntoResponse, ApiError> {
+    let Path(transaction_id) = match transaction_id {
+        Ok(path) => path,
+        Err(rejection) => {
+            tracing::warn!("Malformed transaction id in path: {rejection}");
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_path_parameter",
+                "Invalid path parameter `uid`: expected a UUID",
+            ));
+        }
+    };
+
+    let transaction = match sqlx::query_as::<_, TransferDetails>(
+        r#"
+        SELECT id, transfer_no, sender_id, recipient_id AS receiver_id, sender_wallet_id, receiver_wallet_id, amount, currency, description, channel, metadata, reversed_tx_id, refunded_amount, transfer_kind, status, scheduled_for, settles_at, created_at, updated_at
+        FROM transfers
+        WHERE id = $1 AND (sender_id = $2 OR recipient_id = $2)
+        "#,
+    )
+    .bind(transaction_id)
+    .bind(header_uid)
+    .fetch_one(&pool)
+    .await
+    {
+        Ok(transaction) => transaction,
+        // someone else's transfer looks exactly like one that doesn't exist
+        Err(sqlx::Error::RowNotFound) => {
+            tracing::warn!("Transaction {transaction_id} not found for user: {header_uid}");
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "transaction_not_found",
+                "Transaction not found",
+            ));
+        }
+        Err(err) => {
+            tracing::error!("Failed to retrieve transaction: {err}");
+            return Err(ApiError::internal("Failed to retrieve transaction"));
+        }
+    };
+
+    Ok((StatusCode::OK, Json(transaction)))
+}
+
+// Optional body of a refund, without one whatever is left of the transfer is refunded
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Refund {
+    pub amount: Decimal,
+}
+
+// Reverse a transfer the caller received by sending (part of) the amount back to its sender. Each refund is a
+// transfer of its own linked through `reversed_tx_id`, recorded and completed in a single database transaction,
+// the original keeps the running total in `refunded_amount`
+async fn refund_transaction(
+    AuthUser(header_uid): AuthUser,
+    State((_, pool, config)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    transaction_id: Result<Path<Uuid>, PathRejection>,
+    OptionalJsonBody(refund): OptionalJsonBody<Refund>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Path(transaction_id) = match transaction_id {
+        Ok(path) => path,
+        Err(rejection) => {
+            tracing::warn!("Malformed transaction id in path: {rejection}");
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_path_parameter",
+                "Invalid path parameter `uid`: expected a UUID",
+            ));
+        }
+    };
+
+    let original = match sqlx::query!(
+        r#"
+        SELECT sender_id, recipient_id, sender_wallet_id, receiver_wallet_id, amount, refunded_amount,
+               currency AS "currency: Currency", reversed_tx_id, COALESCE(scheduled_for, created_at) AS moved_at
+        FROM transfers
+        WHERE id = $1 AND (sender_id = $2 OR recipient_id = $2) AND status = 'completed'
+        "#,
+        transaction_id,
+        header_uid
+    )
+    .fetch_optional(&pool)
+    .await
+    {
+        Ok(Some(original)) => original,
+        Ok(None) => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "transaction_not_found",
+                "Transaction not found",
+            ));
+        }
+        Err(err) => {
+            tracing::error!("Failed to retrieve transaction: {err}");
+            return Err(ApiError::internal("Failed to refund transaction"));
+        }
+    };
+
+    // the money sits with the recipient, a sender reclaiming it would debit someone else's account
+    if original.recipient_id != header_uid {
+        tracing::warn!("Refund of transfer {transaction_id} attempted by its sender: {header_uid}");
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "refund_not_allowed",
+            "Only the recipient can refund a transfer",
+        ));
+    }
+    if original.reversed_tx_id.is_some() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "not_refundable",
+            "A refund cannot be refunded",
+        ));
+    }
+    // scheduled transfers count from when they ran
+    let moved_at = original.moved_at.map(convert_offsetdt_to_dt);
+    if let (Some(window), Some(moved_at)) = (config.reversal_window, moved_at) {
+        if moved_at + window < Utc::now() {
+            tracing::warn!("Refund of transfer {transaction_id} attempted after the reversal window");
+            return Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "reversal_window_expired",
+                format!("Transfers can only be refunded within {} seconds", window.as_secs()),
+            ));
+        }
+    }
+
+    let refundable = original.amount - original.refunded_amount;
+    if refundable <= Decimal::ZERO {
+        return Err(already_refunded(transaction_id));
+    }
+    let amount = refund.map_or(refundable, |refund| refund.amount);
+    if amount <= Decimal::ZERO {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_amount",
+            "Amount must be positive",
+        ));
+    }
+    if amount > refundable {
+        return Err(refund_exceeds_amount());
+    }
+    check_minor_units(amount, &original.currency)?;
+
+    let refunded = async {
+        let mut tx = pool.begin().await?;
+
+        // the row lock on the original makes concurrent refunds of it add up one after another,
+        // one that no longer fits leaves the total untouched
+        let counted = sqlx::query_scalar!(
+            r#"
+            UPDATE transfers SET refunded_amount = refunded_amount + $2, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND refunded_amount + $2 <= amount
+            RETURNING id
+            "#,
+            transaction_id,
+            amount
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if counted.is_none() {
+            return Ok(None);
+        }
+
+        let refund_id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO transfers (sender_id, recipient_id, sender_wallet_id, receiver_wallet_id, amount, currency, description, status, reversed_tx_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING id
+            "#,
+            header_uid,
+            original.sender_id,
+            original.receiver_wallet_id,
+            original.sender_wallet_id,
+            amount,
+            original.currency.as_str(),
+            format!("Refund of {transaction_id}"),
+            TransactionStatus::Pending as TransactionStatus,
+            transaction_id,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // the money goes back the way it came, between the wallets of the original
+        let refunder = Account::Wallet(original.receiver_wallet_id);
+        let refunded_to = Account::Wallet(original.sender_wallet_id);
+        balance::debit_if_sufficient(&mut tx, refunder, amount, refund_id).await?;
+        balance::credit_within_cap(&mut tx, refunded_to, amount, config.max_account_balance, refund_id).await?;
+
+        let transfer_no = sqlx::query_scalar!(
+            "UPDATE transfer_counter SET value = value + 1 RETURNING value"
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let receipt = complete_transfer(&mut tx, refund_id, transfer_no).await?;
+
+        let audit_entry = AuditEntry {
+            actor_id: header_uid,
+            action: AuditAction::Refund,
+            amount,
+            counterparty_id: Some(original.sender_id),
+            tx_id: refund_id,
+        };
+        audit::record(&mut tx, &audit_entry).await?;
+
+        tx.commit().await?;
+        Ok::<_, BalanceError>(Some(receipt))
+    }
+    .await;
+
+    match refunded {
+        Ok(Some(receipt)) => {
+            tracing::info!("Transfer {transaction_id} refunded {amount} by {}", receipt.id);
+            Ok(transfer_created(receipt))
+        }
+        // a concurrent refund took what was left meanwhile
+        Ok(None) => Err(refund_exceeds_amount()),
+        Err(BalanceError::InsufficientFunds) => {
+            tracing::warn!("Insufficient funds to refund transfer {transaction_id} by user: {header_uid}");
+            Err(ApiError::new(
+                StatusCode::PAYMENT_REQUIRED,
+                "insufficient_funds",
+                "Insufficient funds",
+            ))
+        }
+        Err(BalanceError::AccountFrozen) => {
+            tracing::warn!("Refund of transfer {transaction_id} involves a frozen account");
+            Err(ApiError::new(StatusCode::FORBIDDEN, "account_frozen", "Account is frozen"))
+        }
+        Err(BalanceError::AccountClosed) => {
+            tracing::warn!("Refund of transfer {transaction_id} involves a closed account");
+            Err(ApiError::new(StatusCode::FORBIDDEN, "account_closed", "Account is closed"))
+        }
+        Err(BalanceError::BalanceCapExceeded) => Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "balance_cap_exceeded",
+            "Refund would exceed the original sender's maximum balance",
+        )),
+        Err(BalanceError::UserNotFound) => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "user_not_found",
+            "User not found",
+        )),
+        Err(err) => {
+            tracing::error!("Failed to refund transfer {transaction_id}: {err}");
+            Err(ApiError::internal("Failed to refund transaction"))
+        }
+    }
+}
+
+fn already_refunded(transaction_id: Uuid) -> ApiError {
+    tracing::warn!("Transfer {transaction_id} is already refunded");
+    ApiError::new(
+        StatusCode::CONFLICT,
+        "already_refunded",
+        "Transaction is already refunded",
+    )
+}
+
+fn refund_exceeds_amount() -> ApiError {
+    ApiError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "refund_exceeds_amount",
+        "Refunds cannot add up to more than the transfer amount",
+    )
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListParams {
+    pub limit: Option<i64>,
+    pub before: Option<Uuid>, // id of the last transfer seen on the previous page
+}
+
+// Side of a transfer seen from the user listing it
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryDirection {
+    Credit, // money came in
+    Debit,  // money went out
+}
+
+// Transfer as listed by `list_transactions`, enriched so clients can render it without comparing ids
+#[derive(Debug, Serialize)]
+pub struct HistoryItem {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub transfer: Transfer,
+    pub direction: HistoryDirection,
+    pub counterparty_id: Uuid,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+fn history_item(user_id: Uuid, record: TransferRecord) -> HistoryItem {
+    let (direction, counterparty_id) = if record.receiver_id == user_id {
+        (HistoryDirection::Credit, record.sender_id)
+    } else {
+        (HistoryDirection::Debit, record.receiver_id)
+    };
+
+    HistoryItem {
+        id: record.id,
+        transfer: Transfer {
+            sender_id: record.sender_id,
+            receiver_id: record.receiver_id,
+            amount: record.amount,
+            currency: Some(record.currency),
+            description: record.description,
+            metadata: record.metadata,
+            transfer_no: Some(record.transfer_no),
+            execute_at: None,
+            transfer_kind: record.transfer_kind,
+            sender_wallet_id: Some(record.sender_wallet_id),
+            receiver_wallet_id: Some(record.receiver_wallet_id),
+        },
+        direction,
+        counterparty_id,
+        created_at: record.created_at,
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryPage {
+    pub items: Vec<HistoryItem>,
+    pub next_cursor: Option<Uuid>, // pass as `before` for the next page, absent on the last page
+}
+
+const DEFAULT_LIST_LIMIT: i64 = 25;
+const MAX_LIST_LIMIT: i64 = 100;
+
+// Only clients asking for `text/event-stream` get the page streamed, everyone else gets plain JSON
+fn wants_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| {
+            media_type
+                .split(';')
+                .next()
+                .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("text/event-stream"))
+        })
+}
+
+// return a page of the transactions which a user made through it's user_id, most recently completed first.
+// As JSON the page carries `next_cursor`, as SSE every event carries the transfer id as its id,
+// either way pass the last id as `before` for the next page
+async fn list_transactions(
+    headers: HeaderMap,
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    params: Result<Query<ListParams>, QueryRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Query(params) = match params {
+        Ok(query) => query,
+        Err(rejection) => {
+            tracing::warn!("Malformed list query: {rejection}");
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "Invalid query: `limit` must be a number and `before` a UUID",
+            ));
+        }
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+
+    // keyset pagination on `transfer_no`, handed out in commit order so a transfer completing between two fetches
+    // always sorts before the cursor (unlike `created_at`, which a scheduled transfer carries from when it was made).
+    // A `before` which isn't one of the user's completed transfers yields an empty page
+    let cursor = match sqlx::query_as::<_, TransferRecord>(
+        r#"
+        SELECT id, transfer_no, sender_id, recipient_id AS receiver_id, sender_wallet_id, receiver_wallet_id, amount, currency, description, channel, metadata, reversed_tx_id, transfer_kind, created_at
+        FROM transfers
+        WHERE (sender_id = $1 OR recipient_id = $1) AND status = 'completed'
+          AND ($2::UUID IS NULL OR transfer_no < (
+              SELECT transfer_no FROM transfers
+              WHERE id = $2 AND (sender_id = $1 OR recipient_id = $1)
+          ))
+        ORDER BY transfer_no DESC
+        LIMIT $3
+        "#,
+    )
+    .bind(user_id)
+    .bind(params.before)
+    .bind(limit + 1) // one extra row tells whether another page follows
+    .fetch_all(&pool) // perhaps this better replaced with fetch method instead but avoided it due to static lifetime bound issue
+    .await{
+        Ok(cursor) => cursor,
+        Err(err) => {
+            tracing::error!("Failed to retrieve transactions: {err}");
+            return Err(ApiError::internal("Failed to retrieve transactions"));
+        }
+    };
+
+    let mut records = cursor;
+    let has_more = records.len() as i64 > limit;
+    records.truncate(limit as usize);
+    let items = records
+        .into_iter()
+        .map(|record| history_item(user_id, record))
+        .collect::<Vec<_>>();
+
+    if !wants_event_stream(&headers) {
+        let next_cursor = if has_more { items.last().map(|item| item.id) } else { None };
+        return Ok((StatusCode::OK, Json(HistoryPage { items, next_cursor })).into_response());
+    }
+
+    let stream = futures::stream::iter(items).map(|item| Event::default().id(item.id.to_string()).json_data(item));
+
+    let sse = Sse::new(stream).keep_alive(
+        axum::response::sse::KeepAlive::new()
+        .interval(std::time::Duration::from_secs(2))
+        .text("keep-alive-text"),
+    );
+
+    Ok(sse.into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SummaryParams {
+    pub since: Option<DateTime<Utc>>, // only count transfers made at or after this instant
+}
+
+// Totals over the user's completed transfers in one currency, amounts serialize as strings like everywhere else
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct TxSummary {
+    pub currency: Currency,
+    pub total_sent: Decimal,
+    pub total_received: Decimal,
+    pub net: Decimal, // received minus sent
+    pub count: i64,
+}
+
+// aggregate the user's transfers in a single pass instead of paging through them, one entry per currency
+// as amounts in different currencies can't be added up
+async fn transaction_summary(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    params: Result<Query<SummaryParams>, QueryRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Query(params) = match params {
+        Ok(query) => query,
+        Err(rejection) => {
+            tracing::warn!("Malformed summary query: {rejection}");
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "Invalid query: `since` must be an RFC 3339 timestamp",
+            ));
+        }
+    };
+
+    // bound at runtime, the query macros would want `since` as an `OffsetDateTime`
+    let totals = sqlx::query_as::<_, TxSummary>(
+        r#"
+        SELECT
+            currency,
+            COALESCE(SUM(amount) FILTER (WHERE sender_id = $1), 0) AS total_sent,
+            COALESCE(SUM(amount) FILTER (WHERE recipient_id = $1), 0) AS total_received,
+            COALESCE(SUM(CASE WHEN recipient_id = $1 THEN amount ELSE -amount END), 0) AS net,
+            COUNT(*) AS count
+        FROM transfers
+        WHERE (sender_id = $1 OR recipient_id = $1) AND status = 'completed'
+          AND ($2::TIMESTAMPTZ IS NULL OR created_at >= $2)
+        GROUP BY currency
+        ORDER BY currency
+        "#,
+    )
+    .bind(user_id)
+    .bind(params.since)
+    .fetch_all(&pool)
+    .await;
+
+    match totals {
+        Ok(totals) => Ok((StatusCode::OK, Json(totals))),
+        Err(err) => {
+            tracing::error!("Failed to compute transaction summary: {err}");
+            Err(ApiError::internal("Failed to compute transaction summary"))
+        }
+    }
+}
+
+// Single sided ledger entry (deposit or withdrawal) as returned by `/tx/ledger`
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct LedgerEntry {
+    pub id: Uuid,
+    pub transaction_type: String,
+    pub status: String,
+    pub amount: Decimal,
+    pub reference_id: Option<String>,
+    pub external_reference: Option<String>,
+    pub description: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// return a page of the user's deposits and withdrawals, newest first, paged like `list_transactions`
+async fn list_ledger(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    params: Result<Query<ListParams>, QueryRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Query(params) = match params {
+        Ok(query) => query,
+        Err(rejection) => {
+            tracing::warn!("Malformed ledger query: {rejection}");
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "Invalid query: `limit` must be a number and `before` a UUID",
+            ));
+        }
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+
+    let entries = sqlx::query_as::<_, LedgerEntry>(
+        r#"
+        SELECT id, transaction_type, status, amount, reference_id, external_reference, description, created_at FROM transactions
+        WHERE user_id = $1
+          AND ($2::UUID IS NULL OR (created_at, id) < (
+              SELECT created_at, id FROM transactions WHERE id = $2 AND user_id = $1
+          ))
+        ORDER BY created_at DESC, id DESC
+        LIMIT $3
+        "#,
+    )
+    .bind(user_id)
+    .bind(params.before)
+    .bind(limit)
+    .fetch_all(&pool)
+    .await;
+
+    match entries {
+        Ok(entries) => Ok((StatusCode::OK, Json(entries))),
+        Err(err) => {
+            tracing::error!("Failed to retrieve ledger entries: {err}");
+            Err(ApiError::internal("Failed to retrieve ledger entries"))
+        }
+    }
+}
+
+// positive `net` means the user received more from the counterparty than it sent to it
+#[derive(Debug, Serialize)]
+pub struct NetPosition {
+    pub counterparty_id: Uuid,
+    pub currency: Currency,
+    pub net: Decimal,
+}
+
+// return the signed sum of all transfers between the user and a counterparty, one entry per currency they
+// exchanged money in
+async fn net_position(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    counterparty_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Path(counterparty_id) = match counterparty_id {
+        Ok(path) => path,
+        Err(rejection) => {
+            tracing::warn!("Malformed counterparty id in path: {rejection}");
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_path_parameter",
+                "Invalid path parameter `counterparty_id`: expected a UUID",
+            ));
+        }
+    };
+
+    let positions = sqlx::query!(
+        r#"
+        SELECT currency AS "currency: Currency", SUM(CASE WHEN recipient_id = $1 THEN amount ELSE -amount END) AS "net!"
+        FROM transfers
+        WHERE ((sender_id = $1 AND recipient_id = $2) OR (sender_id = $2 AND recipient_id = $1))
+          AND status = 'completed'
+        GROUP BY currency
+        ORDER BY currency
+        "#,
+        user_id,
+        counterparty_id
+    )
+    .fetch_all(&pool)
+    .await;
+
+    match positions {
+        Ok(records) => {
+            let positions: Vec<NetPosition> = records
+                .into_iter()
+                .map(|record| NetPosition {
+                    counterparty_id,
+                    currency: record.currency,
+                    net: record.net,
+                })
+                .collect();
+            Ok((StatusCode::OK, Json(positions)))
+        }
+        Err(err) => {
+            tracing::error!("Failed to compute net position: {err}");
+            Err(ApiError::internal("Failed to compute net position"))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportStatus {
+    pub id: Uuid,
+    pub status: String,
+    pub error: Option<String>,
+}
+
+// queue a CSV export of every transaction of the user, processed by the export worker
+async fn create_export(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+) -> Result<impl IntoResponse, ApiError> {
+    match ExportRepository::new(pool).create_job(user_id).await {
+        Ok(job_id) => {
+            tracing::info!("Export job {job_id} queued for user: {user_id}");
+            let status = ExportStatus {
+                id: job_id,
+                status: "pending".to_string(),
+                error: None,
+            };
+            Ok((StatusCode::ACCEPTED, Json(status)))
+        }
+        Err(err) => {
+            tracing::error!("Failed to queue export job: {err}");
+            Err(ApiError::internal("Failed to queue export"))
+        }
+    }
+}
+
+// return the status of an export job, or the CSV itself once the job completed
+async fn get_export(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    job_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<Response, ApiError> {
+    let Path(job_id) = match job_id {
+        Ok(path) => path,
+        Err(rejection) => {
+            tracing::warn!("Malformed export id in path: {rejection}");
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_path_parameter",
+                "Invalid path parameter `id`: expected a UUID",
+            ));
+        }
+    };
+
+    let job = match ExportRepository::new(pool).find_job(job_id, user_id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "export_not_found",
+            "Export not found",
+        )),
+        Err(err) => {
+            tracing::error!("Failed to retrieve export job: {err}");
+            return Err(ApiError::internal("Failed to retrieve export"));
+        }
+    };
+
+    match (job.status.as_str(), job.content) {
+        ("completed", Some(content)) => {
+            let disposition = format!("attachment; filename=\"transactions-{}.csv\"", job.id);
+            Ok((
+                StatusCode::OK,
+                [
+                    (header::CONTENT_TYPE, "text/csv".to_string()),
+                    (header::CONTENT_DISPOSITION, disposition),
+                ],
+                content,
+            )
+                .into_response())
+        }
+        _ => {
+            let status = ExportStatus {
+                id: job.id,
+                status: job.status,
+                error: job.error,
+            };
+            Ok((StatusCode::OK, Json(status)).into_response())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+// every filter is optional, the ones present are combined with AND
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TxQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub min_amount: Option<Decimal>,
+    pub max_amount: Option<Decimal>,
+    pub counterparty_id: Option<Uuid>,
+    pub direction: Option<Direction>,
+    pub category: Option<String>, // matched against the `category` key of the transfer metadata
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct TransferRecord {
+    pub id: Uuid,
+    pub transfer_no: i64,
+    pub sender_id: Uuid,
+    pub receiver_id: Uuid,
+    pub sender_wallet_id: Uuid,
+    pub receiver_wallet_id: Uuid,
+    pub amount: Decimal,
+    pub currency: Currency,
+    pub description: Option<String>,
+    pub channel: String,
+    pub metadata: Option<serde_json::Value>,
+    pub reversed_tx_id: Option<Uuid>, // set on refunds, the transfer they reverse
+    pub transfer_kind: TransferKind,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TxQueryPage {
+    pub items: Vec<TransferRecord>,
+    pub next_offset: Option<i64>, // absent on the last page
+}
+
+const DEFAULT_QUERY_LIMIT: i64 = 50;
+const MAX_QUERY_LIMIT: i64 = 100;
+
+// search the user's transfers with a combination of filters, newest first
+async fn query_transactions(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    JsonBody(query): JsonBody<TxQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_filter",
+                "`from` must not be after `to`",
+            ));
+        }
+    }
+    if let (Some(min), Some(max)) = (query.min_amount, query.max_amount) {
+        if min > max {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_filter",
+                "`min_amount` must not exceed `max_amount`",
+            ));
+        }
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+    if !(1..=MAX_QUERY_LIMIT).contains(&limit) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_filter",
+            "`limit` must be between 1 and 100",
+        ));
+    }
+    let offset = query.offset.unwrap_or(0);
+    if offset < 0 {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_filter",
+            "`offset` must not be negative",
+        ));
+    }
+
+    let mut builder = QueryBuilder::<Postgres>::new(
+        "SELECT id, transfer_no, sender_id, recipient_id AS receiver_id, sender_wallet_id, receiver_wallet_id, amount, currency, description, channel, metadata, reversed_tx_id, transfer_kind, created_at FROM transfers WHERE status = 'completed' AND ",
+    );
+    match query.direction {
+        Some(Direction::Sent) => {
+            builder.push("sender_id = ").push_bind(user_id);
+            if let Some(counterparty_id) = query.counterparty_id {
+                builder.push(" AND recipient_id = ").push_bind(counterparty_id);
+            }
+        }
+        Some(Direction::Received) => {
+            builder.push("recipient_id = ").push_bind(user_id);
+            if let Some(counterparty_id) = query.counterparty_id {
+                builder.push(" AND sender_id = ").push_bind(counterparty_id);
+            }
+        }
+        None => {
+            builder
+                .push("(sender_id = ")
+                .push_bind(user_id)
+                .push(" OR recipient_id = ")
+                .push_bind(user_id)
+                .push(")");
+            if let Some(counterparty_id) = query.counterparty_id {
+                builder
+                    .push(" AND (sender_id = ")
+                    .push_bind(counterparty_id)
+                    .push(" OR recipient_id = ")
+                    .push_bind(counterparty_id)
+                    .push(")");
+            }
+        }
+    }
+    if let Some(from) = query.from {
+        builder.push(" AND created_at >= ").push_bind(from);
+    }
+    if let Some(to) = query.to {
+        builder.push(" AND created_at <= ").push_bind(to);
+    }
+    if let Some(min_amount) = query.min_amount {
+        builder.push(" AND amount >= ").push_bind(min_amount);
+    }
+    if let Some(max_amount) = query.max_amount {
+        builder.push(" AND amount <= ").push_bind(max_amount);
+    }
+    if let Some(category) = query.category {
+        builder.push(" AND metadata->>'category' = ").push_bind(category);
+    }
+    // one extra row tells whether another page follows
+    builder
+        .push(" ORDER BY created_at DESC, id DESC LIMIT ")
+        .push_bind(limit + 1)
+        .push(" OFFSET ")
+        .push_bind(offset);
+
+    let mut items = match builder
+        .build_query_as::<TransferRecord>()
+        .fetch_all(&pool)
+        .await
+    {
+        Ok(items) => items,
+        Err(err) => {
+            tracing::error!("Failed to query transactions: {err}");
+            return Err(ApiError::internal("Failed to query transactions"));
+        }
+    };
+
+    let next_offset = if items.len() as i64 > limit {
+        items.truncate(limit as usize);
+        Some(offset + limit)
+    } else {
+        None
+    };
+
+    Ok((StatusCode::OK, Json(TxQueryPage { items, next_offset })))
+}
+
+pub fn tx_route(service: Arc<AuthService>, pool: PgPool, config: Arc<TxConfig>) -> Router {
+    Router::new()
+        .route("/tx/transfer", post(create_transaction))
+        .route("/tx/withdraw", post(create_withdrawal))
+        .route("/tx/get_tx/:uid", get(get_transaction))
+        .route("/tx/:uid/refund", post(refund_transaction))
+        .route("/tx/list_txs", get(list_transactions))
+        .route("/tx/ledger", get(list_ledger))
+        .route("/tx/summary", get(transaction_summary))
+        .route("/tx/query", post(query_transactions))
+        .route("/tx/net/:counterparty_id", get(net_position))
+        .route("/tx/export", post(create_export))
+        .route("/tx/export/:id", get(get_export))
+        .with_state((service, pool, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use chrono::{DateTime, Duration, Utc};
+    use rust_decimal::Decimal;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use super::{execute_due_transfers, settle_external_transfers, TransferReceipt, TxConfig};
+    use crate::db::{
+        balance::{self, Account, BalanceError},
+        ledger,
+        tx::TransactionStatus,
+        wallet::WalletRepository,
+    };
+    use crate::test_utils::{auth_config, decimal, tx_config, TestApp, TestResponse, TestUser};
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn malformed_ids_in_the_path_are_rejected(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+
+        let requests = [
+            app.get("/v1/tx/get_tx/not-a-uuid"),
+            app.post("/v1/tx/not-a-uuid/refund"),
+            app.get("/v1/tx/net/not-a-uuid"),
+            app.get("/v1/tx/export/not-a-uuid"),
+        ];
+        for request in requests {
+            let response = request.token(&alice.token).send().await;
+            assert_eq!(response.status, StatusCode::BAD_REQUEST, "{}", response.body);
+            assert_eq!(response.error_code(), "invalid_path_parameter");
+        }
+    }
+
+    // 401 when the caller can't be identified, 403 when they can but act for someone else
+    #[sqlx::test(migrations = "./migrations")]
+    async fn unauthenticated_and_forbidden_are_told_apart(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&bob, "50").await;
+        let body = json!({ "sender_id": bob.id, "receiver_id": alice.id, "amount": "10" });
+
+        let response = app.post("/v1/tx/transfer").json(body.clone()).send().await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response.error_code(), "invalid_token");
+        let response = app.post("/v1/tx/transfer").token("not-a-token").json(body.clone()).send().await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response.error_code(), "invalid_token");
+
+        let response = app.post("/v1/tx/transfer").token(&alice.token).json(body).send().await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(response.error_code(), "sender_mismatch");
+        let response = app
+            .post("/v1/users/deposit")
+            .token(&alice.token)
+            .json(json!({ "email": bob.email, "amount": "10" }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(response.error_code(), "user_mismatch");
+
+        assert_eq!(app.balance(&bob).await, Decimal::from(50));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn unknown_body_fields_are_rejected(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        // a misspelt optional field would otherwise be dropped without a word
+        let response = app
+            .post("/v1/tx/transfer")
+            .token(&alice.token)
+            .json(json!({ "sender_id": alice.id, "receiver_id": bob.id, "amount": "10", "descripton": "rent" }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.error_code(), "invalid_body");
+        assert!(response.body["error"]["message"].as_str().unwrap().contains("descripton"), "{}", response.body);
+        assert_eq!(app.balance(&bob).await, Decimal::ZERO);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn metadata_round_trips_within_the_size_limit(pool: PgPool) {
+        let config = TxConfig {
+            max_metadata_bytes: 64,
+            ..tx_config()
+        };
+        let app = TestApp::with_config(pool, auth_config(), config);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+        let transfer = |metadata: serde_json::Value| {
+            app.post("/v1/tx/transfer")
+                .token(&alice.token)
+                .json(json!({ "sender_id": alice.id, "receiver_id": bob.id, "amount": "1", "metadata": metadata }))
+                .send()
+        };
+
+        let metadata = json!({ "category": "rent", "invoice": { "no": 42, "paid": true } });
+        let response = transfer(metadata.clone()).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        let id = response.body["id"].as_str().unwrap();
+        let details = app.get(&format!("/v1/tx/get_tx/{id}")).token(&bob.token).send().await;
+        assert_eq!(details.body["metadata"], metadata);
+
+        for metadata in [json!({ "note": "x".repeat(64) }), json!(["not", "an", "object"])] {
+            let response = transfer(metadata).await;
+            assert_eq!(response.status, StatusCode::BAD_REQUEST);
+            assert_eq!(response.error_code(), "invalid_metadata");
+        }
+        assert_eq!(app.balance(&bob).await, Decimal::ONE);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn query_filters_by_date_and_amount_range(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "500").await;
+        let mut ids = Vec::new();
+        for amount in ["5", "20", "100", "300"] {
+            let transfer = app.transfer(&alice, bob.id, amount).await;
+            ids.push(transfer.body["id"].as_str().unwrap().to_string());
+        }
+        // the 100 was made last week
+        sqlx::query("UPDATE transfers SET created_at = CURRENT_TIMESTAMP - interval '7 days' WHERE id = $1::UUID")
+            .bind(&ids[2])
+            .execute(&app.pool)
+            .await
+            .unwrap();
+        let query = |filters: serde_json::Value| app.post("/v1/tx/query").token(&alice.token).json(filters).send();
+        let amounts = |response: &TestResponse| {
+            response.body["items"].as_array().unwrap().iter().map(|item| decimal(&item["amount"])).collect::<Vec<_>>()
+        };
+
+        let response = query(json!({ "min_amount": "20", "max_amount": "100" })).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(amounts(&response), [Decimal::from(20), Decimal::from(100)]);
+
+        let since = Utc::now() - Duration::days(1);
+        let response = query(json!({ "from": since, "to": Utc::now() })).await;
+        assert_eq!(amounts(&response), [Decimal::from(300), Decimal::from(20), Decimal::from(5)]);
+        let response = query(json!({ "to": since })).await;
+        assert_eq!(amounts(&response), [Decimal::from(100)]);
+
+        let response = query(json!({ "from": since, "min_amount": "10", "max_amount": "250" })).await;
+        assert_eq!(amounts(&response), [Decimal::from(20)]);
+
+        for filters in [json!({ "from": Utc::now(), "to": since }), json!({ "min_amount": "10", "max_amount": "5" })] {
+            let response = query(filters).await;
+            assert_eq!(response.status, StatusCode::BAD_REQUEST);
+            assert_eq!(response.error_code(), "invalid_filter");
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn get_tx_reports_status_and_timestamps(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let transfer = app.transfer(&alice, bob.id, "20").await;
+        assert_eq!(transfer.status, StatusCode::OK, "{}", transfer.body);
+        let id = transfer.body["id"].as_str().unwrap();
+
+        // both parties see it
+        for user in [&alice, &bob] {
+            let response = app.get(&format!("/v1/tx/get_tx/{id}")).token(&user.token).send().await;
+            assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+            assert_eq!(response.body["status"], "completed");
+            assert!(response.body["transfer_no"].is_i64());
+            DateTime::parse_from_rfc3339(response.body["created_at"].as_str().unwrap()).unwrap();
+            DateTime::parse_from_rfc3339(response.body["updated_at"].as_str().unwrap()).unwrap();
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn committed_transfers_end_up_completed(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let transfer = app.transfer(&alice, bob.id, "20").await;
+        assert_eq!(transfer.status, StatusCode::OK, "{}", transfer.body);
+        assert_eq!(transfer.body["status"], "completed");
+        let failed = app.transfer(&alice, bob.id, "100").await;
+        assert_eq!(failed.status, StatusCode::PAYMENT_REQUIRED);
+
+        // stored as the Postgres enum, pending only until the money moved
+        let statuses: Vec<(Decimal, TransactionStatus)> =
+            sqlx::query_as("SELECT amount, status FROM transfers WHERE sender_id = $1 ORDER BY created_at")
+                .bind(alice.id)
+                .fetch_all(&app.pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            statuses,
+            [(Decimal::from(20), TransactionStatus::Completed), (Decimal::from(100), TransactionStatus::Failed)]
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn get_tx_includes_failed_transfers(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+
+        let transfer = app.transfer(&alice, bob.id, "20").await;
+        assert_eq!(transfer.status, StatusCode::PAYMENT_REQUIRED);
+        let id: Uuid = sqlx::query_scalar("SELECT id FROM transfers WHERE sender_id = $1")
+            .bind(alice.id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+
+        let response = app.get(&format!("/v1/tx/get_tx/{id}")).token(&alice.token).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(response.body["status"], "failed");
+        assert!(response.body.get("transfer_no").is_none());
+        DateTime::parse_from_rfc3339(response.body["created_at"].as_str().unwrap()).unwrap();
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn get_tx_hides_other_users_transfers(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let mallory = app.register("mallory").await;
+        app.deposit(&alice, "50").await;
+
+        let transfer = app.transfer(&alice, bob.id, "20").await;
+        let id = transfer.body["id"].as_str().unwrap();
+
+        let response = app.get(&format!("/v1/tx/get_tx/{id}")).token(&mallory.token).send().await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert_eq!(response.error_code(), "transaction_not_found");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn description_is_stored_and_read_back(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let transfer = app
+            .post("/v1/tx/transfer")
+            .token(&alice.token)
+            .json(json!({ "sender_id": alice.id, "receiver_id": bob.id, "amount": "20", "description": "rent for march" }))
+            .send()
+            .await;
+        assert_eq!(transfer.status, StatusCode::OK, "{}", transfer.body);
+        let id = transfer.body["id"].as_str().unwrap();
+
+        let response = app.get(&format!("/v1/tx/get_tx/{id}")).token(&bob.token).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(response.body["description"], "rent for march");
+        let listed = app.get("/v1/tx/list_txs").token(&alice.token).send().await;
+        assert_eq!(listed.body["items"][0]["description"], "rent for march");
+
+        // a transfer without one reads back without one
+        let transfer = app.transfer(&alice, bob.id, "5").await;
+        let id = transfer.body["id"].as_str().unwrap();
+        let response = app.get(&format!("/v1/tx/get_tx/{id}")).token(&alice.token).send().await;
+        assert!(response.body["description"].is_null());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn immediate_transfer_completes_right_away(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let transfer = app.transfer(&alice, bob.id, "20").await;
+        assert_eq!(transfer.status, StatusCode::OK, "{}", transfer.body);
+        assert_eq!(transfer.body["status"], "completed");
+        assert_eq!(transfer.body["transfer_kind"], "internal");
+        assert!(transfer.body.get("scheduled_for").is_none());
+        assert!(transfer.body.get("settles_at").is_none());
+        assert_eq!(app.balance(&alice).await, Decimal::from(30));
+        assert_eq!(app.balance(&bob).await, Decimal::from(20));
+
+        let id = transfer.body["id"].as_str().unwrap();
+        let details = app.get(&format!("/v1/tx/get_tx/{id}")).token(&bob.token).send().await;
+        assert_eq!(details.body["status"], "completed");
+        assert_eq!(details.body["transfer_no"], transfer.body["transfer_no"]);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn withdrawals_debit_the_balance_and_refuse_overdrafts(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        app.deposit(&alice, "50").await;
+        let withdraw = |amount: &str| {
+            app.post("/v1/tx/withdraw")
+                .token(&alice.token)
+                .json(json!({ "amount": amount, "destination_reference": "DE89370400440532013000" }))
+                .send()
+        };
+
+        let response = withdraw("30").await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(decimal(&response.body["balance"]), Decimal::from(20));
+        let id: Uuid = response.body["transaction_id"].as_str().unwrap().parse().unwrap();
+        let (kind, reference): (String, String) =
+            sqlx::query_as("SELECT transaction_type::TEXT, reference_id FROM transactions WHERE id = $1")
+                .bind(id)
+                .fetch_one(&app.pool)
+                .await
+                .unwrap();
+        assert_eq!((kind.as_str(), reference.as_str()), ("withdrawal", "DE89370400440532013000"));
+        assert_eq!(app.balance(&alice).await, Decimal::from(20));
+
+        // more than is left is refused and nothing moves
+        let response = withdraw("20.01").await;
+        assert_eq!(response.status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(response.error_code(), "insufficient_funds");
+        assert_eq!(app.balance(&alice).await, Decimal::from(20));
+
+        // the whole rest can go
+        let response = withdraw("20").await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(decimal(&response.body["balance"]), Decimal::ZERO);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn transfer_answers_the_created_transfer(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let response = app
+            .post("/v1/tx/transfer")
+            .token(&alice.token)
+            .json(json!({ "sender_id": alice.id, "receiver_id": bob.id, "amount": "12.5", "description": "lunch" }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        let receipt: TransferReceipt = serde_json::from_value(response.body.clone()).unwrap();
+        assert_eq!(receipt.sender_id, alice.id);
+        assert_eq!(receipt.receiver_id, bob.id);
+        assert_eq!(receipt.amount, Decimal::new(125, 1));
+        assert_eq!(receipt.currency.as_str(), "USD");
+        assert_eq!(receipt.description.as_deref(), Some("lunch"));
+        assert_eq!(receipt.status, TransactionStatus::Completed);
+        assert!(receipt.transfer_no.is_some());
+        assert!(receipt.created_at.is_some());
+
+        // the same row a lookup finds
+        let details = app.get(&format!("/v1/tx/get_tx/{}", receipt.id)).token(&alice.token).send().await;
+        assert_eq!(details.status, StatusCode::OK, "{}", details.body);
+        for field in ["transfer_no", "sender_wallet_id", "receiver_wallet_id", "amount", "created_at"] {
+            assert_eq!(details.body[field], response.body[field], "{field}");
+        }
+    }
+
+    async fn external_transfer(app: &TestApp, sender: &TestUser, receiver_id: Uuid, amount: &str) -> TestResponse {
+        app.post("/v1/tx/transfer")
+            .token(&sender.token)
+            .json(json!({
+                "sender_id": sender.id,
+                "receiver_id": receiver_id,
+                "amount": amount,
+                "transfer_kind": "external",
+            }))
+            .send()
+            .await
+    }
+
+    async fn settle_now(app: &TestApp, id: &str) -> usize {
+        sqlx::query("UPDATE transfers SET settles_at = CURRENT_TIMESTAMP - INTERVAL '1 second' WHERE id = $1::uuid")
+            .bind(id)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+        settle_external_transfers(&app.pool, &tx_config()).await.unwrap()
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn external_transfer_holds_funds_until_it_settles(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let transfer = external_transfer(&app, &alice, bob.id, "20").await;
+        assert_eq!(transfer.status, StatusCode::ACCEPTED, "{}", transfer.body);
+        assert_eq!(transfer.body["status"], "pending");
+        assert_eq!(transfer.body["transfer_kind"], "external");
+        assert!(transfer.body.get("transfer_no").is_none());
+        let settles_at = DateTime::parse_from_rfc3339(transfer.body["settles_at"].as_str().unwrap()).unwrap();
+        assert!(settles_at > Utc::now() + Duration::hours(23));
+
+        // held: gone from the sender, not yet with the recipient, and not picked up before its time
+        assert_eq!(app.balance(&alice).await, Decimal::from(30));
+        assert_eq!(app.balance(&bob).await, Decimal::ZERO);
+        assert_eq!(settle_external_transfers(&app.pool, &tx_config()).await.unwrap(), 0);
+        assert_eq!(execute_due_transfers(&app.pool, &tx_config()).await.unwrap(), 0);
+
+        let id = transfer.body["id"].as_str().unwrap();
+        assert_eq!(settle_now(&app, id).await, 1);
+        assert_eq!(app.balance(&alice).await, Decimal::from(30));
+        assert_eq!(app.balance(&bob).await, Decimal::from(20));
+
+        let details = app.get(&format!("/v1/tx/get_tx/{id}")).token(&bob.token).send().await;
+        assert_eq!(details.body["status"], "completed");
+        assert!(details.body["transfer_no"].is_i64());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn external_transfer_returns_funds_when_it_cannot_settle(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let transfer = external_transfer(&app, &alice, bob.id, "20").await;
+        assert_eq!(transfer.status, StatusCode::ACCEPTED, "{}", transfer.body);
+        sqlx::query("UPDATE users SET status = 'frozen' WHERE id = $1")
+            .bind(bob.id)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+
+        let id = transfer.body["id"].as_str().unwrap();
+        assert_eq!(settle_now(&app, id).await, 1);
+        assert_eq!(app.balance(&alice).await, Decimal::from(50));
+
+        let details = app.get(&format!("/v1/tx/get_tx/{id}")).token(&alice.token).send().await;
+        assert_eq!(details.body["status"], "failed");
+        assert!(details.body.get("transfer_no").is_none());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn scheduled_transfer_runs_once_due(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let execute_at = Utc::now() + Duration::hours(1);
+        let transfer = app
+            .post("/v1/tx/transfer")
+            .token(&alice.token)
+            .json(json!({ "sender_id": alice.id, "receiver_id": bob.id, "amount": "20", "execute_at": execute_at }))
+            .send()
+            .await;
+        assert_eq!(transfer.status, StatusCode::ACCEPTED, "{}", transfer.body);
+        assert_eq!(transfer.body["status"], "pending");
+        let id: Uuid = transfer.body["id"].as_str().unwrap().parse().unwrap();
+
+        // visible while pending, without a number, and no money moved yet
+        let details = app.get(&format!("/v1/tx/get_tx/{id}")).token(&alice.token).send().await;
+        assert_eq!(details.status, StatusCode::OK, "{}", details.body);
+        assert_eq!(details.body["status"], "pending");
+        assert!(details.body.get("transfer_no").is_none());
+        DateTime::parse_from_rfc3339(details.body["scheduled_for"].as_str().unwrap()).unwrap();
+        assert_eq!(execute_due_transfers(&app.pool, &tx_config()).await.unwrap(), 0);
+        assert_eq!(app.balance(&alice).await, Decimal::from(50));
+
+        sqlx::query("UPDATE transfers SET scheduled_for = CURRENT_TIMESTAMP - interval '1 second' WHERE id = $1")
+            .bind(id)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+        assert_eq!(execute_due_transfers(&app.pool, &tx_config()).await.unwrap(), 1);
+
+        let details = app.get(&format!("/v1/tx/get_tx/{id}")).token(&bob.token).send().await;
+        assert_eq!(details.body["status"], "completed");
+        assert!(details.body["transfer_no"].is_i64());
+        assert_eq!(app.balance(&alice).await, Decimal::from(30));
+        assert_eq!(app.balance(&bob).await, Decimal::from(20));
+
+        // a second run finds nothing left to do
+        assert_eq!(execute_due_transfers(&app.pool, &tx_config()).await.unwrap(), 0);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn history_tells_credits_from_debits(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+        app.deposit(&bob, "50").await;
+        let outgoing = app.transfer(&alice, bob.id, "20").await;
+        let incoming = app.transfer(&bob, alice.id, "5").await;
+
+        let listed = app.get("/v1/tx/list_txs").token(&alice.token).send().await;
+        assert_eq!(listed.status, StatusCode::OK, "{}", listed.body);
+        let items = listed.body["items"].as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0]["id"], incoming.body["id"]);
+        assert_eq!(items[0]["direction"], "credit");
+        assert_eq!(items[0]["counterparty_id"], bob.id.to_string());
+        assert_eq!(items[1]["id"], outgoing.body["id"]);
+        assert_eq!(items[1]["direction"], "debit");
+        assert_eq!(items[1]["counterparty_id"], bob.id.to_string());
+        DateTime::parse_from_rfc3339(items[1]["created_at"].as_str().unwrap()).unwrap();
+
+        // and the query endpoint narrows to one side
+        for (direction, expected) in [("sent", &outgoing), ("received", &incoming)] {
+            let response = app
+                .post("/v1/tx/query")
+                .token(&alice.token)
+                .json(json!({ "direction": direction }))
+                .send()
+                .await;
+            assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+            let ids = response.body["items"].as_array().unwrap().iter().map(|item| item["id"].clone()).collect::<Vec<_>>();
+            assert_eq!(ids, [expected.body["id"].clone()], "{direction}");
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn listing_is_json_unless_an_event_stream_is_asked_for(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+        for amount in ["1", "2", "3"] {
+            app.transfer(&alice, bob.id, amount).await;
+        }
+
+        let json = app.get("/v1/tx/list_txs?limit=2").token(&alice.token).send().await;
+        assert_eq!(json.status, StatusCode::OK, "{}", json.body);
+        assert!(json.header("content-type").starts_with("application/json"));
+        let items = json.body["items"].as_array().unwrap().clone();
+        assert_eq!(items.len(), 2);
+        assert_eq!(json.body["next_cursor"], items[1]["id"]);
+
+        let sse = app
+            .get("/v1/tx/list_txs?limit=2")
+            .token(&alice.token)
+            .header("accept", "application/json;q=0.5, text/event-stream")
+            .send()
+            .await;
+        assert_eq!(sse.status, StatusCode::OK);
+        assert!(sse.header("content-type").starts_with("text/event-stream"));
+        let body = sse.body.as_str().unwrap();
+        let ids = body.lines().filter_map(|line| line.strip_prefix("id:")).map(str::trim).collect::<Vec<_>>();
+        let events = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|data| serde_json::from_str::<serde_json::Value>(data.trim()).unwrap())
+            .collect::<Vec<_>>();
+        // the same page either way, each event keyed by its transfer id
+        assert_eq!(events, items);
+        assert_eq!(ids, items.iter().map(|item| item["id"].as_str().unwrap()).collect::<Vec<_>>());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn listing_pages_through_every_transfer_once(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "100").await;
+        let mut ids = Vec::new();
+        for _ in 0..27 {
+            let transfer = app.transfer(&alice, bob.id, "1").await;
+            assert_eq!(transfer.status, StatusCode::OK, "{}", transfer.body);
+            ids.push(transfer.body["id"].clone());
+        }
+        ids.reverse();
+        let page_ids = |page: &TestResponse| page.body["items"].as_array().unwrap().iter().map(|item| item["id"].clone()).collect::<Vec<_>>();
+
+        // 25 by default
+        let first = app.get("/v1/tx/list_txs").token(&alice.token).send().await;
+        assert_eq!(first.status, StatusCode::OK, "{}", first.body);
+        assert_eq!(page_ids(&first), ids[..25]);
+        assert_eq!(first.body["next_cursor"], ids[24]);
+
+        // walking pages of 10 visits each transfer once, newest first
+        let mut listed = Vec::new();
+        let mut uri = "/v1/tx/list_txs?limit=10".to_string();
+        loop {
+            let page = app.get(&uri).token(&bob.token).send().await;
+            assert_eq!(page.status, StatusCode::OK, "{}", page.body);
+            listed.extend(page_ids(&page));
+            match page.body["next_cursor"].as_str() {
+                Some(cursor) => uri = format!("/v1/tx/list_txs?limit=10&before={cursor}"),
+                None => break,
+            }
+        }
+        assert_eq!(listed, ids);
+
+        // out of range limits are clamped rather than refused
+        let page = app.get("/v1/tx/list_txs?limit=0").token(&alice.token).send().await;
+        assert_eq!(page_ids(&page), ids[..1]);
+        let page = app.get("/v1/tx/list_txs?limit=500").token(&alice.token).send().await;
+        assert_eq!(page_ids(&page), ids);
+        assert!(page.body["next_cursor"].is_null());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn transfers_completing_between_pages_are_not_listed_late(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "100").await;
+
+        // made first but only completes between the two page fetches
+        let scheduled = app
+            .post("/v1/tx/transfer")
+            .token(&alice.token)
+            .json(json!({ "sender_id": alice.id, "receiver_id": bob.id, "amount": "1", "execute_at": Utc::now() + Duration::hours(1) }))
+            .send()
+            .await;
+        assert_eq!(scheduled.status, StatusCode::ACCEPTED, "{}", scheduled.body);
+        let mut ids = Vec::new();
+        for amount in ["2", "3", "4"] {
+            let transfer = app.transfer(&alice, bob.id, amount).await;
+            assert_eq!(transfer.status, StatusCode::OK, "{}", transfer.body);
+            ids.push(transfer.body["id"].clone());
+        }
+
+        let first = app.get("/v1/tx/list_txs?limit=2").token(&alice.token).send().await;
+        assert_eq!(first.status, StatusCode::OK, "{}", first.body);
+        let listed = first.body["items"].as_array().unwrap().iter().map(|item| item["id"].clone()).collect::<Vec<_>>();
+        assert_eq!(listed, [ids[2].clone(), ids[1].clone()]);
+
+        sqlx::query("UPDATE transfers SET scheduled_for = CURRENT_TIMESTAMP - interval '1 second' WHERE id = $1")
+            .bind(scheduled.body["id"].as_str().unwrap().parse::<Uuid>().unwrap())
+            .execute(&app.pool)
+            .await
+            .unwrap();
+        assert_eq!(execute_due_transfers(&app.pool, &tx_config()).await.unwrap(), 1);
+
+        // the next page carries on where the first stopped, the late transfer belongs before the cursor
+        let cursor = first.body["next_cursor"].as_str().unwrap();
+        let second = app.get(&format!("/v1/tx/list_txs?limit=2&before={cursor}")).token(&alice.token).send().await;
+        assert_eq!(second.status, StatusCode::OK, "{}", second.body);
+        let listed = second.body["items"].as_array().unwrap().iter().map(|item| item["id"].clone()).collect::<Vec<_>>();
+        assert_eq!(listed, [ids[0].clone()]);
+        assert!(second.body["next_cursor"].is_null());
+
+        // and heads the list when fetched from the start
+        let fresh = app.get("/v1/tx/list_txs?limit=1").token(&alice.token).send().await;
+        assert_eq!(fresh.body["items"][0]["id"], scheduled.body["id"]);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn scheduled_transfer_fails_without_funds(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+
+        let transfer = app
+            .post("/v1/tx/transfer")
+            .token(&alice.token)
+            .json(json!({ "sender_id": alice.id, "receiver_id": bob.id, "amount": "20", "execute_at": Utc::now() + Duration::hours(1) }))
+            .send()
+            .await;
+        assert_eq!(transfer.status, StatusCode::ACCEPTED, "{}", transfer.body);
+        let id: Uuid = transfer.body["id"].as_str().unwrap().parse().unwrap();
+
+        sqlx::query("UPDATE transfers SET scheduled_for = CURRENT_TIMESTAMP - interval '1 second' WHERE id = $1")
+            .bind(id)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+        assert_eq!(execute_due_transfers(&app.pool, &tx_config()).await.unwrap(), 0);
+
+        let details = app.get(&format!("/v1/tx/get_tx/{id}")).token(&alice.token).send().await;
+        assert_eq!(details.body["status"], "failed");
+        assert_eq!(app.balance(&bob).await, Decimal::ZERO);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn daily_limit_reports_rate_limit_headers(pool: PgPool) {
+        let config = TxConfig {
+            daily_transfer_limit: Some(Decimal::from(100)),
+            ..tx_config()
+        };
+        let app = TestApp::with_config(pool, auth_config(), config);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "500").await;
+
+        assert_eq!(app.transfer(&alice, bob.id, "70").await.status, StatusCode::OK);
+        let response = app.transfer(&alice, bob.id, "40").await;
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.error_code(), "daily_limit_exceeded");
+        assert_eq!(response.header("x-ratelimit-limit"), "100");
+        assert_eq!(response.header("x-ratelimit-remaining"), "30");
+        let retry_after: u64 = response.header("retry-after").parse().unwrap();
+        assert!((1..=86400).contains(&retry_after));
+
+        // what remains can still be sent
+        assert_eq!(app.transfer(&alice, bob.id, "30").await.status, StatusCode::OK);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn cached_balances_equal_the_ledger_sums(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "100").await;
+        app.deposit(&bob, "12.34").await;
+        app.transfer(&alice, bob.id, "30").await;
+        let transfer = app.transfer(&bob, alice.id, "2.34").await;
+        let id = transfer.body["id"].as_str().unwrap();
+        app.post(&format!("/v1/tx/{id}/refund")).token(&alice.token).send().await;
+        app.post("/v1/tx/withdraw")
+            .token(&alice.token)
+            .json(json!({ "amount": "15", "destination_reference": "acct-1" }))
+            .send()
+            .await;
+        // refused, leaves no entries behind
+        app.transfer(&alice, bob.id, "1000").await;
+
+        for (user, expected) in [(&alice, Decimal::from(55)), (&bob, Decimal::new(4234, 2))] {
+            let (cached, summed): (Decimal, Decimal) = sqlx::query_as(
+                "SELECT balance, (SELECT COALESCE(SUM(delta), 0) FROM ledger_entries WHERE user_id = users.id) FROM users WHERE id = $1",
+            )
+            .bind(user.id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+            assert_eq!(cached, expected);
+            assert_eq!(summed, expected);
+            let breakdown = ledger::LedgerRepository::new(app.pool.clone()).breakdown_of(user.id).await.unwrap();
+            assert_eq!(breakdown.available, expected);
+        }
+
+        // every transfer books a balanced pair of entries
+        let unbalanced: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM (SELECT tx_id FROM ledger_entries WHERE tx_id IN (SELECT id FROM transfers) GROUP BY tx_id HAVING SUM(delta) <> 0 OR COUNT(*) <> 2) t",
+        )
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+        assert_eq!(unbalanced, 0);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn refund_within_reversal_window(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+        let transfer = app.transfer(&alice, bob.id, "20").await;
+        let id = transfer.body["id"].as_str().unwrap();
+
+        // a day old, well within the 30 days
+        sqlx::query("UPDATE transfers SET created_at = CURRENT_TIMESTAMP - interval '1 day' WHERE id = $1::uuid")
+            .bind(id)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+
+        let refund = app.post(&format!("/v1/tx/{id}/refund")).token(&bob.token).send().await;
+        assert_eq!(refund.status, StatusCode::OK, "{}", refund.body);
+        assert_eq!(refund.body["reversed_tx_id"], id);
+        assert_eq!(app.balance(&alice).await, Decimal::from(50));
+        assert_eq!(app.balance(&bob).await, Decimal::ZERO);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn refund_after_reversal_window_is_rejected(pool: PgPool) {
+        let config = TxConfig {
+            reversal_window: Some(std::time::Duration::from_secs(3600)),
+            ..tx_config()
+        };
+        let app = TestApp::with_config(pool, auth_config(), config);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+        let transfer = app.transfer(&alice, bob.id, "20").await;
+        let id = transfer.body["id"].as_str().unwrap();
+
+        sqlx::query("UPDATE transfers SET created_at = CURRENT_TIMESTAMP - interval '2 hours' WHERE id = $1::uuid")
+            .bind(id)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+
+        let refund = app.post(&format!("/v1/tx/{id}/refund")).token(&bob.token).send().await;
+        assert_eq!(refund.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(refund.error_code(), "reversal_window_expired");
+        assert_eq!(app.balance(&bob).await, Decimal::from(20));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn partial_refunds_add_up_to_the_transfer(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+        let transfer = app.transfer(&alice, bob.id, "20").await;
+        let id = transfer.body["id"].as_str().unwrap();
+
+        for amount in ["5", "10.5"] {
+            let refund = app
+                .post(&format!("/v1/tx/{id}/refund"))
+                .token(&bob.token)
+                .json(json!({ "amount": amount }))
+                .send()
+                .await;
+            assert_eq!(refund.status, StatusCode::OK, "{}", refund.body);
+            assert_eq!(refund.body["reversed_tx_id"], id);
+        }
+        let details = app.get(&format!("/v1/tx/get_tx/{id}")).token(&alice.token).send().await;
+        assert_eq!(decimal(&details.body["refunded_amount"]), "15.5".parse::<Decimal>().unwrap());
+
+        // without an amount the rest is refunded
+        let refund = app.post(&format!("/v1/tx/{id}/refund")).token(&bob.token).send().await;
+        assert_eq!(refund.status, StatusCode::OK, "{}", refund.body);
+        assert_eq!(decimal(&refund.body["amount"]), "4.5".parse::<Decimal>().unwrap());
+        assert_eq!(app.balance(&alice).await, Decimal::from(50));
+        assert_eq!(app.balance(&bob).await, Decimal::ZERO);
+
+        let refund = app.post(&format!("/v1/tx/{id}/refund")).token(&bob.token).send().await;
+        assert_eq!(refund.status, StatusCode::CONFLICT);
+        assert_eq!(refund.error_code(), "already_refunded");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn refunds_beyond_the_transfer_amount_are_rejected(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+        app.deposit(&bob, "50").await;
+        let transfer = app.transfer(&alice, bob.id, "20").await;
+        let id = transfer.body["id"].as_str().unwrap();
+
+        let refund = |amount: &'static str| {
+            app.post(&format!("/v1/tx/{id}/refund"))
+                .token(&bob.token)
+                .json(json!({ "amount": amount }))
+                .send()
+        };
+        let response = refund("25").await;
+        assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.error_code(), "refund_exceeds_amount");
+
+        assert_eq!(refund("15").await.status, StatusCode::OK);
+        let response = refund("10").await;
+        assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.error_code(), "refund_exceeds_amount");
+        let response = refund("0").await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.error_code(), "invalid_amount");
+
+        // bob holds more than enough, only the 15 went back
+        assert_eq!(app.balance(&alice).await, Decimal::from(45));
+        assert_eq!(app.balance(&bob).await, Decimal::from(55));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn transfer_beyond_the_balance_is_rejected(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let response = app.transfer(&alice, bob.id, "50.01").await;
+        assert_eq!(response.status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(response.error_code(), "insufficient_funds");
+        assert_eq!(app.balance(&alice).await, Decimal::from(50));
+        assert_eq!(app.balance(&bob).await, Decimal::ZERO);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn concurrent_transfers_cannot_overdraw(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "100").await;
+
+        let responses = futures::future::join_all((0..10).map(|_| app.transfer(&alice, bob.id, "20"))).await;
+        let succeeded = responses.iter().filter(|response| response.status == StatusCode::OK).count();
+        assert_eq!(succeeded, 5);
+        assert!(responses
+            .iter()
+            .filter(|response| response.status != StatusCode::OK)
+            .all(|response| response.error_code() == "insufficient_funds"));
+        assert_eq!(app.balance(&alice).await, Decimal::ZERO);
+        assert_eq!(app.balance(&bob).await, Decimal::from(100));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn transfer_to_an_unknown_recipient_moves_nothing(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        app.deposit(&alice, "50").await;
+
+        let response = app.transfer(&alice, Uuid::new_v4(), "20").await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert_eq!(response.error_code(), "recipient_not_found");
+        assert_eq!(app.balance(&alice).await, Decimal::from(50));
+    }
+
+    async fn transfer_count(app: &TestApp, user: &TestUser) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM transfers WHERE sender_id = $1")
+            .bind(user.id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn self_transfer_is_rejected_before_anything_is_recorded(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        app.deposit(&alice, "50").await;
+
+        let response = app.transfer(&alice, alice.id, "20").await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.error_code(), "self_transfer");
+        assert_eq!(transfer_count(&app, &alice).await, 0);
+        assert_eq!(app.balance(&alice).await, Decimal::from(50));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn transfer_amounts_are_held_to_the_configured_bounds(pool: PgPool) {
+        let config = TxConfig {
+            min_transfer_amount: Some(Decimal::from(5)),
+            max_transfer_amount: Some(Decimal::from(10)),
+            ..tx_config()
+        };
+        let app = TestApp::with_config(pool, auth_config(), config);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "100").await;
+
+        for (amount, expected) in [
+            ("4.99", Some("amount_below_minimum")),
+            ("5", None),
+            ("10", None),
+            ("10.01", Some("amount_above_maximum")),
+        ] {
+            let response = app.transfer(&alice, bob.id, amount).await;
+            match expected {
+                Some(code) => {
+                    assert_eq!(response.status, StatusCode::BAD_REQUEST, "{amount}");
+                    assert_eq!(response.error_code(), code, "{amount}");
+                }
+                None => assert_eq!(response.status, StatusCode::OK, "{amount}: {}", response.body),
+            }
+        }
+        // only the two within bounds moved money
+        assert_eq!(app.balance(&bob).await, Decimal::from(15));
+        assert_eq!(transfer_count(&app, &alice).await, 2);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn zero_and_negative_transfers_are_rejected(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+        app.deposit(&bob, "50").await;
+
+        for amount in ["0", "-20"] {
+            let response = app.transfer(&alice, bob.id, amount).await;
+            assert_eq!(response.status, StatusCode::BAD_REQUEST, "{amount}");
+            assert_eq!(response.error_code(), "invalid_amount");
+        }
+        assert_eq!(transfer_count(&app, &alice).await, 0);
+        assert_eq!(app.balance(&alice).await, Decimal::from(50));
+        assert_eq!(app.balance(&bob).await, Decimal::from(50));
+    }
+
+    async fn keyed_transfer(app: &TestApp, sender: &TestUser, receiver_id: Uuid, key: &str) -> TestResponse {
+        app.post("/v1/tx/transfer")
+            .token(&sender.token)
+            .header("Idempotency-Key", key)
+            .json(json!({ "sender_id": sender.id, "receiver_id": receiver_id, "amount": "10" }))
+            .send()
+            .await
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn replayed_idempotency_key_answers_the_original_transfer(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let first = keyed_transfer(&app, &alice, bob.id, "order-1").await;
+        assert_eq!(first.status, StatusCode::OK, "{}", first.body);
+        let replay = keyed_transfer(&app, &alice, bob.id, "order-1").await;
+        assert_eq!(replay.status, StatusCode::OK, "{}", replay.body);
+        assert_eq!(replay.body["id"], first.body["id"]);
+        assert_eq!(replay.body["transfer_no"], first.body["transfer_no"]);
+        assert_eq!(app.balance(&alice).await, Decimal::from(40));
+        assert_eq!(transfer_count(&app, &alice).await, 1);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn different_idempotency_keys_make_separate_transfers(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+        app.deposit(&bob, "50").await;
+
+        let first = keyed_transfer(&app, &alice, bob.id, "order-1").await;
+        let second = keyed_transfer(&app, &alice, bob.id, "order-2").await;
+        assert_eq!(second.status, StatusCode::OK, "{}", second.body);
+        assert_ne!(second.body["id"], first.body["id"]);
+        assert_eq!(app.balance(&alice).await, Decimal::from(30));
+
+        // keys are scoped per user, bob's "order-1" is his own
+        let bobs = keyed_transfer(&app, &bob, alice.id, "order-1").await;
+        assert_eq!(bobs.status, StatusCode::OK, "{}", bobs.body);
+        assert_ne!(bobs.body["id"], first.body["id"]);
+        assert_eq!(app.balance(&alice).await, Decimal::from(40));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn only_the_recipient_refunds_and_only_once(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let carol = app.register("carol").await;
+        app.deposit(&alice, "50").await;
+        let transfer = app.transfer(&alice, bob.id, "20").await;
+        let id = transfer.body["id"].as_str().unwrap();
+        let uri = format!("/v1/tx/{id}/refund");
+
+        // the sender can't pull the money back
+        let response = app.post(&uri).token(&alice.token).send().await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(response.error_code(), "refund_not_allowed");
+
+        // bob spent it meanwhile
+        app.transfer(&bob, carol.id, "15").await;
+        let response = app.post(&uri).token(&bob.token).send().await;
+        assert_eq!(response.status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(response.error_code(), "insufficient_funds");
+        app.transfer(&carol, bob.id, "15").await;
+
+        let refund = app.post(&uri).token(&bob.token).send().await;
+        assert_eq!(refund.status, StatusCode::OK, "{}", refund.body);
+        let response = app.post(&uri).token(&bob.token).send().await;
+        assert_eq!(response.status, StatusCode::CONFLICT);
+        assert_eq!(response.error_code(), "already_refunded");
+        assert_eq!(app.balance(&alice).await, Decimal::from(50));
+        assert_eq!(app.balance(&bob).await, Decimal::ZERO);
+
+        // nor can the refund itself be refunded
+        let refund_id = refund.body["id"].as_str().unwrap();
+        let response = app.post(&format!("/v1/tx/{refund_id}/refund")).token(&alice.token).send().await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.error_code(), "not_refundable");
+    }
+
+    async fn open_wallet(app: &TestApp, user: &TestUser, currency: &str) -> String {
+        let response = app
+            .post("/v1/users/wallets")
+            .token(&user.token)
+            .json(json!({ "currency": currency }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::CREATED, "{}", response.body);
+        response.body["id"].as_str().unwrap().to_string()
+    }
+
+    async fn wallet_balance(app: &TestApp, user: &TestUser, currency: &str) -> Decimal {
+        let response = app.get("/v1/users/wallets").token(&user.token).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        let wallets = response.body.as_array().unwrap();
+        let wallet = wallets.iter().find(|wallet| wallet["currency"] == currency).unwrap();
+        decimal(&wallet["balance"])
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn transfer_in_another_currency_moves_between_those_wallets(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let alice_eur = open_wallet(&app, &alice, "EUR").await;
+        open_wallet(&app, &bob, "EUR").await;
+        app.deposit(&alice, "10").await;
+        let deposit = app
+            .post("/v1/users/deposit")
+            .token(&alice.token)
+            .json(json!({ "email": alice.email, "amount": "50", "wallet_id": alice_eur }))
+            .send()
+            .await;
+        assert_eq!(deposit.status, StatusCode::OK, "{}", deposit.body);
+
+        let transfer = app
+            .post("/v1/tx/transfer")
+            .token(&alice.token)
+            .json(json!({ "sender_id": alice.id, "receiver_id": bob.id, "amount": "20", "currency": "EUR" }))
+            .send()
+            .await;
+        assert_eq!(transfer.status, StatusCode::OK, "{}", transfer.body);
+        assert_eq!(transfer.body["currency"], "EUR");
+        assert_eq!(transfer.body["sender_wallet_id"], alice_eur.as_str());
+
+        assert_eq!(wallet_balance(&app, &alice, "EUR").await, Decimal::from(30));
+        assert_eq!(wallet_balance(&app, &bob, "EUR").await, Decimal::from(20));
+        // the primary wallets are untouched
+        assert_eq!(app.balance(&alice).await, Decimal::from(10));
+        assert_eq!(app.balance(&bob).await, Decimal::ZERO);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn daily_limit_counts_each_currency_separately(pool: PgPool) {
+        let config = TxConfig {
+            daily_transfer_limit: Some(Decimal::from(1000)),
+            ..tx_config()
+        };
+        let app = TestApp::with_config(pool, auth_config(), config);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let alice_jpy = open_wallet(&app, &alice, "JPY").await;
+        open_wallet(&app, &bob, "JPY").await;
+        app.deposit(&alice, "600").await;
+        deposit_into(&app, &alice, &alice_jpy, "2000").await;
+
+        assert_eq!(transfer_in(&app, &alice, &bob, "800", "JPY").await.status, StatusCode::OK);
+        // 800 yen and 500 dollars don't add up to anything, each stays within its own limit
+        assert_eq!(app.transfer(&alice, bob.id, "500").await.status, StatusCode::OK);
+
+        let response = transfer_in(&app, &alice, &bob, "300", "JPY").await;
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.error_code(), "daily_limit_exceeded");
+        assert_eq!(response.header("x-ratelimit-remaining"), "200");
+        let response = app.transfer(&alice, bob.id, "100").await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+
+        assert_eq!(wallet_balance(&app, &alice, "JPY").await, Decimal::from(1200));
+        assert_eq!(app.balance(&alice).await, Decimal::ZERO);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn transfer_numbers_have_no_gaps_left_by_failed_transfers(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "100").await;
+
+        assert_eq!(app.transfer(&alice, bob.id, "10").await.status, StatusCode::OK);
+        assert_eq!(app.transfer(&alice, bob.id, "1000").await.status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(app.transfer(&alice, bob.id, "10").await.status, StatusCode::OK);
+        // racing transfers, only some of which the balance covers
+        let responses = futures::future::join_all((0..10).map(|_| app.transfer(&alice, bob.id, "15"))).await;
+        let completed = responses.iter().filter(|response| response.status == StatusCode::OK).count();
+        assert_eq!(completed, 5);
+
+        let numbers: Vec<Option<i64>> =
+            sqlx::query_scalar("SELECT transfer_no FROM transfers WHERE status = 'completed' ORDER BY transfer_no")
+                .fetch_all(&app.pool)
+                .await
+                .unwrap();
+        let expected: Vec<Option<i64>> = (1..=numbers.len() as i64).map(Some).collect();
+        assert_eq!(numbers, expected);
+        let counter: i64 = sqlx::query_scalar("SELECT value FROM transfer_counter").fetch_one(&app.pool).await.unwrap();
+        assert_eq!(counter, numbers.len() as i64);
+        let failed_numbered: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM transfers WHERE status <> 'completed' AND transfer_no IS NOT NULL")
+                .fetch_one(&app.pool)
+                .await
+                .unwrap();
+        assert_eq!(failed_numbered, 0);
+    }
+
+    async fn failure_reasons(app: &TestApp, user: &TestUser) -> Vec<String> {
+        sqlx::query_scalar(
+            "SELECT changes->>'reason' FROM audit_logs WHERE action = 'transfer_failed' AND user_id = $1 ORDER BY created_at, id",
+        )
+        .bind(user.id)
+        .fetch_all(&app.pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn each_rejected_transfer_is_audited_once(pool: PgPool) {
+        let config = TxConfig {
+            daily_transfer_limit: Some(Decimal::from(50)),
+            ..tx_config()
+        };
+        let app = TestApp::with_config(pool, auth_config(), config);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "40").await;
+
+        let response = app.transfer(&alice, bob.id, "45").await;
+        assert_eq!(response.error_code(), "insufficient_funds", "{}", response.body);
+        assert_eq!(failure_reasons(&app, &alice).await, ["insufficient_funds"]);
+
+        app.deposit(&alice, "100").await;
+        let response = app.transfer(&alice, bob.id, "45").await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        let response = app.transfer(&alice, bob.id, "10").await;
+        assert_eq!(response.error_code(), "daily_limit_exceeded", "{}", response.body);
+        assert_eq!(failure_reasons(&app, &alice).await, ["insufficient_funds", "daily_limit_exceeded"]);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn summary_totals_completed_transfers_since_a_point(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+
+        // nothing sent or received yet
+        let summary = app.get("/v1/tx/summary").token(&alice.token).send().await;
+        assert_eq!(summary.status, StatusCode::OK, "{}", summary.body);
+        assert_eq!(summary.body, json!([]));
+
+        app.deposit(&alice, "50").await;
+        app.deposit(&bob, "10").await;
+        let old = app.transfer(&alice, bob.id, "7.25").await;
+        sqlx::query("UPDATE transfers SET created_at = CURRENT_TIMESTAMP - interval '2 days' WHERE id = $1::UUID")
+            .bind(old.body["id"].as_str().unwrap())
+            .execute(&app.pool)
+            .await
+            .unwrap();
+        app.transfer(&alice, bob.id, "10.10").await;
+        app.transfer(&bob, alice.id, "2.20").await;
+        // refused, so not counted
+        assert_eq!(app.transfer(&alice, bob.id, "1000").await.status, StatusCode::PAYMENT_REQUIRED);
+
+        let summary = app.get("/v1/tx/summary").token(&alice.token).send().await;
+        let totals = &summary.body[0];
+        assert!(totals["total_sent"].is_string(), "{totals}");
+        assert_eq!(decimal(&totals["total_sent"]), Decimal::new(1735, 2));
+        assert_eq!(decimal(&totals["total_received"]), Decimal::new(220, 2));
+        assert_eq!(decimal(&totals["net"]), Decimal::new(-1515, 2));
+        assert_eq!(totals["count"], 3);
+
+        let since = (Utc::now() - Duration::days(1)).to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let summary = app.get(&format!("/v1/tx/summary?since={since}")).token(&alice.token).send().await;
+        assert_eq!(summary.status, StatusCode::OK, "{}", summary.body);
+        let totals = &summary.body[0];
+        assert_eq!(decimal(&totals["total_sent"]), Decimal::new(1010, 2));
+        assert_eq!(decimal(&totals["net"]), Decimal::new(-790, 2));
+        assert_eq!(totals["count"], 2);
+
+        let summary = app.get("/v1/tx/summary?since=yesterday").token(&alice.token).send().await;
+        assert_eq!(summary.status, StatusCode::BAD_REQUEST);
+        assert_eq!(summary.error_code(), "invalid_query");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn summary_and_net_position_are_per_currency(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let alice_jpy = open_wallet(&app, &alice, "JPY").await;
+        open_wallet(&app, &bob, "JPY").await;
+        app.deposit(&alice, "50").await;
+        app.deposit(&bob, "10").await;
+        deposit_into(&app, &alice, &alice_jpy, "5000").await;
+
+        app.transfer(&alice, bob.id, "20").await;
+        app.transfer(&bob, alice.id, "5").await;
+        let transfer = transfer_in(&app, &alice, &bob, "1000", "JPY").await;
+        assert_eq!(transfer.status, StatusCode::OK, "{}", transfer.body);
+
+        let summary = app.get("/v1/tx/summary").token(&alice.token).send().await;
+        assert_eq!(summary.status, StatusCode::OK, "{}", summary.body);
+        let totals = summary.body.as_array().unwrap();
+        assert_eq!(totals.len(), 2);
+        assert_eq!(totals[0]["currency"], "JPY");
+        assert_eq!(decimal(&totals[0]["total_sent"]), Decimal::from(1000));
+        assert_eq!(decimal(&totals[0]["net"]), Decimal::from(-1000));
+        assert_eq!(totals[0]["count"], 1);
+        assert_eq!(totals[1]["currency"], "USD");
+        assert_eq!(decimal(&totals[1]["total_sent"]), Decimal::from(20));
+        assert_eq!(decimal(&totals[1]["total_received"]), Decimal::from(5));
+        assert_eq!(decimal(&totals[1]["net"]), Decimal::from(-15));
+        assert_eq!(totals[1]["count"], 2);
+
+        let position = app.get(&format!("/v1/tx/net/{}", alice.id)).token(&bob.token).send().await;
+        assert_eq!(position.status, StatusCode::OK, "{}", position.body);
+        let positions = position.body.as_array().unwrap();
+        assert_eq!(positions.len(), 2);
+        assert_eq!(positions[0]["counterparty_id"], alice.id.to_string());
+        assert_eq!(positions[0]["currency"], "JPY");
+        assert_eq!(decimal(&positions[0]["net"]), Decimal::from(1000));
+        assert_eq!(positions[1]["currency"], "USD");
+        assert_eq!(decimal(&positions[1]["net"]), Decimal::from(15));
+
+        // nothing exchanged, nothing to report
+        let carol = app.register("carol").await;
+        let position = app.get(&format!("/v1/tx/net/{}", carol.id)).token(&alice.token).send().await;
+        assert_eq!(position.body, json!([]));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn net_position_counts_only_completed_transfers_between_the_pair(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let carol = app.register("carol").await;
+        app.deposit(&alice, "100").await;
+        app.deposit(&bob, "100").await;
+
+        app.transfer(&alice, bob.id, "30").await;
+        app.transfer(&bob, alice.id, "12.50").await;
+        app.transfer(&alice, carol.id, "40").await;
+        // rejected and still scheduled transfers don't count
+        let rejected = app.transfer(&alice, bob.id, "500").await;
+        assert_eq!(rejected.error_code(), "insufficient_funds");
+        let scheduled = app
+            .post("/v1/tx/transfer")
+            .token(&alice.token)
+            .json(json!({ "sender_id": alice.id, "receiver_id": bob.id, "amount": "5", "execute_at": Utc::now() + Duration::hours(1) }))
+            .send()
+            .await;
+        assert_eq!(scheduled.status, StatusCode::ACCEPTED, "{}", scheduled.body);
+
+        let net = |viewer: &TestUser, counterparty: &TestUser| {
+            app.get(&format!("/v1/tx/net/{}", counterparty.id)).token(&viewer.token).send()
+        };
+        let position = net(&alice, &bob).await;
+        assert_eq!(position.status, StatusCode::OK, "{}", position.body);
+        assert_eq!(position.body[0]["currency"], "USD");
+        assert_eq!(decimal(&position.body[0]["net"]), Decimal::new(-1750, 2));
+        // the same position seen from the other side
+        let position = net(&bob, &alice).await;
+        assert_eq!(decimal(&position.body[0]["net"]), Decimal::new(1750, 2));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn wallets_of_different_currencies_are_rejected(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let alice_eur = open_wallet(&app, &alice, "EUR").await;
+        app.post("/v1/users/deposit")
+            .token(&alice.token)
+            .json(json!({ "email": alice.email, "amount": "50", "wallet_id": alice_eur }))
+            .send()
+            .await;
+
+        // the recipient only holds dollars
+        let transfer = app
+            .post("/v1/tx/transfer")
+            .token(&alice.token)
+            .json(json!({ "sender_id": alice.id, "receiver_id": bob.id, "amount": "20", "sender_wallet_id": alice_eur }))
+            .send()
+            .await;
+        assert_eq!(transfer.status, StatusCode::BAD_REQUEST);
+        assert_eq!(transfer.error_code(), "currency_mismatch");
+
+        // a named recipient wallet in another currency
+        let bob_usd: String = sqlx::query_scalar("SELECT id::text FROM wallets WHERE user_id = $1")
+            .bind(bob.id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+        let transfer = app
+            .post("/v1/tx/transfer")
+            .token(&alice.token)
+            .json(json!({
+                "sender_id": alice.id,
+                "receiver_id": bob.id,
+                "amount": "20",
+                "sender_wallet_id": alice_eur,
+                "receiver_wallet_id": bob_usd,
+            }))
+            .send()
+            .await;
+        assert_eq!(transfer.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(transfer.error_code(), "wallet_currency_mismatch");
+        assert!(transfer.body["error"]["message"].as_str().unwrap().contains("EUR"));
+
+        assert_eq!(wallet_balance(&app, &alice, "EUR").await, Decimal::from(50));
+        assert_eq!(app.balance(&bob).await, Decimal::ZERO);
+        assert_eq!(transfer_count(&app, &alice).await, 0);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn wallet_of_another_user_is_rejected(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let mallory = app.register("mallory").await;
+        app.deposit(&bob, "50").await;
+        let bob_usd: String = sqlx::query_scalar("SELECT id::text FROM wallets WHERE user_id = $1")
+            .bind(bob.id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+
+        // spending from bob's wallet
+        let transfer = app
+            .post("/v1/tx/transfer")
+            .token(&mallory.token)
+            .json(json!({ "sender_id": mallory.id, "receiver_id": alice.id, "amount": "20", "sender_wallet_id": bob_usd }))
+            .send()
+            .await;
+        assert_eq!(transfer.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(transfer.error_code(), "wallet_not_owned");
+
+        // paying alice into bob's wallet
+        app.deposit(&mallory, "50").await;
+        let transfer = app
+            .post("/v1/tx/transfer")
+            .token(&mallory.token)
+            .json(json!({ "sender_id": mallory.id, "receiver_id": alice.id, "amount": "20", "receiver_wallet_id": bob_usd }))
+            .send()
+            .await;
+        assert_eq!(transfer.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(transfer.error_code(), "wallet_not_owned");
+
+        assert_eq!(app.balance(&bob).await, Decimal::from(50));
+        assert_eq!(app.balance(&mallory).await, Decimal::from(50));
+        assert_eq!(app.balance(&alice).await, Decimal::ZERO);
+        assert_eq!(transfer_count(&app, &mallory).await, 0);
+    }
+
+    async fn deposit_into(app: &TestApp, user: &TestUser, wallet_id: &str, amount: &str) -> TestResponse {
+        app.post("/v1/users/deposit")
+            .token(&user.token)
+            .json(json!({ "email": user.email, "amount": amount, "wallet_id": wallet_id }))
+            .send()
+            .await
+    }
+
+    async fn transfer_in(app: &TestApp, sender: &TestUser, receiver: &TestUser, amount: &str, currency: &str) -> TestResponse {
+        app.post("/v1/tx/transfer")
+            .token(&sender.token)
+            .json(json!({ "sender_id": sender.id, "receiver_id": receiver.id, "amount": amount, "currency": currency }))
+            .send()
+            .await
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn fractional_yen_amounts_are_rejected(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let alice_jpy = open_wallet(&app, &alice, "JPY").await;
+        open_wallet(&app, &bob, "JPY").await;
+
+        let deposit = deposit_into(&app, &alice, &alice_jpy, "1000.5").await;
+        assert_eq!(deposit.status, StatusCode::BAD_REQUEST);
+        assert_eq!(deposit.error_code(), "invalid_amount");
+        // trailing zeros are still whole yen
+        let deposit = deposit_into(&app, &alice, &alice_jpy, "1000.00").await;
+        assert_eq!(deposit.status, StatusCode::OK, "{}", deposit.body);
+
+        let transfer = transfer_in(&app, &alice, &bob, "0.5", "JPY").await;
+        assert_eq!(transfer.status, StatusCode::BAD_REQUEST);
+        assert_eq!(transfer.error_code(), "invalid_amount");
+        assert_eq!(wallet_balance(&app, &alice, "JPY").await, Decimal::from(1000));
+
+        // the same amount is fine in a currency with cents
+        app.deposit(&alice, "1").await;
+        let transfer = app.transfer(&alice, bob.id, "0.5").await;
+        assert_eq!(transfer.status, StatusCode::OK, "{}", transfer.body);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn partial_refunds_respect_the_minor_unit(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let alice_jpy = open_wallet(&app, &alice, "JPY").await;
+        open_wallet(&app, &bob, "JPY").await;
+        deposit_into(&app, &alice, &alice_jpy, "1000").await;
+        app.deposit(&alice, "50").await;
+        let yen = transfer_in(&app, &alice, &bob, "100", "JPY").await;
+        let dollars = app.transfer(&alice, bob.id, "20").await;
+
+        let refund = |id: String, amount: &'static str| {
+            app.post(&format!("/v1/tx/{id}/refund"))
+                .token(&bob.token)
+                .json(json!({ "amount": amount }))
+                .send()
+        };
+        let yen_id = yen.body["id"].as_str().unwrap().to_string();
+        let dollars_id = dollars.body["id"].as_str().unwrap().to_string();
+        for (id, amount) in [(&yen_id, "1.5"), (&dollars_id, "0.001")] {
+            let response = refund(id.clone(), amount).await;
+            assert_eq!(response.status, StatusCode::BAD_REQUEST, "{}", response.body);
+            assert_eq!(response.error_code(), "invalid_amount");
+        }
+        assert_eq!(refund(yen_id, "1").await.status, StatusCode::OK);
+        assert_eq!(refund(dollars_id, "0.01").await.status, StatusCode::OK);
+
+        assert_eq!(wallet_balance(&app, &alice, "JPY").await, Decimal::from(901));
+        assert_eq!(app.balance(&alice).await, "30.01".parse::<Decimal>().unwrap());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn three_decimal_dinar_amounts_are_accepted(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let alice_bhd = open_wallet(&app, &alice, "BHD").await;
+        open_wallet(&app, &bob, "BHD").await;
+
+        let deposit = deposit_into(&app, &alice, &alice_bhd, "10.125").await;
+        assert_eq!(deposit.status, StatusCode::OK, "{}", deposit.body);
+        let transfer = transfer_in(&app, &alice, &bob, "1.125", "BHD").await;
+        assert_eq!(transfer.status, StatusCode::OK, "{}", transfer.body);
+        assert_eq!(wallet_balance(&app, &alice, "BHD").await, Decimal::from(9));
+        assert_eq!(wallet_balance(&app, &bob, "BHD").await, "1.125".parse().unwrap());
+
+        let transfer = transfer_in(&app, &alice, &bob, "1.0005", "BHD").await;
+        assert_eq!(transfer.status, StatusCode::BAD_REQUEST);
+        assert_eq!(transfer.error_code(), "invalid_amount");
+    }
+
+    // Transfers 20 from alice to bob while another transaction debits alice 5, committing only once the transfer
+    // waits for her wallet, so by the time the transfer checks it the version it read is stale
+    async fn transfer_racing_a_debit(app: &TestApp, alice: &TestUser, bob: &TestUser) -> TestResponse {
+        let wallet_id = WalletRepository::new(app.pool.clone()).find_for(alice.id, None).await.unwrap().unwrap().id;
+        let mut concurrent = app.pool.begin().await.unwrap();
+        ledger::append_entry(&mut concurrent, alice.id, wallet_id, Decimal::from(-5), Uuid::new_v4()).await.unwrap();
+        sqlx::query!("UPDATE wallets SET version = version + 1 WHERE id = $1", wallet_id)
+            .execute(&mut *concurrent)
+            .await
+            .unwrap();
+
+        let commit_once_waiting = async {
+            loop {
+                let waiting = sqlx::query_scalar!(
+                    r#"SELECT COUNT(*) AS "count!" FROM pg_stat_activity WHERE datname = current_database() AND wait_event_type = 'Lock'"#
+                )
+                .fetch_one(&app.pool)
+                .await
+                .unwrap();
+                if waiting > 0 {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            concurrent.commit().await.unwrap();
+        };
+        let (response, _) = tokio::join!(app.transfer(alice, bob.id, "20"), commit_once_waiting);
+        response
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn transfer_reruns_after_a_concurrent_balance_change(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let transfer = transfer_racing_a_debit(&app, &alice, &bob).await;
+        assert_eq!(transfer.status, StatusCode::OK, "{}", transfer.body);
+        // the other debit and the transfer both went through, each exactly once
+        assert_eq!(app.balance(&alice).await, Decimal::from(25));
+        assert_eq!(app.balance(&bob).await, Decimal::from(20));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn transfer_conflicts_once_out_of_retries(pool: PgPool) {
+        let app = TestApp::with_config(pool, auth_config(), TxConfig { version_retries: 0, ..tx_config() });
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let transfer = transfer_racing_a_debit(&app, &alice, &bob).await;
+        assert_eq!(transfer.status, StatusCode::CONFLICT, "{}", transfer.body);
+        assert_eq!(transfer.error_code(), "concurrent_modification");
+        assert_eq!(app.balance(&alice).await, Decimal::from(45));
+        assert_eq!(app.balance(&bob).await, Decimal::ZERO);
+
+        let status = sqlx::query_scalar!(
+            r#"SELECT status::TEXT AS "status!" FROM transfers WHERE sender_id = $1"#,
+            alice.id
+        )
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+        assert_eq!(status, "failed");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn credits_between_the_read_and_the_debit_do_not_conflict(pool: PgPool) {
+        let app = TestApp::with_config(pool, auth_config(), TxConfig { version_retries: 0, ..tx_config() });
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+        app.deposit(&bob, "30").await;
+        let wallets = WalletRepository::new(app.pool.clone());
+        let wallet_id = wallets.find_for(alice.id, None).await.unwrap().unwrap().id;
+        let version = wallets.version(wallet_id).await.unwrap();
+
+        // alice keeps getting paid after the read
+        for _ in 0..3 {
+            assert_eq!(app.transfer(&bob, alice.id, "10").await.status, StatusCode::OK);
+        }
+        app.deposit(&alice, "5").await;
+        let mut tx = app.pool.begin().await.unwrap();
+        let debited = balance::debit_at_version(&mut tx, Account::Wallet(wallet_id), Decimal::from(20), Uuid::new_v4(), Some(version)).await;
+        assert_eq!(debited.unwrap(), Decimal::from(65));
+        tx.rollback().await.unwrap();
+
+        // a payment of hers does make the read stale
+        assert_eq!(app.transfer(&alice, bob.id, "1").await.status, StatusCode::OK);
+        let mut tx = app.pool.begin().await.unwrap();
+        let debited = balance::debit_at_version(&mut tx, Account::Wallet(wallet_id), Decimal::from(20), Uuid::new_v4(), Some(version)).await;
+        assert!(matches!(debited, Err(BalanceError::VersionConflict)));
+    }
 }
@@ -1,132 +1,424 @@
 use std::sync::Arc;
 
 use axum::{
-    extract::State,
+    extract::{rejection::PathRejection, Path, Query, State},
     http::{HeaderMap, StatusCode},
     response::IntoResponse,
-    routing::{get, post, put},
+    routing::{delete, get, post, put},
     Json, Router,
 };
+use chrono::{DateTime, Utc};
 use sqlx::{FromRow, PgPool};
 
 use serde::{Deserialize, Serialize};
-use sqlx::types::Decimal;
+use serde_email::Email;
+use sqlx::types::{time::OffsetDateTime, Decimal};
 use uuid::Uuid;
 
-use crate::db::user::User;
+use crate::audit::{self, AuditAction, AuditEntry};
+use crate::currency::Currency;
+use crate::db::{
+    api_key::{self, ApiKey, ApiKeyRepository, API_KEY_SCOPES},
+    balance::{self, BalanceError},
+    ledger::{self, BalanceBreakdown, LedgerRepository},
+    tx::{find_transaction_by_reference, insert_transaction, set_external_reference, TransactionStatus, TransactionType},
+    utils::convert_offsetdt_to_dt,
+    wallet::{self, Wallet, WalletRepository},
+};
+
+use super::{
+    auth::{AuthConfig, AuthService},
+    error::ApiError,
+    tx::{check_minor_units, own_account, TransferRecord, TxConfig},
+    utils::{self, AuthUser, JsonBody, TokenUser},
+};
+
+// Profile of the authenticated user as answered by `/users/me`, kept apart from the `users` row so
+// columns can be added to the table without showing up in the API
+#[derive(Debug, Serialize, FromRow)]
+pub struct UserPublic {
+    pub id: Uuid,
+    pub email: String,
+    pub full_name: String,
+    pub balance: Decimal,
+    pub currency: String,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+}
 
-use super::{auth::AuthService, utils::validate_auth_token};
+const USER_PUBLIC_QUERY: &str = "SELECT id, email, full_name, balance, currency, status, created_at FROM users WHERE id = $1";
 
 async fn get_user(
-    headers: HeaderMap,
-    State((service, pool)): State<(Arc<AuthService>, PgPool)>,
-) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
-    let uid = match validate_auth_token(headers, &service) {
-        Ok(val) => {
-            tracing::info!("Token validation succeeded for user: {}", val);
-            val
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user = sqlx::query_as::<_, UserPublic>(USER_PUBLIC_QUERY)
+        .bind(user_id)
+        .fetch_optional(&pool)
+        .await;
+
+    let mut user = match user {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            tracing::warn!("User not found: {user_id}");
+            return Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found"));
         }
         Err(err) => {
-            tracing::error!("Token validation failed: {:?}", err);
-            return Err((err, "Invalid token"));
+            tracing::error!("Failed to read user {user_id}: {err}");
+            return Err(ApiError::internal("Failed to read user"));
         }
     };
-    let user_id = uid;
 
-    // generate our query
-    let mut query_builder = sqlx::QueryBuilder::new("SELECT * FROM users WHERE id = ");
-    query_builder.push_bind(user_id);
-    let query = query_builder.build();
+    // the ledger is the source of truth, `users.balance` only caches its sum
+    let balances = match LedgerRepository::new(pool).breakdown_of(user_id).await {
+        Ok(balances) => balances,
+        Err(err) => {
+            tracing::error!("Failed to read balance: {err}");
+            return Err(ApiError::internal("Failed to read user"));
+        }
+    };
+    user.balance = balances.available;
+
+    tracing::info!("User found: {user_id}");
+    Ok((StatusCode::OK, Json(UserWithBalances { user, balances })))
+}
 
-    let user = query.fetch_one(&pool).await;
+#[derive(Debug, Serialize)]
+pub struct UserWithBalances {
+    #[serde(flatten)]
+    pub user: UserPublic,
+    #[serde(flatten)]
+    pub balances: BalanceBreakdown,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BalanceResponse {
+    pub balance: Decimal, // the available balance, kept for clients predating the breakdown
+    pub currency: String,
+    #[serde(flatten)]
+    pub balances: BalanceBreakdown,
+}
+
+// just the balance of the authenticated user, without serializing the whole profile
+async fn get_balance(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let currency = match sqlx::query_scalar!("SELECT currency FROM users WHERE id = $1", user_id)
+        .fetch_optional(&pool)
+        .await
+    {
+        Ok(Some(currency)) => currency,
+        Ok(None) => {
+            tracing::warn!("User not found: {user_id}");
+            return Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found"));
+        }
+        Err(err) => {
+            tracing::error!("Failed to read balance: {err}");
+            return Err(ApiError::internal("Failed to read balance"));
+        }
+    };
 
-    match user.map(|row| User::from_row(&row)) {
-        Ok(Ok(user)) => {
-            let body = serde_json::to_string(&user).unwrap();
-            tracing::info!("User found: {}", user_id);
-            return Ok((StatusCode::OK, body));
+    // the ledger is the source of truth, `users.balance` only caches its sum
+    match LedgerRepository::new(pool).breakdown_of(user_id).await {
+        Ok(balances) => {
+            let balance = balances.available;
+            Ok((StatusCode::OK, Json(BalanceResponse { balance, currency, balances })))
         }
-        _ => {
-            tracing::error!("User not found: {}", user_id);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "User not found",
-            ));
+        Err(err) => {
+            tracing::error!("Failed to read balance: {err}");
+            Err(ApiError::internal("Failed to read balance"))
         }
     }
 }
 
+// Only the fields present are changed, at least one of them has to be
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct UpdateUser {
     pub user_id: Uuid,
-    #[serde(rename = "name")]
-    pub new_name: String,
-    #[serde(rename = "email")]
-    pub new_email: String,
+    #[serde(default, rename = "name")]
+    pub new_name: Option<String>,
+    #[serde(default, rename = "email")]
+    pub new_email: Option<String>,
+}
+
+const MAX_NAME_CHARS: usize = 100;
+
+fn email_taken() -> ApiError {
+    ApiError::new(StatusCode::CONFLICT, "email_taken", "Email already in use")
 }
 
 async fn update_user(
-    headers: HeaderMap,
-    State((service, pool)): State<(Arc<AuthService>, PgPool)>,
-    Json(payload): Json<UpdateUser>,
-) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
-    let user_id = match validate_auth_token(headers, &service) {
-        Ok(val) => val,
+    AuthUser(user_id): AuthUser,
+    State((service, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    JsonBody(payload): JsonBody<UpdateUser>,
+) -> Result<impl IntoResponse, ApiError> {
+    if payload.user_id != user_id {
+        tracing::warn!("Forbidden update attempt by user: {}", user_id);
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "user_mismatch",
+            "Cannot update another user",
+        ));
+    }
+    if payload.new_name.is_none() && payload.new_email.is_none() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "empty_update",
+            "Provide at least one of `name` or `email`",
+        ));
+    }
+
+    // same rules as registration, where the email is parsed on deserialization
+    let new_email = match payload.new_email.as_deref().map(|email| Email::from_str(email.trim())) {
+        Some(Ok(email)) => Some(email.as_str().to_string()),
+        Some(Err(_)) => {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "invalid_email", "Invalid email address"));
+        }
+        None => None,
+    };
+    let new_name = payload.new_name.as_deref().map(str::trim);
+    if let Some(name) = new_name {
+        let name_chars = name.chars().count();
+        if name_chars == 0 || name_chars > MAX_NAME_CHARS {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_name",
+                format!("Name must be between 1 and {MAX_NAME_CHARS} characters"),
+            ));
+        }
+    }
+
+    let current_email = match sqlx::query!("SELECT email FROM users WHERE id = $1", user_id)
+        .fetch_one(&pool)
+        .await
+    {
+        Ok(record) => record.email,
         Err(err) => {
-            tracing::error!("Token validation failed: {:?}", err);
-            return Err((err, "Invalid token"));
+            tracing::error!("Failed to get user email: {err}");
+            return Err(ApiError::internal("Failed to update user"));
         }
     };
 
-    if payload.user_id != user_id {
-        tracing::warn!("Unauthorized update attempt by user: {}", user_id);
-        return Ok((StatusCode::UNAUTHORIZED, "Unauthorized"));
+    // resubmitting the current email is not a change
+    let new_email = new_email.filter(|email| *email != current_email);
+    if let Some(email) = new_email.as_deref() {
+        service.check_email_domain(email)?;
+
+        // caught early for a clear answer, the unique index still decides when two updates race
+        match sqlx::query!("SELECT id FROM users WHERE email = $1 AND id <> $2", email, user_id)
+            .fetch_optional(&pool)
+            .await
+        {
+            Ok(None) => {}
+            Ok(Some(_)) => return Err(email_taken()),
+            Err(err) => {
+                tracing::error!("Failed to look up email owner: {err}");
+                return Err(ApiError::internal("Failed to update user"));
+            }
+        }
+    }
+
+    // with confirmation enabled a new email only takes effect once confirmed, the old one keeps working meanwhile
+    let defer_email = service.config().confirm_email_changes && new_email.is_some();
+
+    let mut query_builder = sqlx::QueryBuilder::new("UPDATE users SET updated_at = CURRENT_TIMESTAMP");
+    if let Some(name) = new_name {
+        query_builder.push(", full_name = ").push_bind(name);
+    }
+    if let Some(email) = new_email.as_deref().filter(|_| !defer_email) {
+        query_builder.push(", email = ").push_bind(email);
     }
 
-    let mut query_builder = sqlx::QueryBuilder::new("UPDATE users SET ");
-    query_builder
-        .push("full_name = ")
-        .push_bind(&payload.new_name)
-        .push(", email = ")
-        .push_bind(&payload.new_email);
+    // only ever touch the authenticated user's row
+    query_builder.push(" WHERE id = ").push_bind(user_id);
 
     let query = query_builder.build();
     let result = query.execute(&pool).await;
 
+    if let Ok(done) = &result {
+        if done.rows_affected() != 1 {
+            tracing::warn!("No user updated for id: {}", user_id);
+            return Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found"));
+        }
+    }
+
+    if let (Ok(_), Some(email), true) = (&result, new_email.as_deref(), defer_email) {
+        if let Err(err) = request_email_change(&pool, service.config(), user_id, email).await {
+            tracing::error!("Failed to request email change: {:?}", err);
+            return Err(ApiError::internal("Failed to update user"));
+        }
+        return Ok((
+            StatusCode::ACCEPTED,
+            "User updated, the new email applies once confirmed",
+        ));
+    }
+
     match result {
         Ok(_) => {
             tracing::info!("User updated successfully: {}", user_id);
-            return Ok((StatusCode::OK, "User updated successfully"));
+            Ok((StatusCode::OK, "User updated successfully"))
         }
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => Err(email_taken()),
         Err(err) => {
             tracing::error!("Failed to update user: {:?}", err);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to update user",
-            ));
+            Err(ApiError::internal("Failed to update user"))
+        }
+    }
+}
+
+// Replace any pending email change of the user with a new one and hand out its confirmation link
+async fn request_email_change(
+    pool: &PgPool,
+    config: &AuthConfig,
+    user_id: Uuid,
+    new_email: &str,
+) -> Result<(), sqlx::Error> {
+    let token = Uuid::new_v4().to_string();
+    let expires_at = Utc::now() + config.email_change_ttl;
+
+    let mut tx = pool.begin().await?;
+    sqlx::query!("DELETE FROM email_change_requests WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO email_change_requests (user_id, new_email, token, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        user_id,
+        new_email,
+        token,
+        OffsetDateTime::from_unix_timestamp(expires_at.timestamp()).unwrap()
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    // no mailer is wired up yet, in development the link can be read from the debug log
+    if config.log_email_tokens {
+        let link = config.link("/users/email/confirm", &token);
+        tracing::debug!("Email change confirmation link for user {user_id}: {link}");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmEmailQuery {
+    pub token: String,
+}
+
+// apply a pending email change, the token itself proves ownership of the new address
+async fn confirm_email_change(
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    Query(query): Query<ConfirmEmailQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(err) => {
+            tracing::error!("Failed to start transaction: {err}");
+            return Err(ApiError::internal("Failed to confirm email"));
+        }
+    };
+
+    let request = match sqlx::query!(
+        r#"
+        DELETE FROM email_change_requests
+        WHERE token = $1 AND expires_at > CURRENT_TIMESTAMP
+        RETURNING user_id, new_email
+        "#,
+        query.token
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(request)) => request,
+        Ok(None) => return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "invalid_confirmation_token",
+            "Invalid or expired token",
+        )),
+        Err(err) => {
+            tracing::error!("Failed to look up email change: {err}");
+            return Err(ApiError::internal("Failed to confirm email"));
+        }
+    };
+
+    let result = sqlx::query!(
+        "UPDATE users SET email = $1, updated_at = CURRENT_TIMESTAMP WHERE id = $2",
+        request.new_email,
+        request.user_id
+    )
+    .execute(&mut *tx)
+    .await;
+
+    match result {
+        Ok(_) => {}
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            return Err(email_taken());
+        }
+        Err(err) => {
+            tracing::error!("Failed to apply email change: {err}");
+            return Err(ApiError::internal("Failed to confirm email"));
+        }
+    }
+
+    match tx.commit().await {
+        Ok(_) => {
+            tracing::info!("Email change confirmed for user: {}", request.user_id);
+            Ok((StatusCode::OK, "Email updated successfully"))
+        }
+        Err(err) => {
+            tracing::error!("Failed to commit email change: {err}");
+            Err(ApiError::internal("Failed to confirm email"))
         }
     }
 }
 
 //method for inserting a new user
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Deposit {
     pub email: String,
+    // no longer needed, still accepted from older clients
+    #[serde(default)]
     pub full_name: String,
     pub amount: Decimal,
+    // reference of the booking in an external system, the same one can't be deposited twice
+    #[serde(default)]
+    pub reference_id: Option<String>,
+    // one of the user's wallets, the primary one when left out
+    #[serde(default)]
+    pub wallet_id: Option<Uuid>,
+}
+
+// Size of the `transactions.external_reference` column
+const MAX_REFERENCE_CHARS: usize = 255;
+
+// Money movements answer 200 with the resulting balance rather than 201, as no new resource is addressable
+#[derive(Debug, Serialize)]
+pub struct DepositResponse {
+    pub user_id: Uuid,
+    pub balance: Decimal,
+    pub transaction_id: Uuid, // ledger entry recording the deposit
 }
 
 async fn deposit(
     headers: HeaderMap,
-    State((service, pool)): State<(Arc<AuthService>, PgPool)>,
-    Json(payload): Json<Deposit>,
-) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
-    let user_id = match validate_auth_token(headers, &service) {
-        Ok(val) => val,
-        Err(err) => {
-            return Err((err, "Invalid token"));
-        }
-    };
+    AuthUser(user_id): AuthUser,
+    State((_, pool, config)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    JsonBody(payload): JsonBody<Deposit>,
+) -> Result<impl IntoResponse, ApiError> {
+    // a negative deposit would be a withdrawal in disguise
+    if payload.amount <= Decimal::ZERO {
+        tracing::warn!("Non positive deposit amount from user: {}", user_id);
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_amount",
+            "Amount must be positive",
+        ));
+    }
 
     let user_email = match sqlx::query!("SELECT email FROM users WHERE id = $1", user_id)
         .fetch_one(&pool)
@@ -134,79 +426,1087 @@ async fn deposit(
         .map(|row| row.email)
     {
         Ok(email) => email,
+        Err(sqlx::Error::RowNotFound) => {
+            tracing::warn!("User not found: {user_id}");
+            return Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found"));
+        }
         Err(err) => {
             tracing::error!("Failed to get user email: {err}");
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to create user",
-            ));
+            return Err(ApiError::internal("Failed to update user balance"));
         }
     };
 
+    // the balance is credited by user id, the payload email only has to agree with the token's user
     if payload.email != user_email {
-        return Err((StatusCode::UNAUTHORIZED, "Unauthorized"));
+        tracing::warn!("Forbidden deposit attempt by user: {}", user_id);
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "user_mismatch",
+            "Cannot deposit to another user",
+        ));
     }
 
-    //check if user alredy exits
-    match service.repo.find_user_by_email(&payload.email).await {
-        Ok(Some(_)) => {
-            tracing::info!("User discovered in database");
-            ()
-        }
-        Err(err) => {
-            tracing::warn!("user not found in database: {err}");
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to create user",
+    let external_reference = payload.reference_id.as_deref().map(str::trim);
+    if let Some(reference) = external_reference {
+        let reference_chars = reference.chars().count();
+        if reference_chars == 0 || reference_chars > MAX_REFERENCE_CHARS {
+            tracing::warn!("Invalid deposit reference from user: {user_id}");
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_reference",
+                format!("Reference must be between 1 and {MAX_REFERENCE_CHARS} characters"),
             ));
         }
-        _ => {
-            tracing::error!("Failed to create user");
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to create user",
-            ));
+    }
+
+    // A replayed key answers with the deposit it created and the current balance, without crediting again
+    let idempotency_key = utils::idempotency_key(&headers)?;
+    if let Some(key) = idempotency_key.as_deref() {
+        match replay_deposit(&pool, user_id, key).await {
+            Ok(Some(body)) => return Ok((StatusCode::OK, Json(body))),
+            Ok(None) => {}
+            Err(err) => {
+                tracing::error!("Failed to look up deposit by idempotency key: {err}");
+                return Err(ApiError::internal("Failed to update user balance"));
+            }
         }
-    };
+    }
 
-    let query = sqlx::query!(
-        r#"
-        UPDATE users SET balance = balance + $1 WHERE email = $2
-        RETURNING id, balance
-        "#,
-        payload.amount,
-        payload.email
-    )
-    .fetch_one(&pool)
+    let (account, currency) = own_account(&pool, user_id, payload.wallet_id).await?;
+    check_minor_units(payload.amount, &currency)?;
+
+    // the ledger entry and the balance change commit together
+    let credited = async {
+        let mut tx = pool.begin().await?;
+        let transaction_id = insert_transaction(
+            &mut tx,
+            user_id,
+            payload.amount,
+            TransactionType::Deposit,
+            TransactionStatus::Completed,
+            idempotency_key.as_deref(),
+            None,
+        )
+        .await?;
+        if let Some(reference) = external_reference {
+            set_external_reference(&mut tx, transaction_id, reference).await?;
+        }
+        let balance =
+            balance::credit_within_cap(&mut tx, account, payload.amount, config.max_account_balance, transaction_id).await?;
+        let audit_entry = AuditEntry {
+            actor_id: user_id,
+            action: AuditAction::Deposit,
+            amount: payload.amount,
+            counterparty_id: None,
+            tx_id: transaction_id,
+        };
+        audit::record(&mut tx, &audit_entry).await?;
+        tx.commit().await?;
+        Ok::<_, BalanceError>((balance, transaction_id))
+    }
     .await;
 
-    match query {
-        Ok(record) => {
-            let balance = record.balance.to_string();
+    match credited {
+        Ok((balance, transaction_id)) => {
             tracing::info!(
-                "User balance updated successfully for user: {}. New balance: {balance}",
-                record.id
-            );
-            let body = format!(
-                "User balance updated successfully. New balance: {}",
+                "User balance updated successfully for user: {}. New balance: {}",
+                user_id,
                 balance
             );
-            Ok((StatusCode::OK, body))
+            let body = DepositResponse {
+                user_id,
+                balance,
+                transaction_id,
+            };
+            Ok((StatusCode::OK, Json(body)))
+        }
+        Err(BalanceError::Database(sqlx::Error::Database(err)))
+            if err.constraint() == Some("idx_transactions_deposit_external_reference") =>
+        {
+            tracing::warn!("Duplicate deposit reference from user: {user_id}");
+            Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "duplicate_reference",
+                "A deposit with this reference was already made",
+            ))
+        }
+        // a concurrent request with the same key committed first
+        Err(BalanceError::Database(sqlx::Error::Database(err))) if err.is_unique_violation() => {
+            let key = idempotency_key.as_deref().unwrap_or_default();
+            match replay_deposit(&pool, user_id, key).await {
+                Ok(Some(body)) => Ok((StatusCode::OK, Json(body))),
+                Ok(None) => Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    "idempotency_key_conflict",
+                    "Idempotency-Key is in use, retry the request",
+                )),
+                Err(err) => {
+                    tracing::error!("Failed to look up deposit by idempotency key: {err}");
+                    Err(ApiError::internal("Failed to update user balance"))
+                }
+            }
+        }
+        // the account was removed after the email check, caught by the ledger row or the lock in `credit_within_cap`
+        Err(BalanceError::UserNotFound) => {
+            tracing::warn!("User not found: {user_id}");
+            Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found"))
+        }
+        Err(BalanceError::AccountFrozen) => {
+            tracing::warn!("Deposit attempt to frozen account: {user_id}");
+            Err(ApiError::new(StatusCode::FORBIDDEN, "account_frozen", "Account is frozen"))
+        }
+        Err(BalanceError::AccountClosed) => {
+            tracing::warn!("Deposit attempt to closed account: {user_id}");
+            Err(ApiError::new(StatusCode::FORBIDDEN, "account_closed", "Account is closed"))
+        }
+        Err(BalanceError::BalanceCapExceeded) => {
+            tracing::warn!("Deposit would exceed the maximum balance of user: {}", user_id);
+            Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "balance_cap_exceeded",
+                "Deposit would exceed the maximum account balance",
+            ))
         }
         Err(err) => {
             tracing::info!("Failed to update user balance: {err}");
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to update user balance",
-            ));
+            Err(ApiError::internal("Failed to update user balance"))
+        }
+    }
+}
+
+// Response for a deposit already made under `key`, if there is one
+async fn replay_deposit(pool: &PgPool, user_id: Uuid, key: &str) -> Result<Option<DepositResponse>, sqlx::Error> {
+    let Some(transaction_id) = find_transaction_by_reference(pool, user_id, TransactionType::Deposit, key).await? else {
+        return Ok(None);
+    };
+    // the current balance of the wallet the deposit went to
+    let balance = sqlx::query_scalar!(
+        r#"
+        SELECT COALESCE(SUM(delta), 0) AS "balance!"
+        FROM ledger_entries
+        WHERE wallet_id = (SELECT wallet_id FROM ledger_entries WHERE tx_id = $1 LIMIT 1)
+        "#,
+        transaction_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    tracing::info!("Replayed idempotency key of user {user_id} for deposit: {transaction_id}");
+    Ok(Some(DepositResponse {
+        user_id,
+        balance,
+        transaction_id,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct FailedTransfer {
+    pub id: Uuid,
+    pub details: Option<serde_json::Value>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// return the transfer attempts of the user which were rejected or failed
+async fn list_failed_transfers(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let records = match sqlx::query!(
+        r#"
+        SELECT id, changes, created_at FROM audit_logs
+        WHERE user_id = $1 AND action = 'transfer_failed'
+        ORDER BY created_at DESC
+        "#,
+        user_id
+    )
+    .fetch_all(&pool)
+    .await
+    {
+        Ok(records) => records,
+        Err(err) => {
+            tracing::error!("Failed to retrieve failed transfers: {err}");
+            return Err(ApiError::internal("Failed to retrieve failed transfers"));
+        }
+    };
+
+    let failed = records
+        .into_iter()
+        .map(|record| FailedTransfer {
+            id: record.id,
+            details: record.changes,
+            created_at: record.created_at.map(convert_offsetdt_to_dt),
+        })
+        .collect::<Vec<_>>();
+
+    Ok((StatusCode::OK, Json(failed)))
+}
+
+// the user's wallets with their balances, the primary one first
+async fn list_wallets(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+) -> Result<impl IntoResponse, ApiError> {
+    match WalletRepository::new(pool).list(user_id).await {
+        Ok(wallets) => Ok((StatusCode::OK, Json(wallets))),
+        Err(err) => {
+            tracing::error!("Failed to list wallets of user {user_id}: {err}");
+            Err(ApiError::internal("Failed to list wallets"))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpenWallet {
+    pub currency: Currency,
+}
+
+// open an empty wallet in another currency, one per currency
+async fn open_wallet(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    JsonBody(payload): JsonBody<OpenWallet>,
+) -> Result<impl IntoResponse, ApiError> {
+    match WalletRepository::new(pool).open(user_id, &payload.currency).await {
+        Ok(Some(wallet)) => {
+            tracing::info!("User {user_id} opened wallet {} in {}", wallet.id, wallet.currency);
+            Ok((StatusCode::CREATED, Json(wallet)))
+        }
+        Ok(None) => Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "wallet_exists",
+            format!("A {} wallet is already open", payload.currency),
+        )),
+        Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+            tracing::warn!("User not found: {user_id}");
+            Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found"))
         }
+        Err(err) => {
+            tracing::error!("Failed to open wallet for user {user_id}: {err}");
+            Err(ApiError::internal("Failed to open wallet"))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewApiKey {
+    pub label: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String, // only ever shown in this response
+}
+
+// Create an API key with the given scopes, answered 201 with the key itself. Managing keys takes an
+// access token, a key can't mint or revoke others
+async fn create_api_key(
+    TokenUser(user_id): TokenUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    JsonBody(mut payload): JsonBody<NewApiKey>,
+) -> Result<impl IntoResponse, ApiError> {
+    let label = payload.label.trim();
+    if label.is_empty() || label.chars().count() > 100 {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_label",
+            "Label must be 1 to 100 characters",
+        ));
+    }
+    payload.scopes.sort();
+    payload.scopes.dedup();
+    if payload.scopes.is_empty() || !payload.scopes.iter().all(|scope| API_KEY_SCOPES.contains(&scope.as_str())) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_scopes",
+            format!("Scopes must be some of: {}", API_KEY_SCOPES.join(", ")),
+        ));
+    }
+
+    let key = api_key::generate_key();
+    match ApiKeyRepository::new(pool).create(user_id, label, &payload.scopes, &key).await {
+        Ok(api_key) => {
+            tracing::info!("User {user_id} created API key {}", api_key.id);
+            Ok((StatusCode::CREATED, Json(CreatedApiKey { api_key, key })))
+        }
+        Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+            tracing::warn!("User not found: {user_id}");
+            Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found"))
+        }
+        Err(err) => {
+            tracing::error!("Failed to create API key for user {user_id}: {err}");
+            Err(ApiError::internal("Failed to create API key"))
+        }
+    }
+}
+
+// the user's API keys with when each was last used, newest first
+async fn list_api_keys(
+    TokenUser(user_id): TokenUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+) -> Result<impl IntoResponse, ApiError> {
+    match ApiKeyRepository::new(pool).list(user_id).await {
+        Ok(api_keys) => Ok((StatusCode::OK, Json(api_keys))),
+        Err(err) => {
+            tracing::error!("Failed to list API keys of user {user_id}: {err}");
+            Err(ApiError::internal("Failed to list API keys"))
+        }
+    }
+}
+
+// revoke one of the user's API keys, answered 204
+async fn delete_api_key(
+    TokenUser(user_id): TokenUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    key_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    let key_id = utils::id_from_path(key_id)?;
+    match ApiKeyRepository::new(pool).delete(user_id, key_id).await {
+        Ok(true) => {
+            tracing::info!("User {user_id} deleted API key {key_id}");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err(ApiError::new(StatusCode::NOT_FOUND, "api_key_not_found", "API key not found")),
+        Err(err) => {
+            tracing::error!("Failed to delete API key {key_id} of user {user_id}: {err}");
+            Err(ApiError::internal("Failed to delete API key"))
+        }
+    }
+}
+
+// transfers included in the account summary, newest first
+const SUMMARY_RECENT_TRANSACTIONS: i64 = 5;
+
+// one of the user's wallets and where its money stands
+#[derive(Debug, Serialize)]
+pub struct WalletSummary {
+    #[serde(flatten)]
+    pub wallet: Wallet,
+    #[serde(flatten)]
+    pub balances: BalanceBreakdown,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountSummary {
+    pub profile: UserPublic,
+    pub balance: Decimal, // available in the primary wallet, as on the profile
+    pub wallets: Vec<WalletSummary>, // primary first
+    pub recent_transactions: Vec<TransferRecord>,
+}
+
+// profile, every wallet's balances and the latest transfers in one call, read from a single snapshot so they agree
+async fn account_summary(
+    AuthUser(user_id): AuthUser,
+    State((_, pool, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let summary = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+            .execute(&mut *tx)
+            .await?;
+
+        let mut profile = sqlx::query_as::<_, UserPublic>(USER_PUBLIC_QUERY)
+            .bind(user_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        // the ledger is the source of truth, `users.balance` only caches its sum
+        profile.balance = ledger::balance_in_tx(&mut tx, user_id).await?;
+        let mut wallets = Vec::new();
+        for wallet in wallet::list_in_tx(&mut tx, user_id).await? {
+            let balances = ledger::breakdown_in_tx(&mut tx, wallet.id).await?;
+            wallets.push(WalletSummary { wallet, balances });
+        }
+        let recent_transactions = sqlx::query_as::<_, TransferRecord>(
+            r#"
+            SELECT id, transfer_no, sender_id, recipient_id AS receiver_id, sender_wallet_id, receiver_wallet_id, amount, currency, description, channel, metadata, reversed_tx_id, transfer_kind, created_at
+            FROM transfers
+            WHERE (sender_id = $1 OR recipient_id = $1) AND status = 'completed'
+            ORDER BY transfer_no DESC
+            LIMIT $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(SUMMARY_RECENT_TRANSACTIONS)
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok::<_, sqlx::Error>(AccountSummary {
+            balance: profile.balance,
+            profile,
+            wallets,
+            recent_transactions,
+        })
+    }
+    .await;
+
+    match summary {
+        Ok(summary) => Ok((StatusCode::OK, Json(summary))),
+        Err(sqlx::Error::RowNotFound) => {
+            tracing::warn!("User not found: {user_id}");
+            Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found"))
+        }
+        Err(err) => {
+            tracing::error!("Failed to build account summary: {err}");
+            Err(ApiError::internal("Failed to build account summary"))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CloseAccount {
+    pub password: String,
+}
+
+// Close the caller's account for good, the ledger history is kept. Only an empty account can be closed
+async fn close_account(
+    headers: HeaderMap,
+    AuthUser(user_id): AuthUser,
+    State((service, _, _)): State<(Arc<AuthService>, PgPool, Arc<TxConfig>)>,
+    JsonBody(request): JsonBody<CloseAccount>,
+) -> Result<impl IntoResponse, ApiError> {
+    if let Err(err) = service.close_account(user_id, &request.password).await {
+        return Err(match err.downcast::<ApiError>() {
+            Ok(api_error) => *api_error,
+            Err(err) => {
+                tracing::error!("Failed to close account of user {user_id}: {err}");
+                ApiError::internal("Failed to close account")
+            }
+        });
     }
+
+    // refresh tokens are gone with the closure, the access token of this request is revoked as well
+    if let Some(token) = headers.get("Authorization").and_then(|token| token.to_str().ok()) {
+        if let Err(err) = service.logout(token).await {
+            tracing::error!("Failed to revoke access token of closed account {user_id}: {err}");
+        }
+    }
+
+    Ok((StatusCode::OK, "Account closed"))
 }
 
-pub fn user_routes(service: Arc<AuthService>, db_pool: PgPool) -> Router {
+pub fn user_routes(service: Arc<AuthService>, db_pool: PgPool, tx_config: Arc<TxConfig>) -> Router {
     Router::new()
-        .route("/users/uid", get(get_user))
+        .route("/users", delete(close_account))
+        .route("/users/me", get(get_user))
+        .route("/users/uid", get(get_user)) // older name of `/users/me`
+        .route("/users/balance", get(get_balance))
+        .route("/users/summary", get(account_summary))
         .route("/users/update", put(update_user))
+        .route("/users/email/confirm", get(confirm_email_change))
         .route("/users/deposit", post(deposit))
-        .with_state((service, db_pool))
+        .route("/users/wallets", get(list_wallets).post(open_wallet))
+        .route("/users/api-keys", get(list_api_keys).post(create_api_key))
+        .route("/users/api-keys/:id", delete(delete_api_key))
+        .route("/users/transfers/failed", get(list_failed_transfers))
+        .with_state((service, db_pool, tx_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use rust_decimal::Decimal;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use crate::routes::auth::AuthConfig;
+    use crate::test_utils::{auth_config, decimal, tx_config, TestApp, TestUser, PASSWORD};
+
+    async fn change_email(app: &TestApp, user: &TestUser, email: &str) -> String {
+        let response = app
+            .request(Method::PUT, "/v1/users/update")
+            .token(&user.token)
+            .json(json!({ "user_id": user.id, "email": email }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::ACCEPTED, "{}", response.body);
+        sqlx::query_scalar("SELECT token FROM email_change_requests WHERE user_id = $1")
+            .bind(user.id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn email_changes_only_once_confirmed(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let token = change_email(&app, &alice, "alice.new@example.com").await;
+
+        // the old email keeps working until the new one is confirmed
+        assert_eq!(app.login(&alice.email, PASSWORD).await.status, StatusCode::OK);
+        assert_eq!(app.login("alice.new@example.com", PASSWORD).await.status, StatusCode::UNAUTHORIZED);
+        let profile = app.get("/v1/users/me").token(&alice.token).send().await;
+        assert_eq!(profile.body["email"], alice.email);
+
+        let response = app.get(&format!("/v1/users/email/confirm?token={token}")).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+
+        assert_eq!(app.login("alice.new@example.com", PASSWORD).await.status, StatusCode::OK);
+        assert_eq!(app.login(&alice.email, PASSWORD).await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn expired_email_change_is_not_applied(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let token = change_email(&app, &alice, "alice.new@example.com").await;
+        sqlx::query("UPDATE email_change_requests SET expires_at = CURRENT_TIMESTAMP - interval '1 second'")
+            .execute(&app.pool)
+            .await
+            .unwrap();
+
+        let response = app.get(&format!("/v1/users/email/confirm?token={token}")).send().await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert_eq!(response.error_code(), "invalid_confirmation_token");
+        assert_eq!(app.login(&alice.email, PASSWORD).await.status, StatusCode::OK);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn summary_shows_the_public_profile_and_ledger_balance(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+        let transfer = app.transfer(&alice, bob.id, "20").await;
+
+        // a stale cache must not leak into the summary
+        sqlx::query("UPDATE users SET balance = 999 WHERE id = $1")
+            .bind(alice.id)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+
+        let response = app.get("/v1/users/summary").token(&alice.token).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        let profile = &response.body["profile"];
+        assert_eq!(profile["id"], json!(alice.id));
+        assert_eq!(decimal(&profile["balance"]), Decimal::from(30));
+        assert!(profile.get("password_hash").is_none());
+        assert!(profile.get("role").is_none());
+        assert_eq!(decimal(&response.body["balance"]), Decimal::from(30));
+        assert_eq!(response.body["recent_transactions"][0]["id"], transfer.body["id"]);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn summary_lists_every_wallet_with_its_balances(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let opened = app
+            .post("/v1/users/wallets")
+            .token(&alice.token)
+            .json(json!({ "currency": "EUR" }))
+            .send()
+            .await;
+        let eur_wallet = opened.body["id"].as_str().unwrap();
+        app.post("/v1/users/deposit")
+            .token(&alice.token)
+            .json(json!({ "email": alice.email, "amount": "10", "wallet_id": eur_wallet }))
+            .send()
+            .await;
+        app.deposit(&alice, "50").await;
+        let hold = app
+            .post("/v1/tx/holds")
+            .token(&alice.token)
+            .json(json!({ "receiver_id": bob.id, "amount": "20" }))
+            .send()
+            .await;
+        assert_eq!(hold.status, StatusCode::CREATED, "{}", hold.body);
+
+        let response = app.get("/v1/users/summary").token(&alice.token).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(decimal(&response.body["balance"]), Decimal::from(30));
+        let wallets = response.body["wallets"].as_array().unwrap();
+        assert_eq!(wallets.len(), 2);
+
+        let usd = &wallets[0];
+        assert_eq!((&usd["currency"], &usd["primary"]), (&json!("USD"), &json!(true)));
+        assert_eq!(decimal(&usd["available"]), Decimal::from(30));
+        assert_eq!(decimal(&usd["held"]), Decimal::from(20));
+        assert_eq!(decimal(&usd["pending_incoming"]), Decimal::ZERO);
+        assert_eq!(decimal(&usd["total"]), Decimal::from(50));
+
+        let eur = &wallets[1];
+        assert_eq!(eur["id"], eur_wallet);
+        assert_eq!((&eur["currency"], &eur["primary"]), (&json!("EUR"), &json!(false)));
+        assert_eq!(decimal(&eur["available"]), Decimal::from(10));
+        assert_eq!(decimal(&eur["total"]), Decimal::from(10));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn deposit_does_not_need_a_full_name(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+
+        let response = app
+            .post("/v1/users/deposit")
+            .token(&alice.token)
+            .json(json!({ "email": alice.email, "amount": "12.50" }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(app.balance(&alice).await, Decimal::new(1250, 2));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn deposit_answers_the_balance_and_transaction_as_json(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        app.deposit(&alice, "5").await;
+
+        let response = app.deposit(&alice, "12.50").await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert!(response.header("content-type").starts_with("application/json"));
+        let body = response.body.as_object().unwrap();
+        let mut fields = body.keys().map(String::as_str).collect::<Vec<_>>();
+        fields.sort_unstable();
+        assert_eq!(fields, ["balance", "transaction_id", "user_id"]);
+        assert_eq!(body["user_id"], alice.id.to_string());
+        // amounts as strings, the balance after this deposit
+        assert!(body["balance"].is_string());
+        assert_eq!(decimal(&body["balance"]), Decimal::new(1750, 2));
+        body["transaction_id"].as_str().unwrap().parse::<Uuid>().unwrap();
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn deposits_are_listed_in_the_ledger(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let first = app.deposit(&alice, "20").await;
+        let second = app.deposit(&alice, "7.5").await;
+        app.deposit(&bob, "3").await;
+
+        let response = app.get("/v1/tx/ledger").token(&alice.token).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        let entries = response.body.as_array().unwrap();
+        // newest first, and only the caller's
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["id"], second.body["transaction_id"]);
+        assert_eq!(entries[1]["id"], first.body["transaction_id"]);
+        assert_eq!(entries[1]["transaction_type"], "deposit");
+        assert_eq!(entries[1]["status"], "completed");
+        assert_eq!(decimal(&entries[1]["amount"]), Decimal::from(20));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn concurrent_deposits_all_count(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+
+        let responses = futures::future::join_all((0..20).map(|_| app.deposit(&alice, "1.5"))).await;
+        for response in &responses {
+            assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        }
+        assert_eq!(app.balance(&alice).await, Decimal::from(30));
+
+        // the account is credited by the token's user, an email that isn't theirs is refused
+        let bob = app.register("bob").await;
+        let response = app
+            .post("/v1/users/deposit")
+            .token(&alice.token)
+            .json(json!({ "email": bob.email, "amount": "5" }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(response.error_code(), "user_mismatch");
+        assert_eq!(app.balance(&bob).await, Decimal::ZERO);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn each_deposit_is_audited_once(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let keyed_deposit = || {
+            app.post("/v1/users/deposit")
+                .token(&alice.token)
+                .header("idempotency-key", "deposit-1")
+                .json(json!({ "email": alice.email, "amount": "20" }))
+                .send()
+        };
+
+        let first = keyed_deposit().await;
+        assert_eq!(first.status, StatusCode::OK, "{}", first.body);
+        // a replay and a refused deposit record nothing
+        assert_eq!(keyed_deposit().await.body["transaction_id"], first.body["transaction_id"]);
+        assert_eq!(app.deposit(&alice, "0").await.status, StatusCode::BAD_REQUEST);
+        let second = app.deposit(&alice, "5.25").await;
+
+        let entries: Vec<(Uuid, String, serde_json::Value)> = sqlx::query_as(
+            "SELECT entity_id, entity_type, changes FROM audit_logs WHERE user_id = $1 AND action = 'deposit' ORDER BY created_at, id",
+        )
+        .bind(alice.id)
+        .fetch_all(&app.pool)
+        .await
+        .unwrap();
+        assert_eq!(entries.len(), 2);
+        for ((entity_id, entity_type, changes), (deposit, amount)) in
+            entries.iter().zip([(&first, Decimal::from(20)), (&second, Decimal::new(525, 2))])
+        {
+            assert_eq!(entity_id.to_string(), deposit.body["transaction_id"].as_str().unwrap());
+            assert_eq!(entity_type, "transaction");
+            assert_eq!(decimal(&changes["amount"]), amount);
+            assert!(changes["counterparty_id"].is_null());
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn a_deposit_reference_is_booked_once_per_user(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let deposit = |user: &TestUser, reference: &str| {
+            app.post("/v1/users/deposit")
+                .token(&user.token)
+                .json(json!({ "email": user.email, "amount": "10", "reference_id": reference }))
+                .send()
+        };
+
+        let response = deposit(&alice, "stmt-42").await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        let ledger = app.get("/v1/tx/ledger").token(&alice.token).send().await;
+        assert_eq!(ledger.body[0]["external_reference"], "stmt-42");
+
+        for reference in ["stmt-42", " stmt-42 "] {
+            let response = deposit(&alice, reference).await;
+            assert_eq!(response.status, StatusCode::CONFLICT, "{reference}");
+            assert_eq!(response.error_code(), "duplicate_reference");
+        }
+        assert_eq!(app.balance(&alice).await, Decimal::from(10));
+
+        // another user's booking may carry the same reference
+        let response = deposit(&bob, "stmt-42").await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+
+        let response = deposit(&alice, " ").await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.error_code(), "invalid_reference");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn profile_has_a_fixed_shape_under_both_paths(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        app.deposit(&alice, "12.5").await;
+
+        let profile = app.get("/v1/users/me").token(&alice.token).send().await;
+        assert_eq!(profile.status, StatusCode::OK, "{}", profile.body);
+        let mut fields = profile.body.as_object().unwrap().keys().map(String::as_str).collect::<Vec<_>>();
+        fields.sort_unstable();
+        // the DTO and the balance breakdown, nothing else of the row (no password hash or totp secret)
+        assert_eq!(
+            fields,
+            ["available", "balance", "created_at", "currency", "email", "full_name", "held", "id", "pending_incoming", "status", "total"]
+        );
+        assert_eq!(profile.body["id"], alice.id.to_string());
+        assert_eq!(profile.body["email"], alice.email);
+        assert_eq!(profile.body["full_name"], "alice");
+        assert_eq!(decimal(&profile.body["balance"]), Decimal::new(125, 1));
+        assert_eq!(profile.body["currency"], "USD");
+        assert_eq!(profile.body["status"], "active");
+        chrono::DateTime::parse_from_rfc3339(profile.body["created_at"].as_str().unwrap()).unwrap();
+
+        // the old path answers the same
+        let alias = app.get("/v1/users/uid").token(&alice.token).send().await;
+        assert_eq!(alias.status, StatusCode::OK);
+        assert_eq!(alias.body, profile.body);
+    }
+
+    async fn name_and_email(app: &TestApp, user: &TestUser) -> (String, String) {
+        sqlx::query_as("SELECT full_name, email FROM users WHERE id = $1")
+            .bind(user.id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn update_changes_only_the_callers_row(pool: PgPool) {
+        let config = AuthConfig {
+            confirm_email_changes: false,
+            ..auth_config()
+        };
+        let app = TestApp::with_config(pool, config, tx_config());
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let carol = app.register("carol").await;
+
+        let response = app
+            .request(Method::PUT, "/v1/users/update")
+            .token(&alice.token)
+            .json(json!({ "user_id": alice.id, "name": "Alice Liddell", "email": "liddell@example.com" }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+
+        assert_eq!(
+            name_and_email(&app, &alice).await,
+            ("Alice Liddell".to_string(), "liddell@example.com".to_string())
+        );
+        assert_eq!(name_and_email(&app, &bob).await, ("bob".to_string(), bob.email.clone()));
+        assert_eq!(name_and_email(&app, &carol).await, ("carol".to_string(), carol.email.clone()));
+
+        // naming someone else's id in the payload changes nothing
+        let response = app
+            .request(Method::PUT, "/v1/users/update")
+            .token(&alice.token)
+            .json(json!({ "user_id": bob.id, "name": "Mallory" }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(name_and_email(&app, &bob).await, ("bob".to_string(), bob.email.clone()));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn zero_and_negative_deposits_are_rejected(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        app.deposit(&alice, "10").await;
+
+        for amount in ["0", "-5"] {
+            let response = app.deposit(&alice, amount).await;
+            assert_eq!(response.status, StatusCode::BAD_REQUEST, "{amount}");
+            assert_eq!(response.error_code(), "invalid_amount");
+        }
+        assert_eq!(app.balance(&alice).await, Decimal::from(10));
+    }
+
+    async fn create_api_key(app: &TestApp, user: &TestUser, scopes: &[&str]) -> (String, String) {
+        let response = app
+            .post("/v1/users/api-keys")
+            .token(&user.token)
+            .json(json!({ "label": "ci", "scopes": scopes }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::CREATED, "{}", response.body);
+        (
+            response.body["id"].as_str().unwrap().to_string(),
+            response.body["key"].as_str().unwrap().to_string(),
+        )
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn using_an_api_key_updates_its_last_used_at(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let (key_id, key) = create_api_key(&app, &alice, &["read"]).await;
+
+        let keys = app.get("/v1/users/api-keys").token(&alice.token).send().await;
+        assert_eq!(keys.status, StatusCode::OK, "{}", keys.body);
+        assert_eq!(keys.body[0]["id"], key_id);
+        assert_eq!(keys.body[0]["label"], "ci");
+        assert_eq!(keys.body[0]["scopes"], json!(["read"]));
+        assert!(keys.body[0]["last_used_at"].is_null());
+        // the key itself is never listed, not even its hash
+        assert!(!keys.body.to_string().contains(&key));
+        assert!(keys.body[0].get("key").is_none() && keys.body[0].get("key_hash").is_none());
+
+        let balance = app.get("/v1/users/balance").header("X-Api-Key", &key).send().await;
+        assert_eq!(balance.status, StatusCode::OK, "{}", balance.body);
+
+        let keys = app.get("/v1/users/api-keys").token(&alice.token).send().await;
+        let last_used_at = keys.body[0]["last_used_at"].as_str().unwrap().to_string();
+        chrono::DateTime::parse_from_rfc3339(&last_used_at).unwrap();
+
+        // a read key can't move money, and keys can't manage keys
+        let deposit = app
+            .post("/v1/users/deposit")
+            .header("X-Api-Key", &key)
+            .json(json!({ "email": alice.email, "full_name": "Test", "amount": "5" }))
+            .send()
+            .await;
+        assert_eq!(deposit.status, StatusCode::FORBIDDEN);
+        assert_eq!(deposit.error_code(), "insufficient_scope");
+        let keys = app.get("/v1/users/api-keys").header("X-Api-Key", &key).send().await;
+        assert_eq!(keys.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn deleting_an_api_key_revokes_access(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        let (key_id, key) = create_api_key(&app, &alice, &["read", "write"]).await;
+
+        let deposit = app
+            .post("/v1/users/deposit")
+            .header("X-Api-Key", &key)
+            .json(json!({ "email": alice.email, "full_name": "Test", "amount": "5" }))
+            .send()
+            .await;
+        assert_eq!(deposit.status, StatusCode::OK, "{}", deposit.body);
+
+        // only the owner can delete it
+        let path = format!("/v1/users/api-keys/{key_id}");
+        let response = app.request(Method::DELETE, &path).token(&bob.token).send().await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        let response = app.request(Method::DELETE, &path).token(&alice.token).send().await;
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+
+        let balance = app.get("/v1/users/balance").header("X-Api-Key", &key).send().await;
+        assert_eq!(balance.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(balance.error_code(), "invalid_api_key");
+        let keys = app.get("/v1/users/api-keys").token(&alice.token).send().await;
+        assert_eq!(keys.body, json!([]));
+    }
+
+    async fn balances(app: &TestApp, user: &TestUser) -> [Decimal; 4] {
+        let response = app.get("/v1/users/balance").token(&user.token).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(response.body["balance"], response.body["available"]);
+        ["available", "held", "pending_incoming", "total"].map(|field| decimal(&response.body[field]))
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn hold_reduces_available_but_not_total(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let hold = app
+            .post("/v1/tx/holds")
+            .token(&alice.token)
+            .json(json!({ "receiver_id": bob.id, "amount": "20" }))
+            .send()
+            .await;
+        assert_eq!(hold.status, StatusCode::CREATED, "{}", hold.body);
+
+        let [available, held, pending_incoming, total] = balances(&app, &alice).await;
+        assert_eq!(available, Decimal::from(30));
+        assert_eq!(held, Decimal::from(20));
+        assert_eq!(pending_incoming, Decimal::ZERO);
+        assert_eq!(total, Decimal::from(50));
+
+        // `/users/me` reports the same breakdown
+        let me = app.get("/v1/users/me").token(&alice.token).send().await;
+        assert_eq!(decimal(&me.body["available"]), Decimal::from(30));
+        assert_eq!(decimal(&me.body["total"]), Decimal::from(50));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn incoming_pending_transfer_raises_pending_incoming(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&alice, "50").await;
+
+        let transfer = app
+            .post("/v1/tx/transfer")
+            .token(&alice.token)
+            .json(json!({ "sender_id": alice.id, "receiver_id": bob.id, "amount": "15", "transfer_kind": "external" }))
+            .send()
+            .await;
+        assert_eq!(transfer.status, StatusCode::ACCEPTED, "{}", transfer.body);
+
+        let [available, held, pending_incoming, total] = balances(&app, &bob).await;
+        assert_eq!(available, Decimal::ZERO);
+        assert_eq!(held, Decimal::ZERO);
+        assert_eq!(pending_incoming, Decimal::from(15));
+        assert_eq!(total, Decimal::from(15));
+
+        // on the sender's side the amount is held until it settles
+        let [available, held, _, total] = balances(&app, &alice).await;
+        assert_eq!((available, held, total), (Decimal::from(35), Decimal::from(15), Decimal::from(50)));
+    }
+
+    // the token is checked before the path or body, so a missing or bad one is a 401 whatever else is wrong
+    #[sqlx::test(migrations = "./migrations")]
+    async fn credentials_are_checked_before_anything_else(pool: PgPool) {
+        let app = TestApp::new(pool);
+
+        let requests = [
+            app.get("/v1/users/balance"),
+            app.get("/v1/users/balance").token("not-a-token"),
+            app.get("/v1/tx/get_tx/not-a-uuid"),
+            app.request(Method::PUT, "/v1/users/update").json(json!({ "unexpected": true })),
+            app.post("/v1/tx/transfer").header("Content-Type", "application/json").token("Bearer"),
+        ];
+        for request in requests {
+            let response = request.send().await;
+            assert_eq!(response.status, StatusCode::UNAUTHORIZED, "{}", response.body);
+            assert_eq!(response.error_code(), "invalid_token");
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn closing_an_account_needs_the_password_and_no_balance(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let bob = app.register("bob").await;
+        app.deposit(&bob, "5").await;
+        let close = |user: &TestUser, password: &str| {
+            app.request(Method::DELETE, "/v1/users")
+                .token(&user.token)
+                .json(json!({ "password": password }))
+                .send()
+        };
+
+        let response = close(&alice, "Wr0ngPass!x").await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(response.error_code(), "invalid_password");
+        let response = close(&bob, PASSWORD).await;
+        assert_eq!(response.status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(response.error_code(), "nonzero_balance");
+
+        let response = close(&alice, PASSWORD).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        let response = app.get("/v1/users/balance").token(&alice.token).send().await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        let response = app.login(&alice.email, PASSWORD).await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(response.error_code(), "account_closed");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn invalid_names_and_emails_are_rejected(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+
+        let long_name = "a".repeat(101);
+        let cases = [
+            (json!({ "user_id": alice.id, "email": "not-an-email" }), "invalid_email"),
+            (json!({ "user_id": alice.id, "name": "   " }), "invalid_name"),
+            (json!({ "user_id": alice.id, "name": long_name }), "invalid_name"),
+        ];
+        for (body, code) in cases {
+            let response = app.request(Method::PUT, "/v1/users/update").token(&alice.token).json(body).send().await;
+            assert_eq!(response.status, StatusCode::BAD_REQUEST, "{}", response.body);
+            assert_eq!(response.error_code(), code);
+        }
+        assert_eq!(name_and_email(&app, &alice).await, ("alice".to_string(), alice.email.clone()));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn update_leaves_fields_it_is_not_given_alone(pool: PgPool) {
+        let config = AuthConfig {
+            confirm_email_changes: false,
+            ..auth_config()
+        };
+        let app = TestApp::with_config(pool, config, tx_config());
+        let alice = app.register("alice").await;
+        let update = |body| app.request(Method::PUT, "/v1/users/update").token(&alice.token).json(body).send();
+
+        let response = update(json!({ "user_id": alice.id, "name": "Alice Liddell" })).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(name_and_email(&app, &alice).await, ("Alice Liddell".to_string(), alice.email.clone()));
+
+        let response = update(json!({ "user_id": alice.id, "email": "liddell@example.com" })).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(
+            name_and_email(&app, &alice).await,
+            ("Alice Liddell".to_string(), "liddell@example.com".to_string())
+        );
+
+        let response = update(json!({ "user_id": alice.id })).await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.error_code(), "empty_update");
+    }
 }
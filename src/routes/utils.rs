@@ -1,10 +1,121 @@
-use axum::http::{HeaderMap, StatusCode};
+use std::sync::{Arc, LazyLock};
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{
+        rejection::{JsonRejection, PathRejection},
+        FromRequest, FromRequestParts, Path, Request,
+    },
+    http::{header, request::Parts, HeaderMap, Method, StatusCode},
+    Json,
+};
+use serde::de::DeserializeOwned;
+use regex::Regex;
+use sqlx::PgPool;
 use uuid::Uuid;
 
-use super::auth::AuthService;
+use crate::db::user::Role;
 
-#[inline]
-pub fn validate_auth_token(headers: HeaderMap, service: &AuthService) -> Result<Uuid, StatusCode> {
+use super::{auth::AuthService, error::ApiError, tx::TxConfig};
+
+// Router states which carry the auth service, so extractors can validate tokens
+pub trait AuthState {
+    fn auth_service(&self) -> &AuthService;
+}
+
+impl AuthState for (Arc<AuthService>, PgPool, Arc<TxConfig>) {
+    fn auth_service(&self) -> &AuthService {
+        &self.0
+    }
+}
+
+impl AuthState for Arc<AuthService> {
+    fn auth_service(&self) -> &AuthService {
+        self
+    }
+}
+
+// Header carrying an API key, accepted by `AuthUser` in place of an access token
+const API_KEY_HEADER: &str = "x-api-key";
+
+// Id of the authenticated user, by access token or API key. The request is rejected with 401 when
+// the credential is missing or invalid, and with 403 when the API key's scopes don't cover it
+pub struct AuthUser(pub Uuid);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthUser
+where
+    S: AuthState + Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if parts.headers.contains_key(API_KEY_HEADER) {
+            return api_key_user(parts, state.auth_service()).await.map(AuthUser);
+        }
+        TokenUser::from_request_parts(parts, state).await.map(|TokenUser(user_id)| AuthUser(user_id))
+    }
+}
+
+// Id of a user authenticated with an access token, for the routes an API key must not reach (managing API keys)
+pub struct TokenUser(pub Uuid);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for TokenUser
+where
+    S: AuthState + Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match validate_auth_token(&parts.headers, state.auth_service()).await {
+            Ok(user_id) => Ok(TokenUser(user_id)),
+            Err(StatusCode::SERVICE_UNAVAILABLE) => Err(ApiError::database_unavailable()),
+            Err(err) => {
+                tracing::warn!("Token validation failed for {}", parts.uri.path());
+                Err(ApiError::new(err, "invalid_token", "Invalid token"))
+            }
+        }
+    }
+}
+
+// owner of the request's API key, `read` keys may only make GET requests
+async fn api_key_user(parts: &Parts, service: &AuthService) -> Result<Uuid, ApiError> {
+    let invalid_api_key = || ApiError::new(StatusCode::UNAUTHORIZED, "invalid_api_key", "Invalid API key");
+    let Some(key) = parts.headers.get(API_KEY_HEADER).and_then(|key| key.to_str().ok()) else {
+        return Err(invalid_api_key());
+    };
+
+    match service.verify_api_key(key.trim()).await {
+        Ok(Some((user_id, scopes))) => {
+            let scope = if matches!(parts.method, Method::GET | Method::HEAD) { "read" } else { "write" };
+            if scopes.iter().any(|granted| granted == scope) {
+                return Ok(user_id);
+            }
+            tracing::warn!("API key of user {user_id} without `{scope}` scope used for {}", parts.uri.path());
+            Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "insufficient_scope",
+                format!("API key lacks the `{scope}` scope"),
+            ))
+        }
+        Ok(None) => {
+            tracing::warn!("Unknown API key used for {}", parts.uri.path());
+            Err(invalid_api_key())
+        }
+        Err(sqlx::Error::PoolTimedOut) => {
+            tracing::error!("Timed out waiting for a database connection");
+            Err(ApiError::database_unavailable())
+        }
+        Err(err) => {
+            tracing::error!("Failed to look up API key: {err}");
+            Err(ApiError::internal("Failed to verify API key"))
+        }
+    }
+}
+
+pub async fn validate_auth_token(headers: &HeaderMap, service: &AuthService) -> Result<Uuid, StatusCode> {
     let jwt_header_token = match headers.get("Authorization").map(|token| token.to_str()) {
         Some(Ok(token)) => token,
         _ => {
@@ -12,9 +123,163 @@ pub fn validate_auth_token(headers: HeaderMap, service: &AuthService) -> Result<
         }
     };
     //validate our token
-    match service.verify_token(jwt_header_token) {
+    match service.verify_token(jwt_header_token).await {
         Ok(user) => Ok(user),
-        Err(_) => Err(StatusCode::UNAUTHORIZED),
+        Err(err) => Err(token_rejection(err.as_ref())),
+    }
+}
+
+// 401 for a token that doesn't check out, 503 when the revocation lookup got no database connection in time
+fn token_rejection(err: &(dyn std::error::Error + 'static)) -> StatusCode {
+    if matches!(err.downcast_ref::<sqlx::Error>(), Some(sqlx::Error::PoolTimedOut)) {
+        tracing::error!("Timed out waiting for a database connection");
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+    StatusCode::UNAUTHORIZED
+}
+
+// Id of an authenticated admin, non-admin tokens are rejected with 403
+pub struct AdminUser(pub Uuid);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AdminUser
+where
+    S: AuthState + Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match require_admin(&parts.headers, state.auth_service()).await {
+            Ok(user_id) => Ok(AdminUser(user_id)),
+            Err(StatusCode::SERVICE_UNAVAILABLE) => Err(ApiError::database_unavailable()),
+            Err(StatusCode::FORBIDDEN) => {
+                tracing::warn!("Non-admin token rejected for {}", parts.uri.path());
+                Err(ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    "insufficient_privileges",
+                    "Admin role required",
+                ))
+            }
+            Err(err) => {
+                tracing::warn!("Token validation failed for {}", parts.uri.path());
+                Err(ApiError::new(err, "invalid_token", "Invalid token"))
+            }
+        }
+    }
+}
+
+// Same as `validate_auth_token`, additionally answering 403 unless the token carries the admin role
+pub async fn require_admin(headers: &HeaderMap, service: &AuthService) -> Result<Uuid, StatusCode> {
+    let jwt_header_token = match headers.get("Authorization").map(|token| token.to_str()) {
+        Some(Ok(token)) => token,
+        _ => {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    };
+    match service.verify_token_role(jwt_header_token).await {
+        Ok((user, Role::Admin)) => Ok(user),
+        Ok(_) => Err(StatusCode::FORBIDDEN),
+        Err(err) => Err(token_rejection(err.as_ref())),
+    }
+}
+
+// `Json` request body whose rejections are answered in the error envelope, a syntax error
+// answers 400 `invalid_json` with the line and column where parsing failed
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(request, state).await {
+            Ok(Json(value)) => Ok(JsonBody(value)),
+            Err(rejection) => {
+                tracing::warn!("Rejected request body: {}", rejection.body_text());
+                Err(match rejection {
+                    JsonRejection::JsonSyntaxError(err) => {
+                        ApiError::new(StatusCode::BAD_REQUEST, "invalid_json", err.body_text())
+                    }
+                    JsonRejection::JsonDataError(err) => ApiError::new(err.status(), "invalid_body", err.body_text()),
+                    JsonRejection::MissingJsonContentType(err) => {
+                        ApiError::new(err.status(), "unsupported_media_type", err.body_text())
+                    }
+                    rejection if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                        ApiError::new(rejection.status(), "payload_too_large", rejection.body_text())
+                    }
+                    rejection => ApiError::new(rejection.status(), "invalid_body", rejection.body_text()),
+                })
+            }
+        }
+    }
+}
+
+// `JsonBody` for endpoints whose body may be left out entirely, a request without a body and without
+// a `Content-Type` gives `None`. A body that is sent is held to the same rules as `JsonBody`
+pub struct OptionalJsonBody<T>(pub Option<T>);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for OptionalJsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if request.headers().contains_key(header::CONTENT_TYPE) {
+            return JsonBody::from_request(request, state).await.map(|JsonBody(value)| Self(Some(value)));
+        }
+
+        match Bytes::from_request(request, state).await {
+            Ok(body) if body.is_empty() => Ok(Self(None)),
+            Ok(_) => Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+                "Expected request with `Content-Type: application/json`",
+            )),
+            Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => Err(ApiError::new(
+                rejection.status(),
+                "payload_too_large",
+                rejection.body_text(),
+            )),
+            Err(rejection) => Err(ApiError::new(rejection.status(), "invalid_body", rejection.body_text())),
+        }
+    }
+}
+
+// the id of the user, transfer or hold a route is about
+pub fn id_from_path(id: Result<Path<Uuid>, PathRejection>) -> Result<Uuid, ApiError> {
+    match id {
+        Ok(Path(id)) => Ok(id),
+        Err(rejection) => {
+            tracing::warn!("Malformed id in path: {rejection}");
+            Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_path_parameter",
+                "Invalid path parameter `id`: expected a UUID",
+            ))
+        }
+    }
+}
+
+// Client supplied `Idempotency-Key` header, a retried request carrying the same key is not executed twice
+pub fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, ApiError> {
+    let Some(value) = headers.get("Idempotency-Key") else {
+        return Ok(None);
+    };
+
+    match value.to_str().map(str::trim) {
+        Ok(key) if !key.is_empty() && key.len() <= 255 => Ok(Some(key.to_string())),
+        _ => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_idempotency_key",
+            "Idempotency-Key must be between 1 and 255 visible characters",
+        )),
     }
 }
 
@@ -29,11 +294,126 @@ pub fn check_password(password: &str) -> Result<(), Box<dyn std::error::Error>>
     if !password.chars().any(|c| c.is_lowercase()) {
         return Err("Password must contain at least one lowercase letter".into());
     }
-    if !password.chars().any(|c| c.is_digit(10)) {
+    if !password.chars().any(|c| c.is_ascii_digit()) {
         return Err("Password must contain at least one digit".into());
     }
     if !password.chars().any(|c| !c.is_alphanumeric()) {
         return Err("Password must contain at least one special character".into());
     }
     Ok(())
-}
\ No newline at end of file
+}
+static SSN_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b\d{3}-\d{2}-\d{4}\b").unwrap());
+static CARD_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(?:\d[ -]?){12,18}\d\b").unwrap());
+
+// Redact card and SSN like numbers from a free text description, rejecting it if it contains a blocked word
+pub fn sanitize_description(description: &str, blocked_words: &[String]) -> Result<String, &'static str> {
+    let lowered = description.to_lowercase();
+    if blocked_words
+        .iter()
+        .any(|word| lowered.split(|c: char| !c.is_alphanumeric()).any(|token| token == word))
+    {
+        return Err("Description contains disallowed language");
+    }
+
+    let redacted = SSN_PATTERN.replace_all(description, "[REDACTED]");
+    let redacted = CARD_PATTERN.replace_all(&redacted, "[REDACTED]");
+    Ok(redacted.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Method, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_utils::TestApp;
+
+    fn headers_with_key(key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("Idempotency-Key", key.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn idempotency_keys_are_trimmed_and_bounded() {
+        assert_eq!(idempotency_key(&HeaderMap::new()).unwrap(), None);
+        assert_eq!(idempotency_key(&headers_with_key(" order-42 ")).unwrap().as_deref(), Some("order-42"));
+        assert_eq!(idempotency_key(&headers_with_key(&"k".repeat(255))).unwrap().map(|key| key.len()), Some(255));
+
+        for key in ["   ", &"k".repeat(256)] {
+            let err = idempotency_key(&headers_with_key(key)).unwrap_err();
+            assert_eq!((err.status(), err.code()), (StatusCode::BAD_REQUEST, "invalid_idempotency_key"));
+        }
+    }
+
+    #[test]
+    fn passwords_need_every_character_class() {
+        assert!(check_password("Passw0rd!x").is_ok());
+        let cases = [
+            ("Pa0!x", "at least 8 characters"),
+            ("passw0rd!x", "uppercase"),
+            ("PASSW0RD!X", "lowercase"),
+            ("Password!x", "digit"),
+            ("Passw0rdxx", "special character"),
+        ];
+        for (password, reason) in cases {
+            let err = check_password(password).unwrap_err().to_string();
+            assert!(err.contains(reason), "{password}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_path_ids_are_a_bad_request() {
+        let router = Router::new().route(
+            "/:id",
+            get(|id: Result<Path<Uuid>, PathRejection>| async move { id_from_path(id).map(|id| id.to_string()) }),
+        );
+        let get = |uri: String| router.clone().oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap());
+        assert_eq!(get(format!("/{}", Uuid::new_v4())).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(get("/not-a-uuid".to_string()).await.unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn truncated_json_is_a_structured_bad_request(pool: sqlx::PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+
+        for (method, uri) in [
+            (Method::POST, "/v1/auth/register"),
+            (Method::POST, "/v1/auth/login"),
+            (Method::POST, "/v1/tx/transfer"),
+            (Method::POST, "/v1/users/deposit"),
+            (Method::PUT, "/v1/users/update"),
+        ] {
+            let response = app.request(method, uri).token(&alice.token).raw_json("{").send().await;
+            assert_eq!(response.status, StatusCode::BAD_REQUEST, "{uri}: {}", response.body);
+            assert_eq!(response.error_code(), "invalid_json", "{uri}");
+            // where parsing stopped
+            let message = response.body["error"]["message"].as_str().unwrap();
+            assert!(message.contains("line 1 column 1"), "{uri}: {message}");
+        }
+    }
+
+    #[test]
+    fn card_numbers_and_ssns_are_redacted() {
+        let cases = [
+            ("rent, card 4111 1111 1111 1111", "rent, card [REDACTED]"),
+            ("card 4111-1111-1111-1111 thanks", "card [REDACTED] thanks"),
+            ("4111111111111111", "[REDACTED]"),
+            ("ssn 123-45-6789 for the form", "ssn [REDACTED] for the form"),
+            // order numbers and amounts are left alone
+            ("invoice 2025-0042, 120.50", "invoice 2025-0042, 120.50"),
+        ];
+        for (description, redacted) in cases {
+            assert_eq!(sanitize_description(description, &[]).unwrap(), redacted);
+        }
+    }
+
+    #[test]
+    fn descriptions_with_blocked_words_are_rejected() {
+        let blocked = ["scam".to_string()];
+        assert!(sanitize_description("not a SCAM, promise", &blocked).is_err());
+        // only whole words count
+        assert!(sanitize_description("scampi dinner", &blocked).is_ok());
+    }
+}
@@ -0,0 +1,452 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::post,
+    Json, Router,
+};
+use chrono::Utc;
+use hmac::{digest::KeyInit, Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Sha256, Sha512};
+use sqlx::{types::Decimal, PgPool};
+use uuid::Uuid;
+
+use crate::audit::{self, AuditAction, AuditEntry};
+use crate::db::balance::{self, Account, BalanceError};
+use crate::db::tx::{insert_transaction, TransactionStatus, TransactionType};
+use crate::db::wallet::WalletRepository;
+
+use super::{
+    error::ApiError,
+    tx::{check_minor_units, TxConfig},
+};
+
+// Header carrying the hex encoded HMAC of `<timestamp>.<raw request body>`
+const SIGNATURE_HEADER: &str = "x-webhook-signature";
+// Header carrying the unix time (seconds) the provider signed the request at
+const TIMESTAMP_HEADER: &str = "x-webhook-timestamp";
+
+// Hash function of the webhook HMAC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl std::str::FromStr for SignatureAlgorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            other => Err(format!("unknown signature algorithm `{other}`, expected `sha256` or `sha512`")),
+        }
+    }
+}
+
+// Shared secrets agreed with the payment providers
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub deposit_secret: String,
+    pub algorithm: SignatureAlgorithm,
+    pub tolerance: Duration, // how far the signing timestamp may lie from now before a request counts as a replay
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DepositEvent {
+    pub event_id: String,
+    pub user_id: Uuid,
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DepositEventResponse {
+    pub event_id: String,
+    pub duplicate: bool, // the event was already credited earlier and has been ignored
+}
+
+// Constant time check of the signature over the timestamp and body against the shared secret
+fn verify_signature(config: &WebhookConfig, timestamp: &str, body: &[u8], headers: &HeaderMap) -> bool {
+    let Some(signature) = headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| hex::decode(value.trim()).ok())
+    else {
+        return false;
+    };
+
+    match config.algorithm {
+        SignatureAlgorithm::Sha256 => verify_mac::<Hmac<Sha256>>(&config.deposit_secret, timestamp, body, &signature),
+        SignatureAlgorithm::Sha512 => verify_mac::<Hmac<Sha512>>(&config.deposit_secret, timestamp, body, &signature),
+    }
+}
+
+fn verify_mac<M: Mac + KeyInit>(secret: &str, timestamp: &str, body: &[u8], signature: &[u8]) -> bool {
+    let Ok(mut mac) = <M as Mac>::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac.verify_slice(signature).is_ok()
+}
+
+// The signing timestamp as sent, as long as it is within the tolerance of now. An older one is a replay of a
+// request captured earlier, the signature binding it to the body keeps it from being swapped for a fresh one
+fn fresh_timestamp<'a>(config: &WebhookConfig, headers: &'a HeaderMap) -> Result<&'a str, ApiError> {
+    let timestamp = headers
+        .get(TIMESTAMP_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty());
+    let Some((timestamp, signed_at)) = timestamp.and_then(|value| Some((value, value.parse::<i64>().ok()?))) else {
+        tracing::warn!("Rejected deposit webhook with a missing or invalid timestamp");
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid_signature",
+            "Invalid webhook signature",
+        ));
+    };
+
+    let age = Utc::now().timestamp().abs_diff(signed_at);
+    if age > config.tolerance.as_secs() {
+        tracing::warn!("Rejected deposit webhook signed {age} seconds away from now");
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "stale_timestamp",
+            "Webhook timestamp is outside the accepted window",
+        ));
+    }
+    Ok(timestamp)
+}
+
+// credit a deposit reported by a payment provider, each event id is credited at most once
+async fn ingest_deposit(
+    State((pool, config, tx_config)): State<(PgPool, Arc<WebhookConfig>, Arc<TxConfig>)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, ApiError> {
+    // the signature covers the exact bytes received, so check it before parsing anything
+    let timestamp = fresh_timestamp(&config, &headers)?;
+    if !verify_signature(&config, timestamp, &body, &headers) {
+        tracing::warn!("Rejected deposit webhook with a missing or invalid signature");
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid_signature",
+            "Invalid webhook signature",
+        ));
+    }
+
+    let event = match serde_json::from_slice::<DepositEvent>(&body) {
+        Ok(event) => event,
+        Err(err) => {
+            tracing::warn!("Malformed deposit webhook: {err}");
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "malformed_event",
+                "Malformed deposit event",
+            ));
+        }
+    };
+    if event.amount <= Decimal::ZERO {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_amount",
+            "Deposit amount must be positive",
+        ));
+    }
+    // events for unknown users are told apart from duplicates further down, they have no currency to check against
+    match WalletRepository::new(pool.clone()).find_for(event.user_id, None).await {
+        Ok(Some(wallet)) => check_minor_units(event.amount, &wallet.currency)?,
+        Ok(None) => {}
+        Err(err) => {
+            tracing::error!("Failed to look up the wallet of deposit event {}: {err}", event.event_id);
+            return Err(ApiError::internal("Failed to process deposit"));
+        }
+    }
+
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(err) => {
+            tracing::error!("Failed to start transaction: {err}");
+            return Err(ApiError::internal("Failed to process deposit"));
+        }
+    };
+
+    // recording the event first turns a redelivery into a no-op, concurrent duplicates wait on the key
+    let recorded = sqlx::query!(
+        r#"
+        INSERT INTO deposit_events (event_id, user_id, amount)
+        SELECT $1, id, $3 FROM users WHERE id = $2
+        ON CONFLICT (event_id) DO NOTHING
+        "#,
+        event.event_id,
+        event.user_id,
+        event.amount
+    )
+    .execute(&mut *tx)
+    .await;
+
+    match recorded {
+        Ok(done) if done.rows_affected() == 1 => {}
+        Ok(_) => {
+            drop(tx);
+            return match sqlx::query!("SELECT 1 AS found FROM deposit_events WHERE event_id = $1", event.event_id)
+                .fetch_optional(&pool)
+                .await
+            {
+                Ok(Some(_)) => {
+                    tracing::info!("Ignored duplicate deposit event: {}", event.event_id);
+                    let response = DepositEventResponse {
+                        event_id: event.event_id,
+                        duplicate: true,
+                    };
+                    Ok((StatusCode::OK, Json(response)))
+                }
+                Ok(None) => {
+                    tracing::warn!("Deposit event {} targets unknown user: {}", event.event_id, event.user_id);
+                    Err(ApiError::new(StatusCode::NOT_FOUND, "user_not_found", "User not found"))
+                }
+                Err(err) => {
+                    tracing::error!("Failed to look up deposit event: {err}");
+                    Err(ApiError::internal("Failed to process deposit"))
+                }
+            };
+        }
+        Err(err) => {
+            tracing::error!("Failed to record deposit event: {err}");
+            return Err(ApiError::internal("Failed to process deposit"));
+        }
+    }
+
+    // the event id goes in the description, `reference_id` holds the Idempotency-Key of API deposits
+    let description = format!("Deposit event {}", event.event_id);
+    let transaction_id = match insert_transaction(
+        &mut tx,
+        event.user_id,
+        event.amount,
+        TransactionType::Deposit,
+        TransactionStatus::Completed,
+        None,
+        Some(&description),
+    )
+    .await
+    {
+        Ok(transaction_id) => transaction_id,
+        Err(err) => {
+            tracing::error!("Failed to record deposit {}: {err}", event.event_id);
+            return Err(ApiError::internal("Failed to process deposit"));
+        }
+    };
+
+    // the event row is rolled back with the credit, so a later retry can still succeed
+    let account = Account::Primary(event.user_id);
+    match balance::credit_within_cap(&mut tx, account, event.amount, tx_config.max_account_balance, transaction_id).await {
+        Ok(_) => {}
+        Err(BalanceError::AccountFrozen) => {
+            tracing::warn!("Deposit event {} targets frozen account: {}", event.event_id, event.user_id);
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "account_frozen", "Account is frozen"));
+        }
+        Err(BalanceError::AccountClosed) => {
+            tracing::warn!("Deposit event {} targets closed account: {}", event.event_id, event.user_id);
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "account_closed", "Account is closed"));
+        }
+        Err(BalanceError::BalanceCapExceeded) => {
+            tracing::warn!("Deposit event {} would exceed the maximum balance of user: {}", event.event_id, event.user_id);
+            return Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "balance_cap_exceeded",
+                "Deposit would exceed the maximum account balance",
+            ));
+        }
+        Err(err) => {
+            tracing::error!("Failed to credit deposit {}: {err}", event.event_id);
+            return Err(ApiError::internal("Failed to process deposit"));
+        }
+    }
+
+    let audit_entry = AuditEntry {
+        actor_id: event.user_id,
+        action: AuditAction::Deposit,
+        amount: event.amount,
+        counterparty_id: None,
+        tx_id: transaction_id,
+    };
+    if let Err(err) = audit::record(&mut tx, &audit_entry).await {
+        tracing::error!("Failed to record audit entry for deposit {}: {err}", event.event_id);
+        return Err(ApiError::internal("Failed to process deposit"));
+    }
+
+    match tx.commit().await {
+        Ok(_) => {
+            tracing::info!("Credited deposit event {} to user: {}", event.event_id, event.user_id);
+            let response = DepositEventResponse {
+                event_id: event.event_id,
+                duplicate: false,
+            };
+            Ok((StatusCode::OK, Json(response)))
+        }
+        Err(err) => {
+            tracing::error!("Failed to commit deposit {}: {err}", event.event_id);
+            Err(ApiError::internal("Failed to process deposit"))
+        }
+    }
+}
+
+pub fn webhook_routes(pool: PgPool, config: WebhookConfig, tx_config: Arc<TxConfig>) -> Router {
+    Router::new()
+        .route("/webhooks/deposits", post(ingest_deposit))
+        .with_state((pool, Arc::new(config), tx_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use chrono::Utc;
+    use hmac::{digest::KeyInit, Hmac, Mac};
+    use rust_decimal::Decimal;
+    use serde_json::{json, Value};
+    use sha2::{Sha256, Sha512};
+    use sqlx::PgPool;
+
+    use super::{SignatureAlgorithm, WebhookConfig};
+    use crate::test_utils::{webhook_config, TestApp, TestResponse, WEBHOOK_SECRET};
+
+    fn mac<M: Mac + KeyInit>(secret: &str, payload: &str) -> String {
+        let mut mac = <M as Mac>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    // what a provider sends along with `body` signed at `timestamp`
+    fn sign(secret: &str, timestamp: i64, body: &Value) -> String {
+        mac::<Hmac<Sha256>>(secret, &format!("{timestamp}.{body}"))
+    }
+
+    async fn deliver(app: &TestApp, event: &Value, timestamp: i64, signature: Option<String>) -> TestResponse {
+        let mut request = app
+            .post("/v1/webhooks/deposits")
+            .header("X-Webhook-Timestamp", &timestamp.to_string())
+            .json(event.clone());
+        if let Some(signature) = signature {
+            request = request.header("X-Webhook-Signature", &signature);
+        }
+        request.send().await
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn signed_event_is_credited_once(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let event = json!({ "event_id": "evt_1", "user_id": alice.id, "amount": "25.50" });
+        let now = Utc::now().timestamp();
+
+        let response = deliver(&app, &event, now, Some(sign(WEBHOOK_SECRET, now, &event))).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(response.body["duplicate"], false);
+        assert_eq!(app.balance(&alice).await, Decimal::new(2550, 2));
+
+        // a redelivery is acknowledged but not credited again
+        let response = deliver(&app, &event, now, Some(sign(WEBHOOK_SECRET, now, &event))).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(response.body["duplicate"], true);
+        assert_eq!(app.balance(&alice).await, Decimal::new(2550, 2));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn unsigned_or_forged_events_are_rejected(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let event = json!({ "event_id": "evt_1", "user_id": alice.id, "amount": "25" });
+        let tampered = json!({ "event_id": "evt_1", "user_id": alice.id, "amount": "2500" });
+        let now = Utc::now().timestamp();
+
+        for signature in [
+            None,
+            Some("not-hex".to_string()),
+            Some(sign("some-other-secret", now, &event)),
+            Some(sign(WEBHOOK_SECRET, now, &event)), // sent along with the tampered body
+        ] {
+            let response = deliver(&app, &tampered, now, signature).await;
+            assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+            assert_eq!(response.error_code(), "invalid_signature");
+        }
+        assert_eq!(app.balance(&alice).await, Decimal::ZERO);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn signature_covers_the_timestamp(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let event = json!({ "event_id": "evt_1", "user_id": alice.id, "amount": "25" });
+        let now = Utc::now().timestamp();
+        let signature = sign(WEBHOOK_SECRET, now, &event);
+
+        // the same signature under a different, still fresh, timestamp
+        let response = deliver(&app, &event, now - 1, Some(signature.clone())).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response.error_code(), "invalid_signature");
+
+        // a signature of the body alone
+        let body_only = mac::<Hmac<Sha256>>(WEBHOOK_SECRET, &event.to_string());
+        let response = deliver(&app, &event, now, Some(body_only)).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+
+        // no timestamp at all
+        let response = app
+            .post("/v1/webhooks/deposits")
+            .header("X-Webhook-Signature", &signature)
+            .json(event.clone())
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(app.balance(&alice).await, Decimal::ZERO);
+
+        let response = deliver(&app, &event, now, Some(signature)).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn stale_timestamps_are_rejected(pool: PgPool) {
+        let app = TestApp::new(pool);
+        let alice = app.register("alice").await;
+        let event = json!({ "event_id": "evt_1", "user_id": alice.id, "amount": "25" });
+
+        // correctly signed, but captured and replayed after the tolerance of 300 seconds
+        for signed_at in [Utc::now().timestamp() - 301, Utc::now().timestamp() + 301] {
+            let response = deliver(&app, &event, signed_at, Some(sign(WEBHOOK_SECRET, signed_at, &event))).await;
+            assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+            assert_eq!(response.error_code(), "stale_timestamp");
+        }
+        assert_eq!(app.balance(&alice).await, Decimal::ZERO);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn configured_algorithm_is_used(pool: PgPool) {
+        let app = TestApp::with_webhook_config(
+            pool,
+            WebhookConfig {
+                algorithm: SignatureAlgorithm::Sha512,
+                ..webhook_config()
+            },
+        );
+        let alice = app.register("alice").await;
+        let event = json!({ "event_id": "evt_1", "user_id": alice.id, "amount": "25" });
+        let now = Utc::now().timestamp();
+
+        let response = deliver(&app, &event, now, Some(sign(WEBHOOK_SECRET, now, &event))).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+
+        let signature = mac::<Hmac<Sha512>>(WEBHOOK_SECRET, &format!("{now}.{event}"));
+        let response = deliver(&app, &event, now, Some(signature)).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(app.balance(&alice).await, Decimal::new(25, 0));
+    }
+}
@@ -0,0 +1,312 @@
+// Shared setup of the handler tests: the full router over the database of a `#[sqlx::test]`,
+// driven request by request without binding a port
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::ConnectInfo,
+    http::{HeaderMap, Method, Request, StatusCode},
+    Router,
+};
+use rust_decimal::Decimal;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+use crate::currency::Currency;
+use crate::routes::auth::{AuthConfig, SessionPolicy};
+use crate::routes::jwt_keys::JwtKeys;
+use crate::routes::tx::TxConfig;
+use crate::routes::webhook::{SignatureAlgorithm, WebhookConfig};
+
+pub const PASSWORD: &str = "Passw0rd!x";
+pub const WEBHOOK_SECRET: &str = "test-webhook-secret";
+
+// The defaults of `Config::from_env`, with the cheapest argon2 parameters so registrations stay fast
+pub fn auth_config() -> AuthConfig {
+    AuthConfig {
+        leeway: 10,
+        access_ttl: Duration::from_secs(900),
+        refresh_ttl: Duration::from_secs(604800),
+        email_verification_ttl: Duration::from_secs(86400),
+        email_change_ttl: Duration::from_secs(86400),
+        log_email_tokens: false,
+        public_base_url: "https://pay.example.com".to_string(),
+        login_ip_max_failures: 20,
+        login_ip_window: Duration::from_secs(300),
+        login_email_max_failures: 5,
+        login_email_window: Duration::from_secs(60),
+        confirm_email_changes: true,
+        max_sessions: 5,
+        session_policy: SessionPolicy::EvictOldest,
+        default_currency: Currency::from_str("USD").unwrap(),
+        totp_key: None,
+        argon2_params: argon2::Params::new(8, 1, 1, None).unwrap(),
+        blocked_email_domains: Vec::new(),
+    }
+}
+
+pub fn tx_config() -> TxConfig {
+    TxConfig {
+        record_failed_transfers: true,
+        filter_descriptions: true,
+        blocked_words: Vec::new(),
+        max_metadata_bytes: 1024,
+        max_account_balance: None,
+        min_transfer_amount: None,
+        max_transfer_amount: None,
+        daily_transfer_limit: None,
+        reversal_window: Some(Duration::from_secs(2592000)),
+        external_settlement_delay: Duration::from_secs(86400),
+        hold_expiry: Duration::from_secs(604800),
+        version_retries: 10,
+    }
+}
+
+pub fn webhook_config() -> WebhookConfig {
+    WebhookConfig {
+        deposit_secret: WEBHOOK_SECRET.to_string(),
+        algorithm: SignatureAlgorithm::Sha256,
+        tolerance: Duration::from_secs(300),
+    }
+}
+
+// Everything logged on the test's thread while the guard lives, down to debug level
+#[derive(Clone, Default)]
+pub struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    pub fn start() -> (Self, tracing::subscriber::DefaultGuard) {
+        let logs = Self::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// amounts are serialized as strings
+pub fn decimal(value: &Value) -> Decimal {
+    match value {
+        Value::String(value) => value.parse().unwrap(),
+        value => value.to_string().parse().unwrap(),
+    }
+}
+
+pub struct TestApp {
+    router: Router,
+    pub pool: PgPool,
+}
+
+pub struct TestUser {
+    pub id: Uuid,
+    pub email: String,
+    pub token: String,
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Value, // JSON bodies parsed, plain text ones as a string
+}
+
+impl TestResponse {
+    pub fn error_code(&self) -> &str {
+        self.body["error"]["code"].as_str().unwrap_or_default()
+    }
+
+    pub fn header(&self, name: &str) -> &str {
+        self.headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or_default()
+    }
+}
+
+pub struct TestRequest<'a> {
+    app: &'a TestApp,
+    request: axum::http::request::Builder,
+    body: Body,
+    client: SocketAddr,
+}
+
+impl TestRequest<'_> {
+    pub fn token(self, token: &str) -> Self {
+        self.header("Authorization", token)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.request = self.request.header(name, value);
+        self
+    }
+
+    // the address the request comes from, 127.0.0.1 unless set
+    pub fn client_ip(mut self, ip: [u8; 4]) -> Self {
+        self.client = SocketAddr::from((ip, 40000));
+        self
+    }
+
+    pub fn json(mut self, body: Value) -> Self {
+        self.request = self.request.header("Content-Type", "application/json");
+        self.body = Body::from(body.to_string());
+        self
+    }
+
+    // sent as JSON without going through `Value`, for bodies that don't parse
+    pub fn raw_json(mut self, body: &str) -> Self {
+        self.request = self.request.header("Content-Type", "application/json");
+        self.body = Body::from(body.to_string());
+        self
+    }
+
+    pub async fn send(self) -> TestResponse {
+        let mut request = self.request.body(self.body).unwrap();
+        // stands in for `into_make_service_with_connect_info`, the login throttle keys on the client ip
+        request.extensions_mut().insert(ConnectInfo(self.client));
+
+        let response = self.app.router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice(&bytes)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned()));
+        TestResponse { status, headers, body }
+    }
+}
+
+impl TestApp {
+    pub fn new(pool: PgPool) -> Self {
+        Self::with_config(pool, auth_config(), tx_config())
+    }
+
+    pub fn with_config(pool: PgPool, auth_config: AuthConfig, tx_config: TxConfig) -> Self {
+        Self::build(pool, auth_config, tx_config, webhook_config())
+    }
+
+    pub fn with_webhook_config(pool: PgPool, webhook_config: WebhookConfig) -> Self {
+        Self::build(pool, auth_config(), tx_config(), webhook_config)
+    }
+
+    pub fn with_jwt_keys(pool: PgPool, jwt_keys: JwtKeys) -> Self {
+        Self::build_with_keys(pool, jwt_keys, auth_config(), tx_config(), webhook_config())
+    }
+
+    fn build(pool: PgPool, auth_config: AuthConfig, tx_config: TxConfig, webhook_config: WebhookConfig) -> Self {
+        let jwt_keys = JwtKeys::single("test-jwt-secret".to_string());
+        Self::build_with_keys(pool, jwt_keys, auth_config, tx_config, webhook_config)
+    }
+
+    fn build_with_keys(
+        pool: PgPool,
+        jwt_keys: JwtKeys,
+        auth_config: AuthConfig,
+        tx_config: TxConfig,
+        webhook_config: WebhookConfig,
+    ) -> Self {
+        let router = crate::process_begin(
+            pool.clone(),
+            jwt_keys,
+            auth_config,
+            tx_config,
+            Some(webhook_config),
+            65536,
+        )
+        .unwrap();
+        Self { router, pool }
+    }
+
+    pub fn request(&self, method: Method, uri: &str) -> TestRequest<'_> {
+        TestRequest {
+            app: self,
+            request: Request::builder().method(method).uri(uri),
+            body: Body::empty(),
+            client: SocketAddr::from(([127, 0, 0, 1], 40000)),
+        }
+    }
+
+    pub fn get(&self, uri: &str) -> TestRequest<'_> {
+        self.request(Method::GET, uri)
+    }
+
+    pub fn post(&self, uri: &str) -> TestRequest<'_> {
+        self.request(Method::POST, uri)
+    }
+
+    // registers `<name>@example.com`, tokens are usable right away
+    pub async fn register(&self, name: &str) -> TestUser {
+        let email = format!("{name}@example.com");
+        let response = self
+            .post("/v1/auth/register")
+            .json(json!({ "email": email, "password": PASSWORD, "full_name": name }))
+            .send()
+            .await;
+        assert_eq!(response.status, StatusCode::CREATED, "{}", response.body);
+        TestUser {
+            id: response.body["user_uid"].as_str().unwrap().parse().unwrap(),
+            email,
+            token: response.body["access_token"].as_str().unwrap().to_string(),
+        }
+    }
+
+    // a registered user promoted to admin, logged in again so the token carries the role
+    pub async fn register_admin(&self, name: &str) -> TestUser {
+        let mut admin = self.register(name).await;
+        sqlx::query("UPDATE users SET role = 'admin' WHERE id = $1")
+            .bind(admin.id)
+            .execute(&self.pool)
+            .await
+            .unwrap();
+        let response = self.login(&admin.email, PASSWORD).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        admin.token = response.body["access_token"].as_str().unwrap().to_string();
+        admin
+    }
+
+    pub async fn login(&self, email: &str, password: &str) -> TestResponse {
+        self.post("/v1/auth/login")
+            .json(json!({ "email": email, "password": password }))
+            .send()
+            .await
+    }
+
+    pub async fn deposit(&self, user: &TestUser, amount: &str) -> TestResponse {
+        self.post("/v1/users/deposit")
+            .token(&user.token)
+            .json(json!({ "email": user.email, "full_name": "Test", "amount": amount }))
+            .send()
+            .await
+    }
+
+    pub async fn transfer(&self, sender: &TestUser, receiver_id: Uuid, amount: &str) -> TestResponse {
+        self.post("/v1/tx/transfer")
+            .token(&sender.token)
+            .json(json!({ "sender_id": sender.id, "receiver_id": receiver_id, "amount": amount }))
+            .send()
+            .await
+    }
+
+    pub async fn balance(&self, user: &TestUser) -> Decimal {
+        let response = self.get("/v1/users/balance").token(&user.token).send().await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        decimal(&response.body["balance"])
+    }
+}